@@ -0,0 +1,82 @@
+use mio::{Token, Waker};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Sender;
+use serde::Deserialize;
+
+use log::error;
+use crate::store::GeoPacket;
+use crate::wialon::ResponsePacket;
+
+/// When devices get the response for a data packet.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AckMode {
+    /// As soon as the packet is queued for the store.
+    #[default]
+    Immediate,
+    /// Once the store confirmed the packet is saved (at-least-once delivery).
+    AfterStore,
+}
+
+/// Packet travelling from a connection to the store.
+pub struct Delivery {
+    pub packet: GeoPacket,
+    /// Set when the device waits for the store before it gets the response.
+    pub ack: Option<Ack>,
+}
+
+impl Delivery {
+    pub fn new(packet: GeoPacket) -> Delivery {
+        Delivery { packet, ack: None }
+    }
+}
+
+/// Response which has to go back to the connection `token` of a worker.
+pub struct Completion {
+    pub token: Token,
+    pub response: ResponsePacket,
+}
+
+// Way back from the store thread to the worker owning a connection.
+#[derive(Clone)]
+pub(crate) struct AckRoute {
+    pub token: Token,
+    pub completions: Sender<Completion>,
+    pub waker: Arc<Waker>,
+}
+
+/// Pending response, sent to the device when confirmed.
+pub struct Ack {
+    route: AckRoute,
+    response: ResponsePacket,
+    // messages of the packet still to confirm, shared by their acks
+    remaining: Arc<AtomicUsize>,
+}
+
+impl Ack {
+    /// One ack per message of a packet (several for a black box), the response is sent once
+    /// all of them are confirmed.
+    pub(crate) fn new(route: AckRoute, response: ResponsePacket, messages: usize) -> Vec<Ack> {
+        let remaining = Arc::new(AtomicUsize::new(messages));
+        (0..messages).map(|_| Ack {
+            route: route.clone(),
+            response: response.clone(),
+            remaining: remaining.clone(),
+        }).collect()
+    }
+
+    pub fn confirm(self) {
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+
+        let completion = Completion { token: self.route.token, response: self.response };
+        // the connection may be gone already, then there is nobody to answer
+        if self.route.completions.send(completion).is_ok() {
+            if let Err(err) = self.route.waker.wake() {
+                error!("failed to wake worker: {:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_array::builder::{Float64Builder, Int32Builder, MapBuilder, StringBuilder};
+use arrow_array::{Float64Array, Int32Array, StringArray, TimestampSecondArray};
+use arrow_schema::{DataType, Field, Fields, Schema, SchemaRef, TimeUnit};
+use chrono::Local;
+use crossbeam_channel::{bounded, Sender, RecvTimeoutError};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+
+use log::{info, error};
+use crate::store::{GeoPacket, Param, Store, StoreError};
+
+/// Type of a param column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParamType {
+    Int,
+    Float,
+    String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ArrowConfig {
+    /// Files go to `{dir}/date=YYYY-MM-DD/bucket=NN/`, the date is the device time.
+    pub dir: PathBuf,
+    /// Devices are spread over this many buckets by a hash of the IMEI.
+    pub buckets: u32,
+    /// Params with a column `param_<name>` of their own. Other params, and values which don't fit
+    /// the column type, go to the `params` map column as strings.
+    pub columns: Vec<(String, ParamType)>,
+    /// A partition is written once it buffered this many rows...
+    pub max_rows: usize,
+    /// ...or its oldest row waits this long, checked at least once a second.
+    pub max_age: Duration,
+}
+
+impl ArrowConfig {
+    pub fn new(dir: &str) -> ArrowConfig {
+        ArrowConfig {
+            dir: PathBuf::from(dir),
+            buckets: 16,
+            columns: Vec::new(),
+            max_rows: 100_000,
+            max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
+struct Partition {
+    rows: Vec<GeoPacket>,
+    since: Instant,
+}
+
+/// Archives packets as zstd compressed Parquet files, partitioned by date and IMEI bucket.
+///
+/// Packets are buffered in memory and a partition is written as one file when it is full or old enough,
+/// so the archive lags behind by up to `max_age` and the buffer is lost if the process is killed. A saved
+/// batch is buffered even if writing a partition fails, the write is retried later. While a partition can't
+/// be written new batches are refused. Dropping the store writes whatever is buffered.
+///
+/// Saving succeeds before the rows are on disk, so the store must not be run behind a write-ahead log or
+/// with `AckMode::AfterStore`: both would confirm rows a crash loses. The config refuses these setups.
+pub struct ArrowStore {
+    archive: Arc<Archive>,
+    stop: Option<Sender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ArrowStore {
+    pub fn new(config: ArrowConfig) -> Result<ArrowStore, StoreError> {
+        fs::create_dir_all(&config.dir).map_err(|e| StoreError::Permanent(e.to_string()))?;
+        let schema = schema(&config.columns);
+        let archive = Arc::new(Archive { config, schema, partitions: Mutex::new(HashMap::new()), files: Mutex::new(0) });
+
+        // writes partitions which got old without new packets coming in
+        let (stop, stopped) = bounded::<()>(0);
+        let thread = {
+            let archive = archive.clone();
+            let interval = archive.config.max_age.min(Duration::from_secs(1));
+            thread::Builder::new()
+                .name(String::from("wialon-arrow"))
+                .spawn(move || while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(err) = archive.write_due(&mut archive.partitions.lock().unwrap()) {
+                        error!("{}, rows stay buffered", err);
+                    }
+                })
+                .map_err(|e| StoreError::Permanent(e.to_string()))?
+        };
+        Ok(ArrowStore { archive, stop: Some(stop), thread: Some(thread) })
+    }
+
+    pub fn schema(&self) -> SchemaRef {
+        self.archive.schema.clone()
+    }
+
+    /// Writes every buffered partition.
+    pub fn flush(&self) -> Result<(), StoreError> {
+        let mut partitions = self.archive.partitions.lock().unwrap();
+        let keys: Vec<(String, u32)> = partitions.keys().cloned().collect();
+        for key in keys {
+            self.archive.write(&mut partitions, key)?;
+        }
+        Ok(())
+    }
+}
+
+struct Archive {
+    config: ArrowConfig,
+    schema: SchemaRef,
+    partitions: Mutex<HashMap<(String, u32), Partition>>,
+    files: Mutex<u64>,
+}
+
+impl Archive {
+
+    fn bucket(&self, imei: &str) -> u32 {
+        crc32fast::hash(imei.as_bytes()) % self.config.buckets.max(1)
+    }
+
+    // Writes the partitions which are full or old enough, each one is tried even if another one fails.
+    fn write_due(&self, partitions: &mut HashMap<(String, u32), Partition>) -> Result<(), StoreError> {
+        let due: Vec<(String, u32)> = partitions.iter()
+            .filter(|(_, p)| p.rows.len() >= self.config.max_rows || p.since.elapsed() >= self.config.max_age)
+            .map(|(k, _)| k.clone())
+            .collect();
+        let mut result = Ok(());
+        for key in due {
+            if let Err(err) = self.write(partitions, key) {
+                result = result.and(Err(err));
+            }
+        }
+        result
+    }
+
+    // The partition stays buffered when the file can't be written.
+    fn write(&self, partitions: &mut HashMap<(String, u32), Partition>, key: (String, u32)) -> Result<(), StoreError> {
+        let rows = match partitions.get(&key) {
+            Some(p) if !p.rows.is_empty() => &p.rows,
+            _ => {
+                partitions.remove(&key);
+                return Ok(());
+            }
+        };
+        let batch = self.record_batch(rows).map_err(|e| StoreError::Permanent(e.to_string()))?;
+
+        let dir = self.config.dir.join(format!("date={}", key.0)).join(format!("bucket={:02}", key.1));
+        let path = {
+            let mut files = self.files.lock().unwrap();
+            *files += 1;
+            dir.join(format!("part-{}-{}.parquet", Local::now().format("%Y%m%d%H%M%S"), files))
+        };
+        write_parquet(&dir, &path, &batch).map_err(|e| StoreError::Transient(e.to_string()))?;
+
+        info!("Archived {} rows to {}", batch.num_rows(), path.display());
+        partitions.remove(&key);
+        Ok(())
+    }
+
+    fn record_batch(&self, rows: &[GeoPacket]) -> Result<RecordBatch, arrow_schema::ArrowError> {
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|p| &p.imei))),
+            Arc::new(TimestampSecondArray::from_iter_values(rows.iter().map(|p| p.timestamp.and_utc().timestamp()))
+                .with_timezone("UTC")),
+            Arc::new(Float64Array::from_iter_values(rows.iter().map(|p| p.lat))),
+            Arc::new(Float64Array::from_iter_values(rows.iter().map(|p| p.lon))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|p| p.speed as i32))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|p| p.course as i32))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|p| p.height as i32))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|p| p.sats as i32))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|p| &p.ptype))),
+        ];
+
+        for (name, ptype) in &self.config.columns {
+            let values = rows.iter().map(|p| p.params.get(name).and_then(|v| column_value(v, *ptype)));
+            let column: ArrayRef = match ptype {
+                ParamType::Int => {
+                    let mut b = Int32Builder::new();
+                    values.for_each(|v| b.append_option(v.and_then(|v| v.int)));
+                    Arc::new(b.finish())
+                }
+                ParamType::Float => {
+                    let mut b = Float64Builder::new();
+                    values.for_each(|v| b.append_option(v.and_then(|v| v.float)));
+                    Arc::new(b.finish())
+                }
+                ParamType::String => {
+                    let mut b = StringBuilder::new();
+                    values.for_each(|v| b.append_option(v.and_then(|v| v.string)));
+                    Arc::new(b.finish())
+                }
+            };
+            columns.push(column);
+        }
+
+        let mut map = MapBuilder::new(None, StringBuilder::new(), StringBuilder::new());
+        for p in rows {
+            for (name, value) in &p.params {
+                let in_column = self.config.columns.iter()
+                    .any(|(n, t)| n == name && column_value(value, *t).is_some());
+                if !in_column {
+                    map.keys().append_value(name);
+                    map.values().append_value(value.to_string());
+                }
+            }
+            map.append(true)?;
+        }
+        columns.push(Arc::new(map.finish()));
+
+        RecordBatch::try_new(self.schema.clone(), columns)
+    }
+}
+
+impl Store for ArrowStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    // A batch is either refused before any of it is buffered or taken as a whole, a retry never adds
+    // rows twice. Failed writes of taken rows are left to the next save and the timer.
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let archive = &self.archive;
+        let mut partitions = archive.partitions.lock().unwrap();
+        archive.write_due(&mut partitions)?;
+
+        for p in batch {
+            let key = (p.timestamp.format("%Y-%m-%d").to_string(), archive.bucket(&p.imei));
+            partitions.entry(key).or_insert_with(|| Partition { rows: Vec::new(), since: Instant::now() })
+                .rows.push(p.clone());
+        }
+
+        if let Err(err) = archive.write_due(&mut partitions) {
+            error!("{}, rows stay buffered", err);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for ArrowStore {
+    fn drop(&mut self) {
+        self.stop = None;
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                error!("arrow flusher panicked");
+            }
+        }
+        if let Err(err) = self.flush() {
+            error!("failed to write buffered packets: {}", err);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ColumnValue {
+    int: Option<i32>,
+    float: Option<f64>,
+    string: Option<String>,
+}
+
+// The value as it goes into a column of the type, None if it doesn't fit.
+fn column_value(value: &Param, ptype: ParamType) -> Option<ColumnValue> {
+    match (value, ptype) {
+        (Param::Int(v), ParamType::Int) => Some(ColumnValue { int: Some(*v), ..Default::default() }),
+        (Param::Int(v), ParamType::Float) => Some(ColumnValue { float: Some(*v as f64), ..Default::default() }),
+        (Param::Float(v), ParamType::Float) => Some(ColumnValue { float: Some(*v), ..Default::default() }),
+        (v, ParamType::String) => Some(ColumnValue { string: Some(v.to_string()), ..Default::default() }),
+        _ => None,
+    }
+}
+
+fn schema(columns: &[(String, ParamType)]) -> SchemaRef {
+    let mut fields = vec![
+        Field::new("imei", DataType::Utf8, false),
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Second, Some("UTC".into())), false),
+        Field::new("lat", DataType::Float64, false),
+        Field::new("lon", DataType::Float64, false),
+        Field::new("speed", DataType::Int32, false),
+        Field::new("course", DataType::Int32, false),
+        Field::new("height", DataType::Int32, false),
+        Field::new("sats", DataType::Int32, false),
+        Field::new("ptype", DataType::Utf8, false),
+    ];
+    for (name, ptype) in columns {
+        let data_type = match ptype {
+            ParamType::Int => DataType::Int32,
+            ParamType::Float => DataType::Float64,
+            ParamType::String => DataType::Utf8,
+        };
+        fields.push(Field::new(format!("param_{}", name), data_type, true));
+    }
+
+    // the layout MapBuilder produces
+    let entries = Fields::from(vec![
+        Field::new("keys", DataType::Utf8, false),
+        Field::new("values", DataType::Utf8, true),
+    ]);
+    let entries = Arc::new(Field::new("entries", DataType::Struct(entries), false));
+    fields.push(Field::new("params", DataType::Map(entries, false), true));
+
+    Arc::new(Schema::new(fields))
+}
+
+// Written under a temporary name first, readers of the directory never see a file without its footer.
+fn write_parquet(dir: &Path, path: &Path, batch: &RecordBatch) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("parquet.tmp");
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+
+    let mut writer = ArrowWriter::try_new(File::create(&tmp)?, batch.schema(), Some(properties))?;
+    writer.write(batch)?;
+    // writes the footer
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[test]
+fn test_arrow_store_partitions() {
+    use arrow_array::{Array, MapArray};
+    use arrow_array::cast::AsArray;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = ArrowConfig::new(dir.path().to_str().unwrap());
+    config.buckets = 1;
+    config.max_rows = 2;
+    config.columns.push((String::from("fuel"), ParamType::Float));
+    let store = ArrowStore::new(config).unwrap();
+
+    let mut with_int = test_packet("1");
+    with_int.params.insert(String::from("fuel"), Param::Int(40));
+    let mut with_string = test_packet("2");
+    with_string.params.insert(String::from("fuel"), Param::String(String::from("n/a")));
+    with_string.params.insert(String::from("driver"), Param::String(String::from("Ivanov")));
+
+    // two rows fill the partition and write it
+    store.save_batch(&[with_int, with_string]).unwrap();
+    let part_dir = dir.path().join("date=2021-04-28/bucket=00");
+    let files: Vec<PathBuf> = fs::read_dir(&part_dir).unwrap().map(|e| e.unwrap().path()).collect();
+    assert_eq!(files.len(), 1);
+    assert_eq!(files[0].extension().unwrap(), "parquet");
+
+    let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&files[0]).unwrap()).unwrap().build().unwrap();
+    let batch = reader.map(|b| b.unwrap()).next().unwrap();
+    assert_eq!(batch.schema(), store.schema());
+    assert_eq!(batch.num_rows(), 2);
+
+    let fuel = batch.column_by_name("param_fuel").unwrap().as_primitive::<arrow_array::types::Float64Type>();
+    assert_eq!(fuel.value(0), 40.0);
+    assert!(fuel.is_null(1));
+
+    // the value which doesn't fit the column goes to the map with the undeclared param
+    let params = batch.column_by_name("params").unwrap().as_any().downcast_ref::<MapArray>().unwrap();
+    assert_eq!(params.value(0).len(), 0);
+    let second = params.value(1);
+    let keys: Vec<&str> = second.column(0).as_string::<i32>().iter().map(|k| k.unwrap()).collect();
+    let values: Vec<&str> = second.column(1).as_string::<i32>().iter().map(|v| v.unwrap()).collect();
+    assert_eq!(keys, vec!["driver", "fuel"]);
+    assert_eq!(values, vec!["Ivanov", "n/a"]);
+}
+
+#[test]
+fn test_arrow_store_write_retry() {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = ArrowConfig::new(dir.path().to_str().unwrap());
+    config.buckets = 1;
+    config.max_rows = 2;
+    config.max_age = Duration::from_millis(500);
+    let store = ArrowStore::new(config).unwrap();
+
+    let rows = || -> i64 {
+        let part_dir = dir.path().join("date=2021-04-28/bucket=00");
+        fs::read_dir(&part_dir).map(|files| files.map(|f| {
+            SerializedFileReader::new(File::open(f.unwrap().path()).unwrap()).unwrap().metadata().file_metadata().num_rows()
+        }).sum()).unwrap_or(0)
+    };
+
+    // a file where the partition directory should be fails the write, the full batch stays buffered
+    let blocker = dir.path().join("date=2021-04-28");
+    File::create(&blocker).unwrap();
+    store.save_batch(&[test_packet("1"), test_packet("2")]).unwrap();
+    assert!(store.save(test_packet("3")).unwrap_err().is_transient());
+
+    fs::remove_file(&blocker).unwrap();
+    store.save(test_packet("3")).unwrap();
+    assert_eq!(rows(), 2);
+
+    // the single row gets old without anything else saved
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(rows(), 3);
+}
@@ -0,0 +1,98 @@
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::str;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::wialon::{Packet, ResponsePacket};
+
+// Upper bound for a single line, protects against clients that never send "\r\n".
+const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Single "#TYPE#body\r\n" message cut out of the stream.
+#[derive(Debug)]
+pub struct Frame {
+    data: BytesMut,
+}
+
+impl Frame {
+    pub fn packet(&self) -> Result<Packet<'_>, &str> {
+        Packet::from(&self.data)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Splits the incoming stream into Wialon IPS messages and writes responses back.
+#[derive(Debug, Default)]
+pub struct WialonCodec {
+    // position up to which the buffer was already scanned for a line end
+    next_index: usize,
+}
+
+impl WialonCodec {
+    pub fn new() -> WialonCodec {
+        WialonCodec { next_index: 0 }
+    }
+}
+
+impl Decoder for WialonCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, io::Error> {
+        let start = self.next_index.saturating_sub(1);
+        match src[start..].windows(2).position(|w| w == b"\r\n") {
+            Some(pos) => {
+                self.next_index = 0;
+                let data = src.split_to(start + pos + 2);
+                if str::from_utf8(&data).is_err() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Не корректное сообщение"));
+                }
+                Ok(Some(Frame { data }))
+            }
+            None if src.len() > MAX_FRAME_LEN => {
+                src.advance(src.len());
+                self.next_index = 0;
+                Err(io::Error::new(io::ErrorKind::InvalidData, "Слишком длинное сообщение"))
+            }
+            None => {
+                self.next_index = src.len();
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<ResponsePacket> for WialonCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: ResponsePacket, dst: &mut BytesMut) -> Result<(), io::Error> {
+        dst.extend_from_slice(item.to_string().as_bytes());
+        Ok(())
+    }
+}
+
+#[test]
+fn test_codec() {
+    let mut codec = WialonCodec::new();
+    let mut buf = BytesMut::from(&b"#L#1;1\r\n#SD#280421;055447;5355.09260;N;"[..]);
+
+    let frame = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(frame.as_bytes(), b"#L#1;1\r\n");
+    assert!(frame.packet().unwrap().is_auth_packet());
+
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(b"02732.40990;E;60;0;300;7\r");
+    assert!(codec.decode(&mut buf).unwrap().is_none());
+    buf.extend_from_slice(b"\n");
+
+    let frame = codec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(frame.packet().unwrap().ptype, "SD");
+    assert!(buf.is_empty());
+
+    let mut out = BytesMut::new();
+    codec.encode(ResponsePacket { ptype: String::from("ASD"), code: String::from("1") }, &mut out).unwrap();
+    assert_eq!(&out[..], b"#ASD#1\r\n");
+}
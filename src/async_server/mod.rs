@@ -0,0 +1,136 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{SinkExt, StreamExt};
+use log::{info, error};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+use crate::store::GeoPacket;
+
+mod codec;
+pub use codec::{Frame, WialonCodec};
+
+mod store;
+pub use store::{AsyncStore, BlockingStore};
+
+/// Tokio based counterpart of `server::Server`, meant to be embedded into an existing runtime.
+pub struct AsyncServer<T: AsyncStore> {
+    addr: SocketAddr,
+    buf_size: usize,
+    db: Arc<T>,
+}
+
+impl<T: AsyncStore> AsyncServer<T> {
+    pub fn new(addr: &str, buf_size: usize, db: T) -> AsyncServer<T> {
+        AsyncServer {
+            addr: addr.parse().unwrap(),
+            buf_size,
+            db: Arc::new(db),
+        }
+    }
+
+    pub async fn start(&self) -> io::Result<()> {
+        let listener = TcpListener::bind(self.addr).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve connections from an already bound listener.
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        let (sender, mut receiver) = mpsc::channel::<GeoPacket>(self.buf_size.max(1));
+
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            while let Some(p) = receiver.recv().await {
+                if let Err(err) = db.save(p).await {
+                    error!("{}", err);
+                }
+            }
+        });
+
+        info!("Start async server: {}", listener.local_addr()?);
+        loop {
+            let (socket, address) = listener.accept().await?;
+            info!("Accepted connection from: {}", address);
+
+            let bus = sender.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_connection(socket, bus).await {
+                    error!("connection error: {:?}", err);
+                }
+                info!("Connection closed");
+            });
+        }
+    }
+}
+
+async fn handle_connection(socket: TcpStream, bus: mpsc::Sender<GeoPacket>) -> io::Result<()> {
+    let mut imei: Vec<u8> = vec![0, 100];
+    let mut framed = Framed::new(socket, WialonCodec::new());
+
+    while let Some(frame) = framed.next().await {
+        let frame = frame?;
+        let response = match frame.packet() {
+            Ok(p) => {
+                info!("receiver packet: {:?}", p);
+                if p.is_auth_packet() {
+                    // TODO: auth process
+                    let auth = p.get_auth_data().unwrap();
+                    info!("auth: {:?}", auth);
+
+                    imei = auth.imei.as_bytes().to_vec();
+                }
+                for m in p.get_messages() {
+                    let packet = GeoPacket::from_packet(imei.to_owned(), m)
+                        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                    if bus.send(packet).await.is_err() {
+                        return Err(io::Error::new(io::ErrorKind::BrokenPipe, "store stopped"));
+                    }
+                }
+
+                match p.response(1) {
+                    Ok(r) => r,
+                    Err(err) => {
+                        error!("{:?}", err);
+                        continue;
+                    }
+                }
+            }
+            // like the blocking server, hang up on garbage
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("malformed packet: {}", err))),
+        };
+
+        framed.send(response).await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_async_server() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::default_store::ConsoleStore;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let s = AsyncServer::new("127.0.0.1:0", 100, BlockingStore::new(ConsoleStore::new()));
+        s.serve(listener).await
+    });
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let rlt = &mut [0; 128];
+
+    stream.write_all(b"#L#1;1\r\n").await.unwrap();
+    let sz = stream.read(rlt).await.unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+
+    stream.write_all(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n").await.unwrap();
+    let sz = stream.read(rlt).await.unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+
+    stream.write_all(b"#D#280421;055500;5355.09260;N;02732.40990;E;60;0;300;7;22;5;5120;;eee;test1:1:1,var:2:4.5,texttest:3:1\r\n").await.unwrap();
+    let sz = stream.read(rlt).await.unwrap();
+    assert_eq!(&rlt[0..sz], b"#AD#1\r\n");
+}
@@ -0,0 +1,30 @@
+use std::future::Future;
+use std::sync::Arc;
+
+use crate::store::{GeoPacket, Store, StoreError};
+
+/// Async counterpart of `Store` for backends living in the tokio runtime.
+pub trait AsyncStore: Send + Sync + 'static {
+    fn save(&self, p: GeoPacket) -> impl Future<Output = Result<(), StoreError>> + Send;
+}
+
+/// Runs a blocking `Store` on the runtime's blocking pool.
+pub struct BlockingStore<T: Store + Send + Sync + 'static> {
+    db: Arc<T>,
+}
+
+impl<T: Store + Send + Sync + 'static> BlockingStore<T> {
+    pub fn new(db: T) -> BlockingStore<T> {
+        BlockingStore { db: Arc::new(db) }
+    }
+}
+
+impl<T: Store + Send + Sync + 'static> AsyncStore for BlockingStore<T> {
+    async fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        let db = self.db.clone();
+        match tokio::task::spawn_blocking(move || db.save(p)).await {
+            Ok(r) => r,
+            Err(err) => Err(StoreError::Permanent(err.to_string())),
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Decides whether a device may log in, see `Server::set_authenticator`.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, imei: &str, password: &str) -> bool;
+}
+
+/// Fixed set of devices with their passwords, any other device is refused.
+/// A password of `*` lets the device in with any password.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceList {
+    devices: HashMap<String, String>,
+}
+
+impl DeviceList {
+    pub fn new() -> DeviceList {
+        DeviceList { devices: HashMap::new() }
+    }
+
+    pub fn add(&mut self, imei: &str, password: &str) {
+        self.devices.insert(imei.to_string(), password.to_string());
+    }
+
+    /// Reads `imei password` lines, empty lines and lines starting with `#` are skipped.
+    pub fn from_file(path: &Path) -> io::Result<DeviceList> {
+        let mut list = DeviceList::new();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+                [imei, password] => list.add(imei, password),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("{}:{}: expected `imei password`", path.display(), n + 1))),
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+}
+
+impl Authenticator for DeviceList {
+    fn authenticate(&self, imei: &str, password: &str) -> bool {
+        match self.devices.get(imei) {
+            Some(p) => p == "*" || p == password,
+            None => false,
+        }
+    }
+}
+
+#[test]
+fn test_device_list_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("devices");
+    fs::write(&path, "# fleet\n861230043907626 secret\n\n861230043907627 *\n").unwrap();
+
+    let list = DeviceList::from_file(&path).unwrap();
+    assert_eq!(list.len(), 2);
+    assert!(list.authenticate("861230043907626", "secret"));
+    assert!(!list.authenticate("861230043907626", "NA"));
+    assert!(list.authenticate("861230043907627", "NA"));
+    assert!(!list.authenticate("1", "secret"));
+
+    fs::write(&path, "861230043907626\n").unwrap();
+    assert!(DeviceList::from_file(&path).is_err());
+}
@@ -0,0 +1,450 @@
+//! Raw traffic of selected devices, for reproducing what a tracker sent.
+//!
+//! Every captured connection gets its own file. It starts with `WCAP`, a format version byte,
+//! the connection time in microseconds since the epoch (u64, little endian) and the peer
+//! address (varint length and UTF-8), followed by records: direction (0 from the device,
+//! 1 to the device), microseconds since the previous record, length and the bytes, all
+//! numbers but the direction as LEB128 varints. Passwords of `#L#` packets are recorded as `***`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{info, error};
+use crate::ack::Delivery;
+use crate::composite_store::matches_pattern;
+use crate::connection::{Connection, ConnectionSettings};
+use crate::queue;
+use crate::store::GeoPacket;
+use crate::transport::{MemoryStream, Transport};
+
+const MAGIC: &[u8] = b"WCAP";
+const VERSION: u8 = 1;
+
+// Traffic kept while waiting for the login to tell whether the device is captured.
+const MAX_PENDING: usize = 64 * 1024;
+
+// Written instead of login passwords.
+const REDACTED: &[u8] = b"***";
+
+/// Which connections are captured and where the files go, see `Server::set_capture`.
+#[derive(Clone, Debug)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    /// IMEI patterns with `*` and `?` wildcards, `*` captures every device.
+    pub imeis: Vec<String>,
+    /// Peer addresses captured from the first byte on, before the device logs in.
+    pub addrs: Vec<IpAddr>,
+}
+
+impl CaptureConfig {
+    pub fn new(dir: &Path) -> CaptureConfig {
+        CaptureConfig { dir: dir.to_path_buf(), imeis: Vec::new(), addrs: Vec::new() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    /// Sent by the device.
+    Inbound,
+    /// Sent to the device.
+    Outbound,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub direction: Direction,
+    /// Time since the connection was accepted.
+    pub time: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Contents of a capture file.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    pub peer: String,
+    /// When the connection was accepted, UTC.
+    pub started: NaiveDateTime,
+    pub records: Vec<Record>,
+}
+
+impl Capture {
+    /// Reads a capture file. A record cut short by a crash ends the capture without an error.
+    pub fn read(path: &Path) -> io::Result<Capture> {
+        let data = fs::read(path)?;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}: not a capture file", path.display()));
+        if data.len() < MAGIC.len() + 9 || &data[..MAGIC.len()] != MAGIC || data[MAGIC.len()] != VERSION {
+            return Err(invalid());
+        }
+
+        let mut rest = &data[MAGIC.len() + 1..];
+        let mut micros = [0; 8];
+        micros.copy_from_slice(&rest[..8]);
+        rest = &rest[8..];
+        let started = DateTime::from_timestamp_micros(u64::from_le_bytes(micros) as i64).ok_or_else(invalid)?;
+        let peer = read_bytes(&mut rest).ok_or_else(invalid)?;
+
+        let mut records = Vec::new();
+        let mut time = Duration::ZERO;
+        while let Some((&direction, mut tail)) = rest.split_first() {
+            let (delta, data) = match (read_varint(&mut tail), read_bytes(&mut tail)) {
+                (Some(delta), Some(data)) => (delta, data),
+                _ => break,
+            };
+            time += Duration::from_micros(delta);
+            let direction = if direction == 0 { Direction::Inbound } else { Direction::Outbound };
+            records.push(Record { direction, time, data: data.to_vec() });
+            rest = tail;
+        }
+
+        Ok(Capture {
+            peer: String::from_utf8_lossy(peer).into_owned(),
+            started: started.naive_utc(),
+            records,
+        })
+    }
+
+    /// Everything the device sent, in order.
+    pub fn inbound(&self) -> impl Iterator<Item = &Record> {
+        self.records.iter().filter(|r| r.direction == Direction::Inbound)
+    }
+
+    /// What the server answered to each inbound record, up to the next one.
+    pub fn answers(&self) -> Vec<Vec<u8>> {
+        let mut answers: Vec<Vec<u8>> = Vec::new();
+        for r in &self.records {
+            match (r.direction, answers.last_mut()) {
+                (Direction::Inbound, _) => answers.push(Vec::new()),
+                (Direction::Outbound, Some(answer)) => answer.extend_from_slice(&r.data),
+                // server commands sent before the device said anything
+                (Direction::Outbound, None) => {}
+            }
+        }
+        answers
+    }
+}
+
+/// Outcome of feeding a capture through a `Connection`.
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// What the connection answered to each inbound record.
+    pub answers: Vec<Vec<u8>>,
+    /// Positions the connection handed to the store.
+    pub packets: Vec<GeoPacket>,
+    /// The connection closed itself before the capture ended.
+    pub closed: bool,
+}
+
+/// Feeds the inbound records of a capture, one read per record as they arrived, through a
+/// `Connection` without a socket and collects what it answers and stores. Timing is ignored,
+/// so the same capture always gives the same result.
+pub fn replay(capture: &Capture) -> io::Result<Replay> {
+    let inbound: Vec<&Record> = capture.inbound().collect();
+    // every message takes more than a byte, so the queue never fills up
+    let capacity = inbound.iter().map(|r| r.data.len()).sum::<usize>() + 1;
+    let (bus, receiver, _) = queue::channel(capacity);
+    let settings = ConnectionSettings { allow_plain_login: true, ..ConnectionSettings::default() };
+    let mut connection = Connection::new(Transport::Memory(MemoryStream::default()), bus, settings);
+
+    let mut replay = Replay::default();
+    for (i, record) in inbound.iter().enumerate() {
+        if let Transport::Memory(stream) = connection.transport_mut() {
+            stream.input.extend_from_slice(&record.data);
+            stream.closed = i + 1 == inbound.len();
+        }
+        let closed = connection.get_message()?;
+
+        if let Transport::Memory(stream) = connection.transport_mut() {
+            replay.answers.push(std::mem::take(&mut stream.output));
+        }
+        replay.packets.extend(receiver.try_iter().map(|d: Delivery| d.packet));
+        if closed || connection.is_closed() {
+            replay.closed = i + 1 < inbound.len();
+            break;
+        }
+    }
+    Ok(replay)
+}
+
+enum State {
+    // waiting for the login, the records are written if the device turns out to be captured
+    Pending(Vec<Record>),
+    Writing(File),
+    Off,
+}
+
+/// Capture of one connection, fed by the connection with everything it reads and sends.
+pub(crate) struct Recorder {
+    config: Arc<CaptureConfig>,
+    peer: SocketAddr,
+    started: Instant,
+    started_at: DateTime<Utc>,
+    last: Duration,
+    state: State,
+    redactor: Redactor,
+}
+
+impl Recorder {
+    pub fn new(config: Arc<CaptureConfig>, peer: SocketAddr) -> Recorder {
+        let mut recorder = Recorder {
+            config,
+            peer,
+            started: Instant::now(),
+            started_at: Utc::now(),
+            last: Duration::ZERO,
+            state: State::Pending(Vec::new()),
+            redactor: Redactor::default(),
+        };
+        if recorder.config.addrs.contains(&peer.ip()) {
+            let name = peer.ip().to_string().replace(':', "_");
+            recorder.open(&name);
+        } else if recorder.config.imeis.is_empty() {
+            recorder.state = State::Off;
+        }
+        recorder
+    }
+
+    pub fn inbound(&mut self, data: &[u8]) {
+        let data = self.redactor.redact(data);
+        self.record(Direction::Inbound, &data);
+    }
+
+    pub fn outbound(&mut self, data: &[u8]) {
+        self.record(Direction::Outbound, data);
+    }
+
+    /// Device logged in as `imei`, decides whether a connection not captured by address is.
+    pub fn login(&mut self, imei: &str) {
+        if !matches!(self.state, State::Pending(_)) {
+            return;
+        }
+        if self.config.imeis.iter().any(|p| matches_pattern(p, imei)) {
+            // the login itself may have no characters a file name can't take
+            let name: String = imei.chars().filter(|c| c.is_ascii_alphanumeric()).collect();
+            self.open(&name);
+        } else {
+            self.state = State::Off;
+        }
+    }
+
+    fn open(&mut self, name: &str) {
+        let pending = match std::mem::replace(&mut self.state, State::Off) {
+            State::Pending(records) => records,
+            _ => Vec::new(),
+        };
+        let path = self.config.dir.join(format!("{}-{}-{}.wcap", name, self.started_at.format("%Y%m%dT%H%M%S"),
+                                                self.peer.port()));
+
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend_from_slice(&(self.started_at.timestamp_micros() as u64).to_le_bytes());
+        write_bytes(&mut header, self.peer.to_string().as_bytes());
+        let mut last = Duration::ZERO;
+        for r in &pending {
+            encode(&mut header, r, last);
+            last = r.time;
+        }
+
+        let opened = fs::create_dir_all(&self.config.dir)
+            .and_then(|_| create(&path))
+            .and_then(|mut f| f.write_all(&header).map(|_| f));
+        match opened {
+            Ok(file) => {
+                info!("capturing {} to {}", self.peer, path.display());
+                self.state = State::Writing(file);
+            }
+            Err(err) => error!("failed to start capture {}: {:?}", path.display(), err),
+        }
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        let record = Record { direction, time: self.started.elapsed(), data: data.to_vec() };
+        match &mut self.state {
+            State::Pending(records) => {
+                records.push(record);
+                if records.iter().map(|r| r.data.len()).sum::<usize>() > MAX_PENDING {
+                    self.state = State::Off;
+                }
+            }
+            State::Writing(file) => {
+                let mut buf = Vec::with_capacity(data.len() + 16);
+                encode(&mut buf, &record, self.last);
+                self.last = record.time;
+                if let Err(err) = file.write_all(&buf) {
+                    error!("capture of {} stopped: {:?}", self.peer, err);
+                    self.state = State::Off;
+                }
+            }
+            State::Off => {}
+        }
+    }
+}
+
+// Captures hold positions of devices, only the owner may read them.
+fn create(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+}
+
+/// Replaces the password of `#L#` packets with `***`, also when a packet is split across reads.
+/// The password is the second field, or the third one after the `2.0` of a 2.0 login.
+#[derive(Default)]
+struct Redactor {
+    // characters of `#L#` matched so far
+    matched: usize,
+    // field of the login being read and the text of its first field
+    login: Option<(usize, Vec<u8>)>,
+}
+
+impl Redactor {
+    fn redact(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            let (field, first) = match &mut self.login {
+                Some(login) => login,
+                None => {
+                    self.matched = match (b, self.matched) {
+                        (b'#', 2) => 3,
+                        (b'L', 1) => 2,
+                        (b'#', _) => 1,
+                        _ => 0,
+                    };
+                    if self.matched == 3 {
+                        self.login = Some((0, Vec::new()));
+                        self.matched = 0;
+                    }
+                    out.push(b);
+                    continue;
+                }
+            };
+
+            let password = if first.as_slice() == b"2.0" { 2 } else { 1 };
+            match b {
+                b'\r' | b'\n' | b'#' => {
+                    self.login = None;
+                    self.matched = if b == b'#' { 1 } else { 0 };
+                    out.push(b);
+                }
+                b';' => {
+                    *field += 1;
+                    out.push(b);
+                    if *field == password {
+                        out.extend_from_slice(REDACTED);
+                    }
+                }
+                _ if *field == password => {}
+                _ => {
+                    if *field == 0 {
+                        first.push(b);
+                    }
+                    out.push(b);
+                }
+            }
+        }
+        out
+    }
+}
+
+fn encode(buf: &mut Vec<u8>, record: &Record, last: Duration) {
+    buf.push(if record.direction == Direction::Inbound { 0 } else { 1 });
+    write_varint(buf, record.time.saturating_sub(last).as_micros() as u64);
+    write_bytes(buf, &record.data);
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, data: &[u8]) {
+    write_varint(buf, data.len() as u64);
+    buf.extend_from_slice(data);
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut n = 0u64;
+    for (i, &b) in buf.iter().enumerate().take(10) {
+        n |= ((b & 0x7f) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(n);
+        }
+    }
+    None
+}
+
+fn read_bytes<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = read_varint(buf)? as usize;
+    if buf.len() < len {
+        return None;
+    }
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(data)
+}
+
+#[test]
+fn test_capture_and_replay() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = CaptureConfig::new(dir.path());
+    config.imeis.push(String::from("8612*"));
+    let config = Arc::new(config);
+    let peer: SocketAddr = "10.0.0.5:40000".parse().unwrap();
+
+    // devices not matching the patterns leave no file
+    let mut other = Recorder::new(config.clone(), peer);
+    other.inbound(b"#L#1;1\r\n");
+    other.login("1");
+    other.outbound(b"#AL#1\r\n");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    let mut recorder = Recorder::new(config, peer);
+    recorder.inbound(b"#L#861230043907626;NA\r\n");
+    recorder.login("861230043907626");
+    recorder.outbound(b"#AL#1\r\n");
+    recorder.inbound(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n");
+    recorder.outbound(b"#ASD#1\r\n");
+    recorder.inbound(b"#P#\r\n");
+    recorder.outbound(b"#AP#\r\n");
+    recorder.inbound(b"wewe\r\n");
+    recorder.inbound(b"#P#\r\n");
+    drop(recorder);
+
+    let path = fs::read_dir(dir.path()).unwrap().next().unwrap().unwrap().path();
+    #[cfg(unix)]
+    assert_eq!(std::os::unix::fs::PermissionsExt::mode(&fs::metadata(&path).unwrap().permissions()) & 0o777, 0o600);
+    assert!(path.file_name().unwrap().to_str().unwrap().starts_with("861230043907626-"));
+    let capture = Capture::read(&path).unwrap();
+    assert_eq!(capture.peer, "10.0.0.5:40000");
+    assert_eq!(capture.records.len(), 8);
+    assert_eq!(capture.records[0].data, b"#L#861230043907626;***\r\n");
+    assert!(capture.records.windows(2).all(|w| w[0].time <= w[1].time));
+
+    // the server hangs up on garbage, the ping after it is never read
+    let replay = replay(&capture).unwrap();
+    assert_eq!(replay.answers, capture.answers()[..4]);
+    assert_eq!(replay.packets.len(), 1);
+    assert_eq!(replay.packets[0].imei, "861230043907626");
+    assert!(replay.closed);
+
+    // a crash in the middle of a record loses only that record
+    let data = fs::read(&path).unwrap();
+    fs::write(&path, &data[..data.len() - 3]).unwrap();
+    assert_eq!(Capture::read(&path).unwrap().records.len(), 7);
+
+    // passwords split across reads don't leak either
+    let mut redactor = Redactor::default();
+    let chunks: [&[u8]; 4] = [b"#P#\r\n#L", b"#2.0;8612;sec", b"ret;ABCD\r\n#L#8612;", b"pass\r\n#SD#1;2\r\n"];
+    let redacted: Vec<u8> = chunks.iter().flat_map(|c| redactor.redact(c)).collect();
+    assert_eq!(redacted, b"#P#\r\n#L#2.0;8612;***;ABCD\r\n#L#8612;***\r\n#SD#1;2\r\n");
+}
@@ -0,0 +1,544 @@
+//! Device side of Wialon IPS: logs in to a server such as Wialon Hosting and sends positions.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use serde::Deserialize;
+
+use log::{info, warn};
+use crate::store::{GeoPacket, Param};
+use crate::wialon::ResponsePacket;
+
+// Params sent in their own fields of a D packet instead of the parameter list.
+const DATA_FIELDS: [&str; 5] = ["hdop", "inputs", "outputs", "adc", "ibutton"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize)]
+pub enum ProtocolVersion {
+    #[default]
+    #[serde(rename = "1.1")]
+    V1_1,
+    /// Adds a CRC16 to every packet.
+    #[serde(rename = "2.0")]
+    V2_0,
+}
+
+#[derive(Clone, Debug)]
+pub struct ClientConfig {
+    pub addr: String,
+    pub imei: String,
+    pub password: String,
+    pub version: ProtocolVersion,
+    /// Longest wait for connecting and for an answer.
+    pub timeout: Duration,
+    /// Wait before reconnecting, doubled after every failure up to `max_reconnect_delay`.
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// Messages kept while the server is unreachable, the oldest are dropped beyond it.
+    pub black_box_size: usize,
+    /// Messages sent in one `#B#` packet.
+    pub batch_size: usize,
+    /// Times a batch the server didn't take is sent again before its messages are dropped, `None` keeps
+    /// them until it does.
+    pub max_retries: Option<u32>,
+}
+
+impl ClientConfig {
+    pub fn new(addr: &str, imei: &str) -> ClientConfig {
+        ClientConfig {
+            addr: addr.to_string(),
+            imei: imei.to_string(),
+            password: String::from("NA"),
+            version: ProtocolVersion::V1_1,
+            timeout: Duration::from_secs(10),
+            reconnect_delay: Duration::from_secs(1),
+            max_reconnect_delay: Duration::from_secs(60),
+            black_box_size: 10000,
+            batch_size: 50,
+            max_retries: Some(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// Server refused the login, e.g. `#AL#01` for a wrong password.
+    Login(ResponsePacket),
+    /// Server answered with an error code, the packet is dropped.
+    Rejected(ResponsePacket),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Io(err) => write!(f, "{}", err),
+            ClientError::Login(r) => write!(f, "login refused: {}", r.to_string().trim_end()),
+            ClientError::Rejected(r) => write!(f, "packet rejected: {}", r.to_string().trim_end()),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> ClientError {
+        ClientError::Io(err)
+    }
+}
+
+/// What happened to a packet handed to the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sent {
+    /// Server confirmed it.
+    Delivered,
+    /// Server is unreachable or asked to send it again later, the packet waits in the black box.
+    Buffered,
+}
+
+struct Session {
+    stream: TcpStream,
+    answers: BufReader<TcpStream>,
+}
+
+/// Connection to an IPS server. Connects on first use and, when the connection breaks, keeps
+/// positions in a black box which is sent as `#B#` packets once the server is back.
+pub struct Client {
+    config: ClientConfig,
+    session: Option<Session>,
+    black_box: VecDeque<String>,
+    delay: Duration,
+    retry_at: Instant,
+    // times in a row the server didn't take the first batch of the black box
+    refusals: u32,
+}
+
+impl Client {
+    pub fn new(config: ClientConfig) -> Client {
+        Client {
+            delay: config.reconnect_delay,
+            config,
+            session: None,
+            black_box: VecDeque::new(),
+            retry_at: Instant::now(),
+            refusals: 0,
+        }
+    }
+
+    /// Connects, logs in and sends the black box.
+    pub fn connect(&mut self) -> Result<(), ClientError> {
+        self.session = None;
+        let addrs: Vec<_> = self.config.addr.to_socket_addrs()?.collect();
+        let mut stream = Err(io::Error::new(io::ErrorKind::NotFound, format!("{}: no address", self.config.addr)));
+        for addr in addrs {
+            stream = TcpStream::connect_timeout(&addr, self.config.timeout);
+            if stream.is_ok() {
+                break;
+            }
+        }
+        let stream = stream?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        stream.set_write_timeout(Some(self.config.timeout))?;
+        self.session = Some(Session { answers: BufReader::new(stream.try_clone()?), stream });
+
+        let login = match self.config.version {
+            ProtocolVersion::V1_1 => format!("#L#{};{}\r\n", self.config.imei, self.config.password),
+            ProtocolVersion::V2_0 => self.frame("L", &format!("2.0;{};{}", self.config.imei, self.config.password)),
+        };
+        let answer = self.request(&login, "AL")?;
+        if answer.code != "1" {
+            self.session = None;
+            return Err(ClientError::Login(answer));
+        }
+        info!("{} logged in to {}", self.config.imei, self.config.addr);
+        self.delay = self.config.reconnect_delay;
+        self.flush()?;
+        Ok(())
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.session.is_some()
+    }
+
+    pub fn disconnect(&mut self) {
+        self.session = None;
+    }
+
+    /// Messages waiting in the black box.
+    pub fn buffered(&self) -> usize {
+        self.black_box.len()
+    }
+
+    /// Sends a position as SD packet, or as D packet if it has params or came from one.
+    /// When reconnecting fails on a refused login the error is returned, the position stays in the black box.
+    pub fn send(&mut self, packet: &GeoPacket) -> Result<Sent, ClientError> {
+        let message = encode(packet);
+        // older positions go first
+        if !self.black_box.is_empty() {
+            self.buffer(message);
+            self.flush()?;
+            return Ok(if self.black_box.is_empty() { Sent::Delivered } else { Sent::Buffered });
+        }
+        match self.ensure_connected() {
+            Ok(true) => {}
+            Ok(false) => {
+                self.buffer(message);
+                return Ok(Sent::Buffered);
+            }
+            Err(err) => {
+                self.buffer(message);
+                return Err(err);
+            }
+        }
+
+        let ptype = message_type(packet);
+        let data = self.frame(ptype, &message);
+        match self.request(&data, &format!("A{}", ptype)) {
+            Ok(r) if r.code == "1" => Ok(Sent::Delivered),
+            Ok(r) if r.is_retry() => {
+                warn!("{}: {} can't take the position now, retry in {:?}", self.config.imei, self.config.addr, self.delay);
+                self.back_off();
+                self.buffer(message);
+                Ok(Sent::Buffered)
+            }
+            Ok(r) => Err(ClientError::Rejected(r)),
+            Err(ClientError::Io(err)) => {
+                self.connection_lost(&err);
+                self.buffer(message);
+                Ok(Sent::Buffered)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Sends positions collected meanwhile as black box, oldest first.
+    pub fn send_black_box(&mut self, packets: &[GeoPacket]) -> Result<Sent, ClientError> {
+        for p in packets {
+            self.buffer(encode(p));
+        }
+        self.flush()?;
+        Ok(if self.black_box.is_empty() { Sent::Delivered } else { Sent::Buffered })
+    }
+
+    /// Checks the connection with `#P#`.
+    pub fn ping(&mut self) -> Result<(), ClientError> {
+        if !self.ensure_connected()? {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "waiting to reconnect").into());
+        }
+        match self.request("#P#\r\n", "AP") {
+            Err(ClientError::Io(err)) => {
+                self.connection_lost(&err);
+                Err(ClientError::Io(err))
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    /// Sends the black box in batches of `batch_size` while the server is reachable and takes them.
+    /// Returns the number of messages left.
+    pub fn flush(&mut self) -> Result<usize, ClientError> {
+        while !self.black_box.is_empty() {
+            // backing off after a refused batch
+            if Instant::now() < self.retry_at {
+                break;
+            }
+            // connecting flushes the black box itself
+            if !self.ensure_connected()? || self.black_box.is_empty() {
+                break;
+            }
+            let n = self.black_box.len().min(self.config.batch_size.max(1));
+            let mut body = String::new();
+            for m in self.black_box.iter().take(n) {
+                body.push_str(m);
+                body.push('|');
+            }
+
+            let data = self.frame("B", &body);
+            match self.request(&data, "AB") {
+                Ok(r) => match r.code.parse::<usize>() {
+                    // the server took the first `code` messages
+                    Ok(accepted) if accepted > 0 => {
+                        self.black_box.drain(..accepted.min(n));
+                        self.refusals = 0;
+                        self.delay = self.config.reconnect_delay;
+                    }
+                    // nothing taken, e.g. the queue of the server is full, the batch is sent again later
+                    _ if self.config.max_retries.is_none_or(|max| self.refusals < max) => {
+                        self.refusals += 1;
+                        warn!("{}: {} took no messages ({}), retry {} in {:?}", self.config.imei, self.config.addr,
+                              r.to_string().trim_end(), self.refusals, self.delay);
+                        self.back_off();
+                        break;
+                    }
+                    _ => {
+                        self.refusals = 0;
+                        for m in self.black_box.drain(..n) {
+                            warn!("{}: {} took no messages, dropping {}", self.config.imei, self.config.addr, m);
+                        }
+                        return Err(ClientError::Rejected(r));
+                    }
+                },
+                Err(ClientError::Io(err)) => {
+                    self.connection_lost(&err);
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(self.black_box.len())
+    }
+
+    // Reconnects unless the last attempt is too recent, returns whether there is a session.
+    fn ensure_connected(&mut self) -> Result<bool, ClientError> {
+        if self.session.is_some() {
+            return Ok(true);
+        }
+        if Instant::now() < self.retry_at {
+            return Ok(false);
+        }
+        match self.connect() {
+            Ok(()) => Ok(self.session.is_some()),
+            Err(ClientError::Io(err)) => {
+                self.connection_lost(&err);
+                Ok(false)
+            }
+            Err(err) => {
+                self.connection_lost(&io::Error::other(err.to_string()));
+                Err(err)
+            }
+        }
+    }
+
+    fn connection_lost(&mut self, err: &io::Error) {
+        warn!("{}: connection to {} lost: {}", self.config.imei, self.config.addr, err);
+        self.session = None;
+        self.back_off();
+    }
+
+    // Waits before the next attempt, twice as long as before up to `max_reconnect_delay`.
+    fn back_off(&mut self) {
+        self.retry_at = Instant::now() + self.delay;
+        self.delay = (self.delay * 2).min(self.config.max_reconnect_delay);
+    }
+
+    fn buffer(&mut self, message: String) {
+        if self.black_box.len() >= self.config.black_box_size.max(1) {
+            warn!("{}: black box is full, dropping the oldest position", self.config.imei);
+            self.black_box.pop_front();
+        }
+        self.black_box.push_back(message);
+    }
+
+    // Packet on the wire, version 2.0 adds the CRC16 of the body.
+    fn frame(&self, ptype: &str, body: &str) -> String {
+        match self.config.version {
+            ProtocolVersion::V1_1 => format!("#{}#{}\r\n", ptype, body),
+            ProtocolVersion::V2_0 if ptype == "B" => format!("#B#{}{:04X}\r\n", body, crc16(body.as_bytes())),
+            ProtocolVersion::V2_0 => {
+                let body = format!("{};", body);
+                format!("#{}#{}{:04X}\r\n", ptype, body, crc16(body.as_bytes()))
+            }
+        }
+    }
+
+    fn request(&mut self, data: &str, answer_type: &str) -> Result<ResponsePacket, ClientError> {
+        let session = match &mut self.session {
+            Some(s) => s,
+            None => return Err(io::Error::from(io::ErrorKind::NotConnected).into()),
+        };
+        session.stream.write_all(data.as_bytes())?;
+
+        let mut answer = String::new();
+        if session.answers.read_line(&mut answer)? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        match ResponsePacket::parse(&answer) {
+            Ok(r) if r.ptype == answer_type => Ok(r),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                    format!("unexpected answer {:?} to {}", answer.trim_end(), answer_type)).into()),
+        }
+    }
+}
+
+/// CRC-16/ARC of IPS 2.0 packets.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+/// `D` for positions with params or from a D packet, `SD` otherwise.
+pub fn message_type(p: &GeoPacket) -> &'static str {
+    if p.ptype == "D" || !p.params.is_empty() { "D" } else { "SD" }
+}
+
+/// Body of the SD or D packet of a position, without the packet type. The params `hdop`,
+/// `inputs`, `outputs`, `adc` and `ibutton` go to their own fields of a D packet.
+pub fn encode(p: &GeoPacket) -> String {
+    let mut body = format!("{};{};{};{};{};{};{};{};{}",
+                           p.timestamp.format("%d%m%y;%H%M%S"),
+                           degrees_minutes(p.lat.abs(), 2), if p.lat < 0.0 { "S" } else { "N" },
+                           degrees_minutes(p.lon.abs(), 3), if p.lon < 0.0 { "W" } else { "E" },
+                           p.speed, p.course, p.height, p.sats);
+    if message_type(p) == "SD" {
+        return body;
+    }
+
+    for name in DATA_FIELDS {
+        let value = match (name, p.params.get(name)) {
+            ("adc", None) => String::new(),
+            (_, None) => String::from("NA"),
+            (_, Some(v)) => v.to_string(),
+        };
+        body.push(';');
+        body.push_str(&value);
+    }
+
+    let params: Vec<String> = p.params.iter()
+        .filter(|(name, _)| !DATA_FIELDS.contains(&name.as_str()))
+        .map(|(name, value)| match value {
+            Param::Int(v) => format!("{}:1:{}", name, v),
+            Param::Float(v) => format!("{}:2:{}", name, v),
+            Param::String(v) => format!("{}:3:{}", name, v),
+        })
+        .collect();
+    body.push(';');
+    body.push_str(&if params.is_empty() { String::from("NA") } else { params.join(",") });
+    body
+}
+
+/// Coordinate as degrees and minutes, `DDMM.MMMMM` with `width` digits of degrees.
+pub(crate) fn degrees_minutes(value: f64, width: usize) -> String {
+    let degrees = value.trunc();
+    format!("{:0width$}{:08.5}", degrees as u32, (value - degrees) * 60.0, width = width)
+}
+
+#[test]
+fn test_client_black_box_after_reconnect() {
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use crate::store::test_packet;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (received, packets) = channel();
+    thread::spawn(move || {
+        for (n, socket) in listener.incoming().enumerate() {
+            let socket = socket.unwrap();
+            let mut writer = socket.try_clone().unwrap();
+            for line in BufReader::new(socket).lines() {
+                let line = line.unwrap();
+                received.send(line.clone()).unwrap();
+                let (ptype, body) = line[1..].split_once('#').unwrap();
+                // 2.0 packets but ping end with the CRC16 of what is before it
+                if ptype != "P" {
+                    let (data, crc) = body.split_at(body.len() - 4);
+                    assert_eq!(format!("{:04X}", crc16(data.as_bytes())), crc);
+                }
+                let answer = match ptype {
+                    "L" if body.contains(";wrong;") => String::from("#AL#01\r\n"),
+                    "L" => String::from("#AL#1\r\n"),
+                    "P" => String::from("#AP#\r\n"),
+                    "B" => format!("#AB#{}\r\n", body.matches('|').count()),
+                    // the first connection breaks on its second position
+                    "D" if n == 0 => break,
+                    _ => format!("#A{}#1\r\n", ptype),
+                };
+                writer.write_all(answer.as_bytes()).unwrap();
+            }
+        }
+    });
+
+    let mut config = ClientConfig::new(&addr, "861230043907626");
+    config.version = ProtocolVersion::V2_0;
+    config.reconnect_delay = Duration::ZERO;
+    let mut client = Client::new(config.clone());
+
+    let sd = test_packet("861230043907626");
+    let mut d = sd.clone();
+    d.params.insert(String::from("hdop"), Param::Float(1.5));
+    d.params.insert(String::from("fuel"), Param::Int(40));
+
+    assert_eq!(client.send(&sd).unwrap(), Sent::Delivered);
+    assert_eq!(client.send(&d).unwrap(), Sent::Buffered);
+    assert_eq!(client.buffered(), 1);
+    // reconnects and sends the buffered position together with the new one
+    assert_eq!(client.send(&sd).unwrap(), Sent::Delivered);
+    assert_eq!(client.buffered(), 0);
+    client.ping().unwrap();
+
+    let lines: Vec<String> = packets.try_iter().collect();
+    assert!(lines[0].starts_with("#L#2.0;861230043907626;NA;"));
+    assert!(lines[1].starts_with("#SD#280421;055220;5355.09260;N;02732.40990;E;0;0;300;7;"));
+    assert!(lines[2].starts_with("#D#280421;055220;") && lines[2].contains(";1.5;NA;NA;;NA;fuel:1:40;"));
+    assert!(lines[4].starts_with("#B#280421;055220;") && lines[4].matches('|').count() == 2);
+    assert_eq!(lines[5], "#P#");
+
+    drop(client);
+    config.password = String::from("wrong");
+    match Client::new(config).connect() {
+        Err(ClientError::Login(r)) => assert_eq!(r.code, "01"),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn test_client_resends_refused_batch() {
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::store::test_packet;
+
+    // takes nothing of the first SD and the first two black boxes of a connection, then everything
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let socket = socket.unwrap();
+            let mut writer = socket.try_clone().unwrap();
+            let (mut black_boxes, mut positions) = (0, 0);
+            for line in BufReader::new(socket).lines() {
+                let line = line.unwrap();
+                let (ptype, body) = line[1..].split_once('#').unwrap();
+                let answer = match ptype {
+                    "B" if black_boxes < 2 => { black_boxes += 1; String::from("#AB#0\r\n") }
+                    "B" => format!("#AB#{}\r\n", body.matches('|').count()),
+                    "SD" if positions == 0 => { positions += 1; ResponsePacket::retry("ASD").to_string() }
+                    _ => format!("#A{}#1\r\n", ptype),
+                };
+                writer.write_all(answer.as_bytes()).unwrap();
+            }
+        }
+    });
+
+    let mut config = ClientConfig::new(&addr, "861230043907626");
+    config.reconnect_delay = Duration::from_millis(10);
+    let mut client = Client::new(config.clone());
+    let sd = test_packet("861230043907626");
+
+    assert_eq!(client.send(&sd).unwrap(), Sent::Buffered);
+    assert_eq!(client.send(&sd).unwrap(), Sent::Buffered);
+    // kept until the server takes them
+    for left in [2, 2, 0] {
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(client.flush().unwrap(), left);
+    }
+
+    // or dropped after `max_retries`
+    drop(client);
+    config.max_retries = Some(0);
+    let mut client = Client::new(config);
+    assert_eq!(client.send(&sd).unwrap(), Sent::Buffered);
+    thread::sleep(Duration::from_millis(100));
+    match client.flush() {
+        Err(ClientError::Rejected(r)) => assert_eq!(r.code, "0"),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(client.buffered(), 0);
+}
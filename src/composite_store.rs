@@ -0,0 +1,255 @@
+use std::thread;
+use crossbeam_channel::{bounded, Sender, TrySendError};
+use serde::Deserialize;
+
+use log::{info, error};
+use crate::ack::Delivery;
+use crate::pipeline::Pipeline;
+use crate::store::{GeoPacket, Store, StoreError};
+
+/// Which packets a sink gets. Empty lists don't restrict anything.
+/// IMEI patterns may use `*` for any number of characters and `?` for exactly one.
+#[derive(Clone, Debug, Default)]
+pub struct Route {
+    pub imei: Vec<String>,
+    pub except_imei: Vec<String>,
+    /// Packet types, e.g. "SD" or "D".
+    pub ptype: Vec<String>,
+}
+
+impl Route {
+    /// Route taking every packet.
+    pub fn all() -> Route {
+        Route::default()
+    }
+
+    pub fn matches(&self, p: &GeoPacket) -> bool {
+        (self.imei.is_empty() || self.imei.iter().any(|pattern| matches_pattern(pattern, &p.imei)))
+            && !self.except_imei.iter().any(|pattern| matches_pattern(pattern, &p.imei))
+            && (self.ptype.is_empty() || self.ptype.contains(&p.ptype))
+    }
+}
+
+/// What a sink does with packets its queue has no room for.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// The packets are dropped for this sink only, the others get the batch anyway.
+    #[default]
+    Drop,
+    /// Saving fails with a transient error, so the batch is retried for every sink. Keeps the sinks in
+    /// step, but a stuck sink holds back all of them.
+    Refuse,
+}
+
+struct Sink {
+    name: String,
+    route: Route,
+    overflow: Overflow,
+    queue: Option<Sender<Delivery>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+/// Fans every packet out to the sinks whose route matches it.
+///
+/// Each sink runs its own `Pipeline` on its own thread behind a bounded queue, so a slow sink doesn't hold
+/// back the others: once its queue is full it drops its packets. Sinks added with `Overflow::Refuse` make
+/// the whole batch wait for them instead, it is queued only when every one of them has room and otherwise
+/// saving fails with a transient error. Saving succeeds once the packets are queued, not when the sinks
+/// stored them.
+#[derive(Default)]
+pub struct CompositeStore {
+    sinks: Vec<Sink>,
+}
+
+impl CompositeStore {
+    pub fn new() -> CompositeStore {
+        CompositeStore::default()
+    }
+
+    /// Adds a sink which is fed through a queue of `queue_size` packets and drops those it has no room for.
+    pub fn add_sink<T: 'static + Store + Send>(&mut self, name: &str, route: Route, pipeline: Pipeline<T>,
+                                                queue_size: usize) {
+        self.add_sink_with_overflow(name, route, pipeline, queue_size, Overflow::Drop);
+    }
+
+    /// Same as `add_sink`, with `Overflow::Refuse` a full sink holds up the others instead of losing packets.
+    pub fn add_sink_with_overflow<T: 'static + Store + Send>(&mut self, name: &str, route: Route,
+                                                              pipeline: Pipeline<T>, queue_size: usize,
+                                                              overflow: Overflow) {
+        let (sender, receiver) = bounded(queue_size);
+        let thread = thread::Builder::new()
+            .name(format!("wialon-sink-{}", name))
+            .spawn(move || pipeline.run(receiver))
+            .expect("failed to spawn sink thread");
+
+        self.sinks.push(Sink {
+            name: name.to_string(),
+            route,
+            overflow,
+            queue: Some(sender),
+            thread: Some(thread),
+        });
+    }
+}
+
+impl Store for CompositeStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    // Room is checked up front, so a refused batch wasn't queued for any sink and its retry
+    // doesn't save anything twice. Only this store sends to the queues, they can't fill up meanwhile.
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        for sink in self.sinks.iter().filter(|s| s.overflow == Overflow::Refuse) {
+            let queue = match &sink.queue {
+                Some(q) => q,
+                None => continue,
+            };
+            let matching = batch.iter().filter(|p| sink.route.matches(p)).count();
+            let capacity = queue.capacity().unwrap_or(usize::MAX);
+            if matching > capacity {
+                return Err(StoreError::Permanent(format!("{} packets don't fit the queue of sink {}",
+                                                         matching, sink.name)));
+            }
+            if matching > capacity - queue.len() {
+                return Err(StoreError::Transient(format!("sink {} is full", sink.name)));
+            }
+        }
+
+        for sink in &self.sinks {
+            let queue = match &sink.queue {
+                Some(q) => q,
+                None => continue,
+            };
+
+            for p in batch.iter().filter(|p| sink.route.matches(p)) {
+                match queue.try_send(Delivery::new(p.clone())) {
+                    Ok(_) => {}
+                    Err(TrySendError::Full(_)) => error!("sink {} is full, packet from {} dropped", sink.name, p.imei),
+                    Err(TrySendError::Disconnected(_)) if sink.overflow == Overflow::Refuse => {
+                        return Err(StoreError::Transient(format!("sink {} stopped", sink.name)));
+                    }
+                    Err(TrySendError::Disconnected(_)) => error!("sink {} stopped, packet from {} dropped", sink.name, p.imei),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CompositeStore {
+    // Lets every sink save what is left in its queue.
+    fn drop(&mut self) {
+        for sink in &mut self.sinks {
+            sink.queue = None;
+        }
+        for sink in &mut self.sinks {
+            if let Some(t) = sink.thread.take() {
+                if t.join().is_err() {
+                    error!("sink {} panicked", sink.name);
+                }
+            }
+        }
+        info!("All sinks stopped");
+    }
+}
+
+// Glob match supporting `*` and `?`.
+pub(crate) fn matches_pattern(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    // position after the last `*` and where the string was when it was seen
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut i) = (0, 0);
+    while i < s.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == s[i]) {
+            p += 1;
+            i += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            star = Some((p, i));
+        } else if let Some((sp, si)) = star {
+            // let the last `*` swallow one more character
+            p = sp;
+            i = si + 1;
+            star = Some((sp, si + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[test]
+fn test_composite_store_routing() {
+    use std::sync::mpsc::{channel, Receiver};
+    use std::time::Duration;
+    use crate::pipeline::PipelineConfig;
+    use crate::store::test_packet;
+
+    struct ChannelStore(std::sync::mpsc::Sender<String>);
+
+    impl Store for ChannelStore {
+        fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+            self.0.send(format!("{}/{}", p.imei, p.ptype)).unwrap();
+            Ok(())
+        }
+    }
+
+    // never finishes saving until the test is over
+    struct StuckStore(Receiver<()>);
+
+    impl Store for StuckStore {
+        fn save(&self, _: GeoPacket) -> Result<(), StoreError> {
+            let _ = self.0.recv();
+            Ok(())
+        }
+    }
+
+    assert!(matches_pattern("35*", "351234"));
+    assert!(matches_pattern("3?1*4", "351234"));
+    assert!(!matches_pattern("35?", "351234"));
+
+    let config = PipelineConfig { batch_size: 1, ..PipelineConfig::default() };
+    let (all_sender, all) = channel();
+    let (special_sender, special) = channel();
+    let (release, stuck) = channel();
+
+    let mut store = CompositeStore::new();
+    let route = Route { except_imei: vec![String::from("99*")], ..Route::all() };
+    store.add_sink("all", route, Pipeline::new(ChannelStore(all_sender), config.clone()), 10);
+    let route = Route { imei: vec![String::from("99*")], ptype: vec![String::from("D")], ..Route::all() };
+    store.add_sink("special", route, Pipeline::new(ChannelStore(special_sender), config.clone()), 10);
+    store.add_sink("stuck", Route::all(), Pipeline::new(StuckStore(stuck), config.clone()), 1);
+
+    let packet = |imei: &str, ptype: &str| GeoPacket { ptype: ptype.to_string(), ..test_packet(imei) };
+    store.save_batch(&[packet("1", "SD"), packet("991", "SD"), packet("992", "D"), packet("2", "D")]).unwrap();
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(all.recv_timeout(timeout).unwrap(), "1/SD");
+    assert_eq!(all.recv_timeout(timeout).unwrap(), "2/D");
+    assert_eq!(special.recv_timeout(timeout).unwrap(), "992/D");
+    assert!(special.try_recv().is_err());
+    // the stuck sink doesn't hold back the others
+    store.save_batch(&[packet("3", "SD"), packet("4", "SD"), packet("5", "SD")]).unwrap();
+    assert_eq!(all.iter().take(3).collect::<Vec<_>>(), vec!["3/SD", "4/SD", "5/SD"]);
+    drop(release);
+
+    // unless it refuses the whole batch when full, then the others don't get it either
+    let (all_sender, all) = channel();
+    let (release, stuck) = channel();
+    let mut store = CompositeStore::new();
+    store.add_sink("all", Route::all(), Pipeline::new(ChannelStore(all_sender), config.clone()), 10);
+    store.add_sink_with_overflow("stuck", Route::all(), Pipeline::new(StuckStore(stuck), config), 2,
+                                 Overflow::Refuse);
+    store.save_batch(&[packet("1", "SD"), packet("2", "SD")]).unwrap();
+    std::thread::sleep(Duration::from_millis(200));
+    store.save(packet("3", "SD")).unwrap();
+    assert!(store.save_batch(&[packet("4", "SD"), packet("5", "SD")]).unwrap_err().is_transient());
+    assert!(!store.save_batch(&[packet("4", "SD"), packet("5", "SD"), packet("6", "SD")]).unwrap_err().is_transient());
+    assert_eq!(all.iter().take(3).collect::<Vec<_>>(), vec!["1/SD", "2/SD", "3/SD"]);
+    assert!(all.recv_timeout(Duration::from_millis(200)).is_err());
+    drop(release);
+}
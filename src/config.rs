@@ -0,0 +1,907 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer};
+
+use crate::ack::AckMode;
+use crate::auth::DeviceList;
+use crate::capture::CaptureConfig;
+use crate::client::ProtocolVersion;
+use crate::composite_store::{CompositeStore, Overflow, Route};
+use crate::file_store::{FileConfig, FileFormat, FileStore};
+use crate::listener::{ListenerConfig, TlsConfig};
+use crate::pipeline::{Pipeline, PipelineConfig};
+use crate::queue::OverflowPolicy;
+use crate::server::Server;
+use crate::store::Store;
+use crate::wal::{FsyncPolicy, WalConfig};
+
+/// Environment variables starting with this override the file, sections and keys are separated by `__`:
+/// `WIALON_SERVER__WORKERS=8` sets `workers` in `[server]`, `WIALON_LISTENER__0__ADDR` the address of the first
+/// listener. Values are read as TOML and fall back to plain strings.
+pub const ENV_PREFIX: &str = "WIALON_";
+
+/// Everything the server binary needs, read from a TOML file. See `wialon.example.toml`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub server: ServerSection,
+    #[serde(default, rename = "listener")]
+    pub listeners: Vec<ListenerSection>,
+    #[serde(default)]
+    pub store: StoreSection,
+    #[serde(default)]
+    pub pipeline: PipelineSection,
+    pub wal: Option<WalSection>,
+    #[serde(default)]
+    pub auth: AuthSection,
+    #[serde(default)]
+    pub limits: LimitsSection,
+    pub capture: Option<CaptureSection>,
+    #[serde(default)]
+    pub log: LogSection,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    /// Reactor threads, 0 means one per CPU.
+    pub workers: usize,
+    /// Packets the store queue holds.
+    pub queue_size: usize,
+    pub overflow: OverflowPolicy,
+    pub ack_mode: AckMode,
+    #[serde(deserialize_with = "duration")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerSection {
+    fn default() -> ServerSection {
+        ServerSection {
+            workers: 0,
+            queue_size: 1000,
+            overflow: OverflowPolicy::default(),
+            ack_mode: AckMode::default(),
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerSection {
+    pub addr: String,
+    /// PEM files, both or none. The listener terminates TLS when they are set.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Defaults to true on plain listeners and has no effect on TLS ones.
+    pub allow_plain_login: Option<bool>,
+}
+
+/// Encoding of the records of the kafka store.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaFormat {
+    #[default]
+    Json,
+    /// Plain Avro binary encoding of `kafka_store::AVRO_SCHEMA`, without a schema registry header.
+    Avro,
+}
+
+/// Where packets are saved, selected by `type`. Types other than `console` and `file` need the cargo
+/// feature of the same name.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum StoreSection {
+    #[default]
+    Console,
+    File {
+        path: String,
+        format: Option<FileFormat>,
+        max_size: Option<u64>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        max_age: Option<Duration>,
+        #[serde(default)]
+        gzip: bool,
+    },
+    Postgres {
+        url: String,
+        table: Option<String>,
+        pool_size: Option<u32>,
+    },
+    Sqlite {
+        path: String,
+        #[serde(default, deserialize_with = "optional_duration")]
+        retention: Option<Duration>,
+    },
+    Mqtt {
+        host: String,
+        port: Option<u16>,
+        client_id: String,
+        topic: Option<String>,
+        qos: Option<u8>,
+        retain: Option<bool>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    Kafka {
+        brokers: String,
+        topic: String,
+        #[serde(default)]
+        format: KafkaFormat,
+        #[serde(default)]
+        options: BTreeMap<String, String>,
+    },
+    Webhook {
+        url: String,
+        secret: Option<String>,
+        batch_size: Option<usize>,
+        concurrency: Option<usize>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        timeout: Option<Duration>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    Influx {
+        /// Write endpoint, either this or `path`.
+        url: Option<String>,
+        path: Option<String>,
+        measurement: Option<String>,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    Redis {
+        url: String,
+        prefix: Option<String>,
+        channel: Option<String>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        timeout: Option<Duration>,
+    },
+    Arrow {
+        dir: String,
+        buckets: Option<u32>,
+        max_rows: Option<usize>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        max_age: Option<Duration>,
+    },
+    Retranslator {
+        /// Queue of positions waiting to be forwarded.
+        dir: String,
+        /// `always`, `never` or an interval such as `100ms`.
+        fsync: Option<String>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        timeout: Option<Duration>,
+        #[serde(default, deserialize_with = "optional_duration")]
+        idle_timeout: Option<Duration>,
+        black_box_size: Option<usize>,
+        batch_size: Option<usize>,
+        /// Checked in order, the first rule matching a position picks its upstream.
+        #[serde(default)]
+        rules: Vec<RetranslatorRule>,
+    },
+    /// Every sink gets the packets matching its filters, each through a pipeline with the `[pipeline]` settings.
+    Composite {
+        #[serde(default)]
+        sinks: Vec<SinkSection>,
+    },
+}
+
+/// Store of a composite store and the packets it gets, filtered like the retranslator rules.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkSection {
+    pub name: String,
+    pub store: StoreSection,
+    /// Packets waiting for this store.
+    pub queue_size: Option<usize>,
+    /// `drop` loses the packets this store has no room for, `refuse` holds up every store until it has.
+    #[serde(default)]
+    pub overflow: Overflow,
+    /// JSON lines file for batches this store refused.
+    pub dead_letter: Option<String>,
+    #[serde(default)]
+    pub imeis: Vec<String>,
+    #[serde(default)]
+    pub except_imeis: Vec<String>,
+    #[serde(default)]
+    pub ptypes: Vec<String>,
+}
+
+/// Upstream IPS server of the devices matching the IMEI patterns and packet types.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetranslatorRule {
+    pub addr: String,
+    pub password: Option<String>,
+    pub version: Option<ProtocolVersion>,
+    #[serde(default)]
+    pub imeis: Vec<String>,
+    #[serde(default)]
+    pub except_imeis: Vec<String>,
+    #[serde(default)]
+    pub ptypes: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineSection {
+    pub batch_size: Option<usize>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub batch_timeout: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub retry_delay: Option<Duration>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub max_retry_delay: Option<Duration>,
+    pub max_retries: Option<u32>,
+    /// JSON lines file for batches the store refused, `{imei}` and `{date}` are replaced.
+    pub dead_letter: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WalSection {
+    pub dir: String,
+    pub segment_size: Option<u64>,
+    /// `always`, `never` or an interval such as `100ms`.
+    pub fsync: Option<String>,
+}
+
+/// Devices allowed to log in. Without any every login is accepted.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSection {
+    /// IMEI to password, `*` accepts any password.
+    pub devices: BTreeMap<String, String>,
+    /// File with `imei password` lines, merged with `devices`.
+    pub devices_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub max_connections: Option<usize>,
+    /// Bytes of a single packet, longer ones close the connection.
+    pub max_message_size: Option<usize>,
+    #[serde(deserialize_with = "optional_duration")]
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Raw traffic of selected devices written to `dir`, see `capture`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureSection {
+    pub dir: PathBuf,
+    /// IMEI patterns, `*` captures every device.
+    #[serde(default)]
+    pub imeis: Vec<String>,
+    /// Peer IP addresses.
+    #[serde(default)]
+    pub addrs: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogSection {
+    /// env_logger filter such as `info` or `warn,wialon_protocol=debug`, `RUST_LOG` takes precedence.
+    pub level: String,
+    /// Log to stdout instead of stderr.
+    pub stdout: bool,
+}
+
+impl Default for LogSection {
+    fn default() -> LogSection {
+        LogSection { level: String::from("info"), stdout: false }
+    }
+}
+
+impl Config {
+    /// Reads the file and applies the environment overrides.
+    pub fn load(path: &Path) -> io::Result<Config> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        Config::parse(&text, std::env::vars())
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn parse<I: Iterator<Item = (String, String)>>(text: &str, env: I) -> io::Result<Config> {
+        let table: toml::Table = toml::from_str(text).map_err(invalid)?;
+        let mut overrides = Vec::new();
+        for (key, value) in env {
+            if let Some(path) = key.strip_prefix(ENV_PREFIX).filter(|p| p.contains("__")) {
+                let path: Vec<String> = path.split("__").map(|s| s.to_lowercase()).collect();
+                overrides.push(Override::new(key, path, &value));
+            }
+        }
+
+        // every value starts as a string, one the config wants as a number or boolean gets its TOML type
+        loop {
+            let mut overridden = table.clone();
+            for o in &overrides {
+                override_value(&mut overridden, &o.path, o.value().clone())
+                    .map_err(|e| invalid(format!("{}: {}", o.key, e)))?;
+            }
+            let err = match toml::Value::Table(overridden).try_into() {
+                Ok(config) => return Ok(config),
+                Err(err) => err,
+            };
+            match overrides.iter_mut().find(|o| o.rejected_by(&err)) {
+                Some(o) => o.use_typed = true,
+                None => return Err(invalid(err)),
+            }
+        }
+    }
+
+    /// Validates everything that can be checked without starting the server or reaching the store.
+    pub fn check(&self) -> io::Result<()> {
+        self.listener_configs()?;
+        self.check_store(&self.store)?;
+        self.wal_config()?;
+        self.device_list()?;
+        self.capture_config()?;
+        Ok(())
+    }
+
+    /// Sets up the server with its store, ready to `start`.
+    pub fn build(&self) -> io::Result<Server> {
+        self.check()?;
+        let listeners = self.listener_configs()?;
+
+        let mut pipeline = Pipeline::new(self.build_store(&self.store)?, self.pipeline_config());
+        if let Some(path) = &self.pipeline.dead_letter {
+            pipeline.set_dead_letter(FileStore::new(FileConfig::new(path, FileFormat::JsonLines)));
+        }
+
+        let workers = match self.server.workers {
+            0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            n => n,
+        };
+        let addr = listeners[0].addr.to_string();
+        let mut server = match self.wal_config()? {
+            Some(wal) => Server::with_wal(&addr, self.server.queue_size, workers, pipeline, &wal)?,
+            None => Server::with_pipeline(&addr, self.server.queue_size, workers, pipeline)?,
+        };
+
+        server.set_listeners(listeners);
+        server.set_ack_mode(self.server.ack_mode);
+        server.set_overflow_policy(self.server.overflow);
+        server.set_shutdown_timeout(self.server.shutdown_timeout);
+        if let Some(devices) = self.device_list()? {
+            server.set_authenticator(devices);
+        }
+        if let Some(max) = self.limits.max_connections {
+            server.set_max_connections(max);
+        }
+        if let Some(max) = self.limits.max_message_size {
+            server.set_max_message_size(max);
+        }
+        if let Some(timeout) = self.limits.idle_timeout {
+            server.set_idle_timeout(timeout);
+        }
+        if let Some(capture) = self.capture_config()? {
+            server.set_capture(capture);
+        }
+        Ok(server)
+    }
+
+    fn listener_configs(&self) -> io::Result<Vec<ListenerConfig>> {
+        if self.listeners.is_empty() {
+            return Err(invalid("no [[listener]] configured"));
+        }
+
+        let mut configs = Vec::with_capacity(self.listeners.len());
+        for l in &self.listeners {
+            let addr: SocketAddr = l.addr.parse().map_err(|e| invalid(format!("listener {}: {}", l.addr, e)))?;
+            let tls = match (&l.tls_cert, &l.tls_key) {
+                (Some(cert_path), Some(key_path)) => {
+                    let tls = TlsConfig { cert_path: cert_path.clone(), key_path: key_path.clone() };
+                    #[cfg(feature = "tls")]
+                    tls.server_config().map_err(|e| invalid(format!("listener {}: {}", l.addr, e)))?;
+                    #[cfg(not(feature = "tls"))]
+                    tls.check().map_err(|e| invalid(format!("listener {}: {}", l.addr, e)))?;
+                    Some(tls)
+                }
+                (None, None) => None,
+                _ => return Err(invalid(format!("listener {}: tls_cert and tls_key go together", l.addr))),
+            };
+            let allow_plain_login = l.allow_plain_login.unwrap_or(tls.is_none());
+            configs.push(ListenerConfig { addr, tls, allow_plain_login });
+        }
+        Ok(configs)
+    }
+
+    fn check_store(&self, store: &StoreSection) -> io::Result<()> {
+        let feature = match store {
+            StoreSection::Console | StoreSection::File { .. } | StoreSection::Retranslator { .. }
+            | StoreSection::Composite { .. } => None,
+            StoreSection::Postgres { .. } => Some(("postgres", cfg!(feature = "postgres"))),
+            StoreSection::Sqlite { .. } => Some(("sqlite", cfg!(feature = "sqlite"))),
+            StoreSection::Mqtt { .. } => Some(("mqtt", cfg!(feature = "mqtt"))),
+            StoreSection::Kafka { .. } => Some(("kafka", cfg!(feature = "kafka"))),
+            StoreSection::Webhook { .. } => Some(("webhook", cfg!(feature = "webhook"))),
+            StoreSection::Influx { .. } => Some(("influx", cfg!(feature = "influx"))),
+            StoreSection::Redis { .. } => Some(("redis", cfg!(feature = "redis"))),
+            StoreSection::Arrow { .. } => Some(("arrow", cfg!(feature = "arrow"))),
+        };
+        if let Some((name, false)) = feature {
+            return Err(not_compiled_in(name));
+        }
+
+        match store {
+            StoreSection::Influx { url, path, .. } if url.is_some() == path.is_some() => {
+                Err(invalid("store influx: set either url or path"))
+            }
+            StoreSection::Mqtt { qos: Some(q), .. } if *q > 2 => Err(invalid(format!("store mqtt: invalid qos {}", q))),
+            // confirming rows which are only in memory would trim the log or ack devices for nothing
+            StoreSection::Arrow { .. } if self.server.ack_mode == AckMode::AfterStore || self.wal.is_some() => {
+                Err(invalid("store arrow: rows are buffered in memory, it can't be used with ack_mode = \"after_store\" or [wal]"))
+            }
+            StoreSection::Retranslator { rules, .. } if rules.is_empty() => Err(invalid("store retranslator: no rules")),
+            StoreSection::Retranslator { fsync, .. } => fsync_policy(fsync.as_deref()).map(|_| ()),
+            StoreSection::Composite { sinks } if sinks.is_empty() => Err(invalid("store composite: no sinks")),
+            StoreSection::Composite { sinks } => sinks.iter().try_for_each(|sink| {
+                self.check_store(&sink.store).map_err(|e| invalid(format!("sink {}: {}", sink.name, e)))
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn build_store(&self, store: &StoreSection) -> io::Result<Box<dyn Store + Send>> {
+        let store: Box<dyn Store + Send> = match store {
+            StoreSection::Console => Box::new(crate::default_store::ConsoleStore::new()),
+            StoreSection::File { path, format, max_size, max_age, gzip } => {
+                let mut config = FileConfig::new(path, format.unwrap_or(FileFormat::JsonLines));
+                config.max_size = *max_size;
+                config.max_age = *max_age;
+                config.gzip = *gzip;
+                Box::new(FileStore::new(config))
+            }
+            #[cfg(feature = "postgres")]
+            StoreSection::Postgres { url, table, pool_size } => {
+                use crate::postgres_store::{PostgresConfig, PostgresStore};
+                let mut config = PostgresConfig::new(url);
+                if let Some(t) = table {
+                    config.table = t.clone();
+                }
+                if let Some(n) = pool_size {
+                    config.pool_size = *n;
+                }
+                Box::new(PostgresStore::new(&config)?)
+            }
+            #[cfg(feature = "sqlite")]
+            StoreSection::Sqlite { path, retention } => {
+                use crate::sqlite_store::{SqliteConfig, SqliteStore};
+                let mut config = SqliteConfig::new(path);
+                config.retention = *retention;
+                Box::new(SqliteStore::new(&config)?)
+            }
+            #[cfg(feature = "mqtt")]
+            StoreSection::Mqtt { host, port, client_id, topic, qos, retain, username, password } => {
+                use crate::mqtt_store::{MqttConfig, MqttStore};
+                let mut config = MqttConfig::new(host, port.unwrap_or(1883), client_id);
+                if let Some(t) = topic {
+                    config.topic = t.clone();
+                }
+                config.qos = qos.unwrap_or(config.qos);
+                config.retain = retain.unwrap_or(config.retain);
+                if let Some(user) = username {
+                    config.credentials = Some((user.clone(), password.clone().unwrap_or_default()));
+                }
+                Box::new(MqttStore::new(&config)?)
+            }
+            #[cfg(feature = "kafka")]
+            StoreSection::Kafka { brokers, topic, format, options } => {
+                use crate::kafka_store::{KafkaConfig, KafkaStore};
+                let mut config = KafkaConfig::new(brokers, topic);
+                config.format = *format;
+                config.options = options.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                Box::new(KafkaStore::new(&config)?)
+            }
+            #[cfg(feature = "webhook")]
+            StoreSection::Webhook { url, secret, batch_size, concurrency, timeout, headers } => {
+                use crate::webhook_store::{WebhookConfig, WebhookStore};
+                let mut config = WebhookConfig::new(url);
+                config.secret = secret.clone();
+                config.batch_size = batch_size.unwrap_or(config.batch_size);
+                config.concurrency = concurrency.unwrap_or(config.concurrency);
+                config.timeout = timeout.unwrap_or(config.timeout);
+                config.headers = headers.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                Box::new(WebhookStore::new(config))
+            }
+            #[cfg(feature = "influx")]
+            StoreSection::Influx { url, path, measurement, headers } => {
+                use crate::influx_store::{InfluxConfig, InfluxStore};
+                let mut config = match (url, path) {
+                    (Some(url), _) => InfluxConfig::http(url),
+                    (None, Some(path)) => InfluxConfig::file(path),
+                    (None, None) => return Err(invalid("store influx: set either url or path")),
+                };
+                if let Some(m) = measurement {
+                    config.measurement = m.clone();
+                }
+                config.headers = headers.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                Box::new(InfluxStore::new(config)?)
+            }
+            #[cfg(feature = "redis")]
+            StoreSection::Redis { url, prefix, channel, timeout } => {
+                use crate::redis_store::{RedisConfig, RedisStore};
+                let mut config = RedisConfig::new(url);
+                if let Some(p) = prefix {
+                    config.prefix = p.clone();
+                }
+                if let Some(c) = channel {
+                    config.channel = c.clone();
+                }
+                config.timeout = timeout.unwrap_or(config.timeout);
+                Box::new(RedisStore::new(&config)?)
+            }
+            #[cfg(feature = "arrow")]
+            StoreSection::Arrow { dir, buckets, max_rows, max_age } => {
+                use crate::arrow_store::{ArrowConfig, ArrowStore};
+                let mut config = ArrowConfig::new(dir);
+                config.buckets = buckets.unwrap_or(config.buckets);
+                config.max_rows = max_rows.unwrap_or(config.max_rows);
+                config.max_age = max_age.unwrap_or(config.max_age);
+                Box::new(ArrowStore::new(config)?)
+            }
+            StoreSection::Retranslator { dir, fsync, timeout, idle_timeout, black_box_size, batch_size, rules } => {
+                use crate::retranslator_store::{RetranslatorConfig, RetranslatorStore, Upstream};
+                let mut config = RetranslatorConfig::new(dir);
+                config.queue.fsync = fsync_policy(fsync.as_deref())?;
+                config.timeout = timeout.unwrap_or(config.timeout);
+                config.idle_timeout = idle_timeout.unwrap_or(config.idle_timeout);
+                config.black_box_size = black_box_size.unwrap_or(config.black_box_size);
+                config.batch_size = batch_size.unwrap_or(config.batch_size);
+                for rule in rules {
+                    let route = route(&rule.imeis, &rule.except_imeis, &rule.ptypes);
+                    let mut upstream = Upstream::new(&rule.addr);
+                    if let Some(p) = &rule.password {
+                        upstream.password = p.clone();
+                    }
+                    upstream.version = rule.version.unwrap_or(upstream.version);
+                    config.rules.push((route, upstream));
+                }
+                Box::new(RetranslatorStore::new(config)?)
+            }
+            StoreSection::Composite { sinks } => {
+                let mut composite = CompositeStore::new();
+                for sink in sinks {
+                    let store = self.build_store(&sink.store)
+                        .map_err(|e| io::Error::new(e.kind(), format!("sink {}: {}", sink.name, e)))?;
+                    let mut pipeline = Pipeline::new(store, self.pipeline_config());
+                    if let Some(path) = &sink.dead_letter {
+                        pipeline.set_dead_letter(FileStore::new(FileConfig::new(path, FileFormat::JsonLines)));
+                    }
+                    let route = route(&sink.imeis, &sink.except_imeis, &sink.ptypes);
+                    composite.add_sink_with_overflow(&sink.name, route, pipeline, sink.queue_size.unwrap_or(1000),
+                                                     sink.overflow);
+                }
+                Box::new(composite)
+            }
+            // check_store refuses stores which aren't compiled in before
+            #[cfg(not(feature = "postgres"))]
+            StoreSection::Postgres { .. } => return Err(not_compiled_in("postgres")),
+            #[cfg(not(feature = "sqlite"))]
+            StoreSection::Sqlite { .. } => return Err(not_compiled_in("sqlite")),
+            #[cfg(not(feature = "mqtt"))]
+            StoreSection::Mqtt { .. } => return Err(not_compiled_in("mqtt")),
+            #[cfg(not(feature = "kafka"))]
+            StoreSection::Kafka { .. } => return Err(not_compiled_in("kafka")),
+            #[cfg(not(feature = "webhook"))]
+            StoreSection::Webhook { .. } => return Err(not_compiled_in("webhook")),
+            #[cfg(not(feature = "influx"))]
+            StoreSection::Influx { .. } => return Err(not_compiled_in("influx")),
+            #[cfg(not(feature = "redis"))]
+            StoreSection::Redis { .. } => return Err(not_compiled_in("redis")),
+            #[cfg(not(feature = "arrow"))]
+            StoreSection::Arrow { .. } => return Err(not_compiled_in("arrow")),
+        };
+        Ok(store)
+    }
+
+    fn pipeline_config(&self) -> PipelineConfig {
+        let defaults = PipelineConfig::default();
+        PipelineConfig {
+            batch_size: self.pipeline.batch_size.unwrap_or(defaults.batch_size),
+            batch_timeout: self.pipeline.batch_timeout.unwrap_or(defaults.batch_timeout),
+            retry_delay: self.pipeline.retry_delay.unwrap_or(defaults.retry_delay),
+            max_retry_delay: self.pipeline.max_retry_delay.unwrap_or(defaults.max_retry_delay),
+            max_retries: self.pipeline.max_retries.unwrap_or(defaults.max_retries),
+        }
+    }
+
+    fn capture_config(&self) -> io::Result<Option<CaptureConfig>> {
+        let capture = match &self.capture {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let mut config = CaptureConfig::new(&capture.dir);
+        config.imeis = capture.imeis.clone();
+        for addr in &capture.addrs {
+            config.addrs.push(addr.parse().map_err(|e| invalid(format!("capture address {}: {}", addr, e)))?);
+        }
+        Ok(Some(config))
+    }
+
+    fn wal_config(&self) -> io::Result<Option<WalConfig>> {
+        let wal = match &self.wal {
+            Some(w) => w,
+            None => return Ok(None),
+        };
+
+        let mut config = WalConfig::new(&wal.dir);
+        if let Some(size) = wal.segment_size {
+            config.segment_size = size;
+        }
+        config.fsync = fsync_policy(wal.fsync.as_deref())?;
+        Ok(Some(config))
+    }
+
+    fn device_list(&self) -> io::Result<Option<DeviceList>> {
+        let mut devices = match &self.auth.devices_file {
+            Some(path) => DeviceList::from_file(path)
+                .map_err(|e| io::Error::new(e.kind(), format!("auth devices_file {}: {}", path.display(), e)))?,
+            None => DeviceList::new(),
+        };
+        for (imei, password) in &self.auth.devices {
+            devices.add(imei, password);
+        }
+
+        if devices.is_empty() && self.auth.devices_file.is_none() {
+            Ok(None)
+        } else {
+            Ok(Some(devices))
+        }
+    }
+}
+
+fn route(imeis: &[String], except_imeis: &[String], ptypes: &[String]) -> Route {
+    Route { imei: imeis.to_vec(), except_imei: except_imeis.to_vec(), ptype: ptypes.to_vec() }
+}
+
+fn fsync_policy(fsync: Option<&str>) -> io::Result<FsyncPolicy> {
+    match fsync {
+        None | Some("always") => Ok(FsyncPolicy::Always),
+        Some("never") => Ok(FsyncPolicy::Never),
+        Some(interval) => Ok(FsyncPolicy::Interval(parse_duration(interval).map_err(|e| invalid(format!("fsync: {}", e)))?)),
+    }
+}
+
+fn not_compiled_in(store: &str) -> io::Error {
+    invalid(format!("store {} is not compiled in, rebuild with --features {}", store, store))
+}
+
+fn invalid<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+// Sets the value at the path, creating tables on the way. Array elements are addressed by index.
+fn override_value(table: &mut toml::Table, path: &[String], value: toml::Value) -> Result<(), String> {
+    let (last, parents) = path.split_last().ok_or("empty key")?;
+    let mut current = table;
+    for (i, key) in parents.iter().enumerate() {
+        let next = current.entry(key.clone()).or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        current = match next {
+            toml::Value::Table(t) => t,
+            toml::Value::Array(items) => {
+                let index: usize = path.get(i + 1)
+                    .and_then(|n| n.parse().ok())
+                    .ok_or(format!("{} is a list, the next key must be an index", key))?;
+                if index == items.len() {
+                    items.push(toml::Value::Table(toml::Table::new()));
+                }
+                match items.get_mut(index) {
+                    Some(toml::Value::Table(t)) => return override_value(t, &path[i + 2..], value),
+                    _ => return Err(format!("{} has no table at index {}", key, index)),
+                }
+            }
+            _ => return Err(format!("{} is not a table", key)),
+        };
+    }
+    current.insert(last.clone(), value);
+    Ok(())
+}
+
+// Value of an environment variable for the key at `path`.
+struct Override {
+    key: String,
+    path: Vec<String>,
+    text: toml::Value,
+    // number, boolean or date the text reads as, used when the key doesn't take a string
+    typed: Option<toml::Value>,
+    use_typed: bool,
+}
+
+impl Override {
+    // Quoted strings, lists and inline tables are taken as TOML, anything else as text.
+    fn new(key: String, path: Vec<String>, value: &str) -> Override {
+        let text = toml::Value::String(value.to_string());
+        let (text, typed) = match toml::from_str::<toml::Table>(&format!("v = {}", value)).map(|mut t| t.remove("v")) {
+            Ok(Some(v @ (toml::Value::String(_) | toml::Value::Array(_) | toml::Value::Table(_)))) => (v, None),
+            Ok(Some(v)) => (text, Some(v)),
+            _ => (text, None),
+        };
+        Override { key, path, text, typed, use_typed: false }
+    }
+
+    fn value(&self) -> &toml::Value {
+        match &self.typed {
+            Some(v) if self.use_typed => v,
+            _ => &self.text,
+        }
+    }
+
+    // Whether the error is about this value as a string, e.g. `invalid type: string "8", expected usize`
+    // followed by `in `server.workers``. Inside the store section the error only names `store`.
+    fn rejected_by(&self, err: &toml::de::Error) -> bool {
+        let message = err.to_string();
+        let at: Vec<String> = match message.rsplit_once("\nin `") {
+            Some((_, at)) => at.trim_end().trim_end_matches('`').replace('[', ".").replace(']', "")
+                .split('.').map(str::to_string).collect(),
+            None => Vec::new(),
+        };
+        !self.use_typed && self.typed.is_some() && self.path.starts_with(&at)
+            && message.contains(&format!("string {:?}", self.text.as_str().unwrap_or_default()))
+    }
+}
+
+/// Parses `500ms`, `30s`, `5m`, `2h` or `7d`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let n: u64 = number.parse().map_err(|_| format!("invalid duration {:?}", s))?;
+    match unit.trim() {
+        "ms" => Ok(Duration::from_millis(n)),
+        "s" | "" => Ok(Duration::from_secs(n)),
+        "m" => Ok(Duration::from_secs(n * 60)),
+        "h" => Ok(Duration::from_secs(n * 3600)),
+        "d" => Ok(Duration::from_secs(n * 86400)),
+        _ => Err(format!("invalid duration {:?}, expected a number with ms, s, m, h or d", s)),
+    }
+}
+
+// Durations are strings like "30s" or plain numbers of seconds.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DurationValue {
+    Seconds(u64),
+    Text(String),
+}
+
+fn duration<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+    match DurationValue::deserialize(d)? {
+        DurationValue::Seconds(n) => Ok(Duration::from_secs(n)),
+        DurationValue::Text(s) => parse_duration(&s).map_err(serde::de::Error::custom),
+    }
+}
+
+fn optional_duration<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duration>, D::Error> {
+    duration(d).map(Some)
+}
+
+#[test]
+fn test_config_parse() {
+    let example = Config::parse(include_str!("../wialon.example.toml"), std::iter::empty()).unwrap();
+    example.check().unwrap();
+
+    let text = r#"
+        [server]
+        workers = 2
+        overflow = "pause"
+        shutdown_timeout = "1m"
+
+        [[listener]]
+        addr = "127.0.0.1:20332"
+
+        [store]
+        type = "file"
+        path = "tracks/{imei}.csv"
+        format = "csv"
+
+        [limits]
+        idle_timeout = 600
+
+        [capture]
+        dir = "/var/lib/wialon/capture"
+        imeis = ["861230*"]
+        addrs = ["10.0.0.5"]
+    "#;
+    let env = vec![
+        (String::from("WIALON_SERVER__WORKERS"), String::from("8")),
+        (String::from("WIALON_LISTENER__0__ADDR"), String::from("0.0.0.0:20332")),
+        (String::from("WIALON_LISTENER__1__ADDR"), String::from("0.0.0.0:20333")),
+        (String::from("WIALON_AUTH__DEVICES__861230043907626"), String::from("1234")),
+        (String::from("WIALON_AUTH__DEVICES__861230043907627"), String::from("\"true\"")),
+        (String::from("WIALON_STORE__PATH"), String::from("2024")),
+        (String::from("WIALON_STORE__GZIP"), String::from("true")),
+        (String::from("WIALON_TEST_POSTGRES"), String::from("not an override")),
+    ];
+    let config = Config::parse(text, env.into_iter()).unwrap();
+    config.check().unwrap();
+
+    assert_eq!(config.server.workers, 8);
+    assert_eq!(config.server.overflow, OverflowPolicy::Pause);
+    assert_eq!(config.server.shutdown_timeout, Duration::from_secs(60));
+    assert_eq!(config.listeners.iter().map(|l| l.addr.as_str()).collect::<Vec<_>>(), vec!["0.0.0.0:20332", "0.0.0.0:20333"]);
+    assert!(matches!(&config.store, StoreSection::File { path, format: Some(FileFormat::Csv), gzip: true, .. } if path == "2024"));
+    assert_eq!(config.limits.idle_timeout, Some(Duration::from_secs(600)));
+    assert_eq!(config.auth.devices["861230043907626"], "1234");
+    assert_eq!(config.auth.devices["861230043907627"], "true");
+    assert_eq!(config.capture_config().unwrap().unwrap().addrs, vec![std::net::IpAddr::from([10, 0, 0, 5])]);
+
+    // typos are reported instead of ignored
+    assert!(Config::parse("[server]\nworkrs = 2\n", std::iter::empty()).is_err());
+    assert!(Config::parse("[store]\ntype = \"file\"\npath = \"x\"\nrotate = true\n", std::iter::empty()).is_err());
+    assert!(Config::parse("[limits]\nidle_timeout = \"10 minutes\"\n", std::iter::empty()).is_err());
+    assert!(Config::parse("[[listener]]\naddr = \"127.0.0.1:1\"\n[capture]\ndir = \"x\"\naddrs = [\"10.0.0\"]\n",
+                          std::iter::empty()).unwrap().check().is_err());
+    assert!(Config::parse("", std::iter::empty()).unwrap().check().is_err());
+
+    let text = r#"
+        [[listener]]
+        addr = "127.0.0.1:20332"
+
+        [store]
+        type = "retranslator"
+        dir = "/var/lib/wialon/retranslator"
+        idle_timeout = "1m"
+
+        [[store.rules]]
+        addr = "193.193.165.165:20332"
+        version = "2.0"
+        imeis = ["86123004*"]
+    "#;
+    let config = Config::parse(text, std::iter::empty()).unwrap();
+    config.check().unwrap();
+    match &config.store {
+        StoreSection::Retranslator { rules, idle_timeout, .. } => {
+            assert_eq!(rules[0].version, Some(ProtocolVersion::V2_0));
+            assert_eq!(*idle_timeout, Some(Duration::from_secs(60)));
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(Config::parse(&text.replace("[[store.rules]]", "[store.x]"), std::iter::empty()).is_err());
+
+    let text = r#"
+        [[listener]]
+        addr = "127.0.0.1:20332"
+
+        [store]
+        type = "composite"
+
+        [[store.sinks]]
+        name = "archive"
+        overflow = "refuse"
+        store = { type = "file", path = "archive/{imei}.jsonl" }
+
+        [[store.sinks]]
+        name = "hook"
+        imeis = ["86123004*"]
+        store = { type = "webhook", url = "http://localhost/positions", timeout = "3s" }
+    "#;
+    let config = Config::parse(text, std::iter::empty()).unwrap();
+    assert_eq!(config.check().is_ok(), cfg!(feature = "webhook"));
+    match &config.store {
+        StoreSection::Composite { sinks } => {
+            assert_eq!((sinks[0].overflow, sinks[1].overflow), (Overflow::Refuse, Overflow::Drop));
+            assert!(matches!(sinks[1].store, StoreSection::Webhook { timeout: Some(t), .. } if t == Duration::from_secs(3)));
+        }
+        other => panic!("{:?}", other),
+    }
+    let without_hook = text.replace(r#"{ type = "webhook", url = "http://localhost/positions", timeout = "3s" }"#,
+                                    r#"{ type = "console" }"#);
+    Config::parse(&without_hook, std::iter::empty()).unwrap().check().unwrap();
+    let nested = without_hook.replace(r#"{ type = "console" }"#, r#"{ type = "composite" }"#);
+    assert!(Config::parse(&nested, std::iter::empty()).unwrap().check().is_err());
+
+    let archive = without_hook.replace(r#"{ type = "file", path = "archive/{imei}.jsonl" }"#, r#"{ type = "arrow", dir = "archive" }"#);
+    assert_eq!(Config::parse(&archive, std::iter::empty()).unwrap().check().is_ok(), cfg!(feature = "arrow"));
+    let after_store = format!("[server]\nack_mode = \"after_store\"\n{}", archive);
+    assert!(Config::parse(&after_store, std::iter::empty()).unwrap().check().is_err());
+}
+
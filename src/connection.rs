@@ -1,31 +1,76 @@
 use mio::{Interest, Registry, Token};
-use mio::net::TcpStream;
 use mio::event::Source;
 
 use log::{info, error};
+use std::collections::VecDeque;
 use std::io;
-use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use crate::wialon;
+use crate::ack::{Ack, AckMode, AckRoute, Delivery};
+use crate::auth::Authenticator;
+use crate::capture::Recorder;
+use crate::queue::{Bus, Offer};
 use crate::store::GeoPacket;
-use crate::wialon::ResponsePacket;
+use crate::wialon::{ResponsePacket, PASSWORD_ERROR};
+use crate::transport::Transport;
 use std::io::{Read, Write};
 
+/// Behaviour of accepted connections, set up by the server for each listener.
+#[derive(Clone, Default)]
+pub struct ConnectionSettings {
+    pub allow_plain_login: bool,
+    pub ack_mode: AckMode,
+    pub auth: Option<Arc<dyn Authenticator>>,
+    /// Close connections the device sent nothing on for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Close connections sending a packet longer than this, line end included.
+    pub max_message_size: Option<usize>,
+    /// Open connections of the server, counted by the connections themselves.
+    pub active: Arc<AtomicUsize>,
+}
+
+// Packet waiting for room in the store queue, see `OverflowPolicy::Pause`. A black box
+// waits with the messages not queued yet.
+struct Paused {
+    deliveries: VecDeque<Delivery>,
+    response: ResponsePacket,
+    rejection: ResponsePacket,
+}
+
 pub struct Connection {
     imei: Vec<u8>,
-    socket: TcpStream,
-    bus: SyncSender<GeoPacket>,
+    socket: Transport,
+    bus: Bus,
+    // bytes of a packet whose line end hasn't arrived yet
+    in_buf: Vec<u8>,
+    // bytes waiting for the socket to become writable
+    out_buf: Vec<u8>,
+    interest: Interest,
+    settings: ConnectionSettings,
+    // set by the worker, needed to answer after the store confirmed a packet
+    ack_route: Option<AckRoute>,
+    // close as soon as the pending output is delivered
+    closing: bool,
+    paused: Option<Paused>,
+    logged_in: bool,
+    last_read: Instant,
+    recorder: Option<Recorder>,
 }
 
 impl Source for Connection {
     fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
                 -> io::Result<()>
     {
+        self.interest = interests;
         self.socket.register(registry, token, interests)
     }
 
     fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
                   -> io::Result<()>
     {
+        self.interest = interests;
         self.socket.reregister(registry, token, interests)
     }
 
@@ -34,87 +79,322 @@ impl Source for Connection {
     }
 }
 
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.paused.is_some() {
+            self.bus.set_paused(false);
+        }
+        self.settings.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 impl Connection {
-    pub fn new(c: TcpStream, bus: SyncSender<GeoPacket>) -> Connection {
+    pub(crate) fn new(c: Transport, bus: Bus, settings: ConnectionSettings) -> Connection {
+        settings.active.fetch_add(1, Ordering::SeqCst);
         Connection {
             imei: vec![0, 100],
             socket: c,
             bus,
+            in_buf: Vec::new(),
+            out_buf: Vec::new(),
+            interest: Interest::READABLE,
+            settings,
+            ack_route: None,
+            closing: false,
+            paused: None,
+            logged_in: false,
+            last_read: Instant::now(),
+            recorder: None,
         }
     }
+
+    pub(crate) fn set_ack_route(&mut self, route: AckRoute) {
+        self.ack_route = Some(route);
+    }
+
+    /// Capture the raw traffic of the connection, see `Server::set_capture`.
+    pub(crate) fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    pub(crate) fn transport_mut(&mut self) -> &mut Transport {
+        &mut self.socket
+    }
+
     pub fn get_message(&mut self) -> io::Result<bool> {
-        let mut connection_closed = false;
-        let mut read_bytes = 0;
-        let mut buf = vec![0; 2048];
+        let mut buf = [0; 2048];
         loop {
+            if self.handle_lines()? {
+                return Ok(true);
+            }
+            // the rest stays in the socket until the paused packet is queued
+            if self.paused.is_some() {
+                return Ok(false);
+            }
+
             match self.socket.read(&mut buf) {
-                Ok(0) => {
-                    connection_closed = true;
-                    break;
-                }
+                Ok(0) => return Ok(true),
                 Ok(n) => {
-                    read_bytes += n;
-                    if read_bytes == buf.len() {
-                        buf.resize(buf.len() + 1024, 0);
+                    self.last_read = Instant::now();
+                    if let Some(r) = &mut self.recorder {
+                        r.inbound(&buf[..n]);
                     }
+                    self.in_buf.extend_from_slice(&buf[..n]);
                 }
                 Err(err) => match err.kind() {
-                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::WouldBlock => return Ok(false),
                     io::ErrorKind::Interrupted => continue,
                     _ => return Err(err)
                 }
             }
         }
+    }
+
+    // Handles the complete packets read so far, a packet split across reads waits for its end.
+    // Returns true if the connection got closed.
+    fn handle_lines(&mut self) -> io::Result<bool> {
+        let max = self.settings.max_message_size.unwrap_or(usize::MAX);
+        while self.paused.is_none() && !self.closing {
+            let end = match self.in_buf.windows(2).position(|w| w == b"\r\n") {
+                Some(pos) => pos + 2,
+                None if self.in_buf.len() > max => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "message is too long"));
+                }
+                None => break,
+            };
+            if end > max {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "message is too long"));
+            }
+            let line: Vec<u8> = self.in_buf.drain(..end).collect();
+            if self.handle_packet(&line)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Returns true if the connection got closed.
+    fn handle_packet(&mut self, line: &[u8]) -> io::Result<bool> {
+        match wialon::Packet::from(line) {
+            Ok(p) => {
+                info!("receiver packet: {:?}", p);
+                if p.is_auth_packet() {
+                    if !self.socket.is_tls() && !self.settings.allow_plain_login {
+                        error!("plaintext login rejected");
+                        self.closing = true;
+                        return match p.response(0) {
+                            Ok(r) => self.send_message(r).map(|_| self.is_closed()),
+                            Err(_) => Ok(true),
+                        };
+                    }
 
-        if read_bytes > 0 {
-            match wialon::Packet::from(&buf[..read_bytes]) {
-                Ok(p) => {
-                    info!("receiver packet: {:?}", p);
-                    if p.is_auth_packet() {
-                        // TODO: auth process
-                        let auth = p.get_auth_data().unwrap();
-                        info!("auth: {:?}", auth);
-
-                        self.imei = auth.imei.as_bytes().to_vec();
-                    } else {
-                        self.bus.send(GeoPacket::new(
-                            self.imei.to_owned(),
-                            p.get_navigate_data().unwrap(),
-                        )).unwrap();
+                    let auth = p.get_auth_data().unwrap();
+                    info!("auth: {:?}", auth);
+                    if let Some(r) = &mut self.recorder {
+                        r.login(&auth.imei);
                     }
 
-                    match p.response(1) {
-                        Ok(r) => self.send_message(r)?,
-                        Err(err) => error!("{:?}", err),
+                    if let Some(authenticator) = &self.settings.auth {
+                        if !authenticator.authenticate(&auth.imei, &auth.password) {
+                            error!("login of {} refused", auth.imei);
+                            self.closing = true;
+                            self.send_message(ResponsePacket { ptype: String::from("AL"), code: String::from(PASSWORD_ERROR) })?;
+                            return Ok(self.is_closed());
+                        }
                     }
+                    self.imei = auth.imei.as_bytes().to_vec();
+                    self.logged_in = true;
+                } else if !self.logged_in && !p.get_messages().is_empty() {
+                    error!("data packet before login");
+                    return Ok(true);
+                } else {
+                    match (p.response(1), p.rejection()) {
+                        (Ok(response), Ok(rejection)) => {
+                            let messages = p.get_messages();
+                            let mut acks = match &self.ack_route {
+                                Some(route) if self.settings.ack_mode == AckMode::AfterStore => {
+                                    Ack::new(route.clone(), response.clone(), messages.len())
+                                }
+                                _ => Vec::new(),
+                            }.into_iter();
+                            let mut deliveries = VecDeque::with_capacity(messages.len());
+                            for m in messages {
+                                match GeoPacket::from_packet(self.imei.to_owned(), m) {
+                                    Ok(packet) => deliveries.push_back(Delivery { packet, ack: acks.next() }),
+                                    Err(err) => {
+                                        error!("{:?}", err);
+                                        return Ok(true);
+                                    }
+                                }
+                            }
+                            self.deliver(Paused { deliveries, response, rejection })?
+                        }
+                        (Err(err), _) | (_, Err(err)) => error!("{:?}", err),
+                    }
+                    return Ok(false);
+                }
+
+                match p.response(1) {
+                    Ok(r) => self.send_message(r)?,
+                    Err(err) => error!("{:?}", err),
                 }
-                Err(err) => error!("{:?}", err),
+            }
+            // whatever follows can't be told apart from the garbage, the device reconnects
+            Err(err) => {
+                error!("malformed packet: {:?}", err);
+                return Ok(true);
             }
         }
+        Ok(false)
+    }
 
-
-        if connection_closed {
-            return Ok(true);
+    /// Device sent nothing for longer than the idle timeout. Paused connections don't read,
+    /// so they never count as idle.
+    pub fn is_idle(&self, now: Instant) -> bool {
+        match self.settings.idle_timeout {
+            Some(timeout) => self.paused.is_none() && now.duration_since(self.last_read) >= timeout,
+            None => false,
         }
+    }
 
-        Ok(false)
+    /// Connection holds a packet the full store queue didn't take and doesn't read meanwhile.
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
     }
 
-    fn send_message(&mut self, msg: ResponsePacket) -> io::Result<()> {
-        loop {
-            match self.socket.write_all(msg.to_string().as_bytes()) {
-                Ok(_) => {
+    /// Retries the paused packet and, once it is queued, reads whatever the device sent meanwhile.
+    /// Returns true if the connection got closed.
+    pub fn resume(&mut self) -> io::Result<bool> {
+        let paused = match self.paused.take() {
+            Some(p) => p,
+            None => return Ok(false),
+        };
+        self.bus.set_paused(false);
+
+        self.deliver(paused)?;
+        if self.paused.is_some() {
+            return Ok(false);
+        }
+        self.get_message()
+    }
+
+    // Hands the messages of a packet to the store queue and answers the device according to
+    // the ack mode and the overflow policy. Packets without messages (pings) are answered right away.
+    fn deliver(&mut self, mut p: Paused) -> io::Result<()> {
+        let waits_for_store = p.deliveries.iter().any(|d| d.ack.is_some());
+        while let Some(delivery) = p.deliveries.pop_front() {
+            match self.bus.offer(delivery) {
+                Offer::Queued => {}
+                // messages queued before are saved anyway, the device sends them again
+                Offer::Rejected => return self.send_message(p.rejection),
+                Offer::Full(delivery) => {
+                    p.deliveries.push_front(delivery);
+                    self.bus.set_paused(true);
+                    self.paused = Some(p);
                     return Ok(());
                 }
+                // without a response the device sends the packet again
+                Offer::Closed => {
+                    error!("store queue is closed");
+                    return Ok(());
+                }
+            }
+        }
+
+        // the response is sent once the store has saved the packet
+        if waits_for_store {
+            return Ok(());
+        }
+        self.send_message(p.response)
+    }
+
+    /// Queue raw bytes (server commands, firmware chunks) for delivery to the device.
+    /// Whatever the socket doesn't accept right away is sent on the next writable event.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some(r) = &mut self.recorder {
+            r.outbound(data);
+        }
+        self.out_buf.extend_from_slice(data);
+        self.flush()
+    }
+
+    /// Write as much of the outbound buffer as the socket accepts without blocking.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.out_buf.is_empty() {
+            match self.socket.write(&self.out_buf) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.out_buf.drain(..n);
+                }
                 Err(err) => match err.kind() {
-                    io::ErrorKind::WouldBlock => {}
+                    io::ErrorKind::WouldBlock => break,
                     io::ErrorKind::Interrupted => continue,
                     _ => return {
-                        error!("failed send ack: {:?}", err);
+                        error!("failed send message: {:?}", err);
                         Err(err)
                     }
                 }
             }
         }
+
+        match self.socket.flush() {
+            Err(err) if err.kind() != io::ErrorKind::WouldBlock => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Connection was asked to close and everything queued for the device is sent.
+    pub fn is_closed(&self) -> bool {
+        self.closing && self.out_buf.is_empty() && !self.socket.wants_write()
+    }
+
+    /// Interest the socket should be registered with: WRITABLE only while output is pending.
+    pub fn wanted_interest(&self) -> Interest {
+        if self.out_buf.is_empty() && !self.socket.wants_write() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        }
+    }
+
+    pub fn interest(&self) -> Interest {
+        self.interest
+    }
+
+    fn send_message(&mut self, msg: ResponsePacket) -> io::Result<()> {
+        self.push(msg.to_string().as_bytes())
+    }
+}
+
+#[test]
+fn test_outbound_queue() {
+    use std::net::TcpListener;
+    use crate::queue;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (mut peer, _) = listener.accept().unwrap();
+    client.set_nonblocking(true).unwrap();
+
+    let (sender, _receiver, _) = queue::channel(1);
+    let socket = mio::net::TcpStream::from_std(client);
+    let mut conn = Connection::new(Transport::Plain(socket), sender, ConnectionSettings::default());
+    assert_eq!(conn.wanted_interest(), Interest::READABLE);
+
+    // nobody reads on the other side, so the kernel buffers fill and the rest stays queued
+    let chunk = vec![b'x'; 64 * 1024];
+    let mut pushed = 0;
+    while conn.wanted_interest() == Interest::READABLE {
+        conn.push(&chunk).unwrap();
+        pushed += chunk.len();
+    }
+    assert_eq!(conn.wanted_interest(), Interest::READABLE | Interest::WRITABLE);
+
+    let mut received = 0;
+    let mut buf = vec![0; 64 * 1024];
+    while received < pushed {
+        conn.flush().unwrap();
+        received += peer.read(&mut buf).unwrap();
     }
+    assert_eq!(conn.wanted_interest(), Interest::READABLE);
 }
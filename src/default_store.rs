@@ -1,17 +1,18 @@
-use crate::store::{Store, GeoPacket};
+use crate::store::{Store, StoreError, GeoPacket};
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct ConsoleStore {}
 
 impl ConsoleStore{
     pub fn new() -> ConsoleStore {
-        return ConsoleStore{}
+        ConsoleStore{}
     }
 }
 
 impl Store for ConsoleStore {
-    fn save(&self, p: GeoPacket) {
-        let packet_json = serde_json::to_string(&p).unwrap();
-        println!("{:?}", packet_json);
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        let packet_json = serde_json::to_string(&p).map_err(|e| StoreError::Permanent(e.to_string()))?;
+        println!("{}", packet_json);
+        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use chrono::Local;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use serde::Deserialize;
+
+use log::{info, error};
+use crate::store::{GeoPacket, Store, StoreError};
+
+const CSV_HEADER: &str = "imei,timestamp,lat,lon,speed,course,height,sats,ptype,params\n";
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma separated with a header, params as a JSON column.
+    Csv,
+}
+
+#[derive(Clone, Debug)]
+pub struct FileConfig {
+    /// Path of the file a packet goes to. `{imei}` and `{date}` (device time, `YYYY-MM-DD`)
+    /// are replaced, e.g. `tracks/{date}/{imei}.jsonl`.
+    pub path: String,
+    pub format: FileFormat,
+    /// Rotate a file once it grows past this size...
+    pub max_size: Option<u64>,
+    /// ...or was opened this long ago, checked whenever a batch is saved.
+    pub max_age: Option<Duration>,
+    /// Compress rotated files to `.gz`.
+    pub gzip: bool,
+    /// Least recently written files are closed above this number (not rotated).
+    pub max_open_files: usize,
+}
+
+impl FileConfig {
+    pub fn new(path: &str, format: FileFormat) -> FileConfig {
+        FileConfig {
+            path: path.to_string(),
+            format,
+            max_size: None,
+            max_age: None,
+            gzip: false,
+            max_open_files: 256,
+        }
+    }
+}
+
+struct OpenFile {
+    writer: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    last_write: Instant,
+}
+
+struct State {
+    files: HashMap<PathBuf, OpenFile>,
+    compressing: Vec<thread::JoinHandle<()>>,
+}
+
+/// Writes packets as JSON lines or CSV into files rotated by size and age.
+pub struct FileStore {
+    config: FileConfig,
+    state: Mutex<State>,
+}
+
+impl FileStore {
+    pub fn new(config: FileConfig) -> FileStore {
+        FileStore {
+            config,
+            state: Mutex::new(State { files: HashMap::new(), compressing: Vec::new() }),
+        }
+    }
+
+    fn path(&self, p: &GeoPacket) -> PathBuf {
+        // the IMEI comes from the device, it must not be able to point outside the directory
+        let imei: String = p.imei.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        PathBuf::from(self.config.path
+            .replace("{imei}", &imei)
+            .replace("{date}", &p.timestamp.format("%Y-%m-%d").to_string()))
+    }
+
+    fn write(&self, state: &mut State, p: &GeoPacket) -> io::Result<()> {
+        let path = self.path(p);
+        if !state.files.contains_key(&path) {
+            if state.files.len() >= self.config.max_open_files.max(1) {
+                close_least_recent(&mut state.files)?;
+            }
+            let file = self.open(&path)?;
+            state.files.insert(path.clone(), file);
+        }
+
+        let line = match self.config.format {
+            FileFormat::JsonLines => json_line(p)?,
+            FileFormat::Csv => csv_line(p)?,
+        };
+        let file = state.files.get_mut(&path).unwrap();
+        file.writer.write_all(line.as_bytes())?;
+        file.size += line.len() as u64;
+        file.last_write = Instant::now();
+
+        if self.config.max_size.is_some_and(|max| file.size >= max) {
+            let file = state.files.remove(&path).unwrap();
+            self.rotate(state, &path, file)?;
+        }
+        Ok(())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<OpenFile> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        let mut writer = BufWriter::new(file);
+        let mut header_len = 0;
+        if size == 0 && self.config.format == FileFormat::Csv {
+            writer.write_all(CSV_HEADER.as_bytes())?;
+            header_len = CSV_HEADER.len() as u64;
+        }
+
+        let now = Instant::now();
+        Ok(OpenFile { writer, size: size + header_len, opened: now, last_write: now })
+    }
+
+    // Moves the file aside under a name with the rotation time and compresses it in the background.
+    fn rotate(&self, state: &mut State, path: &Path, file: OpenFile) -> io::Result<()> {
+        let file = file.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        drop(file);
+
+        let stamp = Local::now().format("%Y%m%d%H%M%S").to_string();
+        let mut rotated = PathBuf::from(format!("{}.{}", path.display(), stamp));
+        let mut n = 1;
+        while rotated.exists() || gz_path(&rotated).exists() {
+            rotated = PathBuf::from(format!("{}.{}-{}", path.display(), stamp, n));
+            n += 1;
+        }
+        fs::rename(path, &rotated)?;
+        info!("Rotated {}", rotated.display());
+
+        if self.config.gzip {
+            state.compressing.retain(|t| !t.is_finished());
+            state.compressing.push(thread::spawn(move || {
+                if let Err(err) = gzip(&rotated) {
+                    error!("failed to compress {}: {}", rotated.display(), err);
+                }
+            }));
+        }
+        Ok(())
+    }
+
+    fn rotate_expired(&self, state: &mut State) -> io::Result<()> {
+        let max_age = match self.config.max_age {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+
+        let expired: Vec<PathBuf> = state.files.iter()
+            .filter(|(_, f)| f.opened.elapsed() >= max_age)
+            .map(|(p, _)| p.clone())
+            .collect();
+        for path in expired {
+            let file = state.files.remove(&path).unwrap();
+            self.rotate(state, &path, file)?;
+        }
+        Ok(())
+    }
+}
+
+impl Store for FileStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let io_error = |e: io::Error| StoreError::Transient(e.to_string());
+
+        self.rotate_expired(&mut state).map_err(io_error)?;
+        for p in batch {
+            self.write(&mut state, p).map_err(io_error)?;
+        }
+        for file in state.files.values_mut() {
+            file.writer.flush().map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        for (path, file) in state.files.iter_mut() {
+            if let Err(err) = file.writer.flush() {
+                error!("failed to flush {}: {}", path.display(), err);
+            }
+        }
+        for t in state.compressing.drain(..) {
+            let _ = t.join();
+        }
+    }
+}
+
+fn close_least_recent(files: &mut HashMap<PathBuf, OpenFile>) -> io::Result<()> {
+    let oldest = files.iter().min_by_key(|(_, f)| f.last_write).map(|(p, _)| p.clone());
+    if let Some(path) = oldest {
+        files.remove(&path).unwrap().writer.flush()?;
+    }
+    Ok(())
+}
+
+fn gz_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.gz", path.display()))
+}
+
+// The original is removed only once the compressed copy is complete.
+fn gzip(path: &Path) -> io::Result<()> {
+    let target = gz_path(path);
+    let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+fn json_line(p: &GeoPacket) -> io::Result<String> {
+    let mut line = serde_json::to_string(p)?;
+    line.push('\n');
+    Ok(line)
+}
+
+fn csv_line(p: &GeoPacket) -> io::Result<String> {
+    let params = serde_json::to_string(&p.params)?;
+    let fields = [
+        csv_escape(&p.imei),
+        p.timestamp.format("%Y-%m-%dT%H:%M:%S").to_string(),
+        p.lat.to_string(),
+        p.lon.to_string(),
+        p.speed.to_string(),
+        p.course.to_string(),
+        p.height.to_string(),
+        p.sats.to_string(),
+        csv_escape(&p.ptype),
+        csv_escape(&params),
+    ];
+    Ok(fields.join(",") + "\n")
+}
+
+fn csv_escape(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[test]
+fn test_file_store_rotation() {
+    use std::io::Read;
+    use flate2::read::GzDecoder;
+    use crate::store::Param;
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let template = dir.path().join("{date}/{imei}.csv");
+    let mut config = FileConfig::new(template.to_str().unwrap(), FileFormat::Csv);
+    config.max_size = Some(200);
+    config.gzip = true;
+    let store = FileStore::new(config);
+
+    let mut p = test_packet("../1");
+    p.params.insert(String::from("text"), Param::String(String::from("a,\"b\"")));
+    store.save_batch(&[p.clone(), p.clone(), p]).unwrap();
+    drop(store);
+
+    // the second row pushes the file past the limit and rotates it
+    let day = dir.path().join("2021-04-28");
+    let mut names: Vec<String> = fs::read_dir(&day).unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect();
+    names.sort();
+    assert_eq!(names.len(), 2);
+    assert_eq!(names[0], "___1.csv");
+    assert!(names[1].starts_with("___1.csv.") && names[1].ends_with(".gz"));
+
+    let row = "../1,2021-04-28T05:52:20,53.91821,27.540165,0,0,300,7,SD,\"{\"\"text\"\":\"\"a,\\\"\"b\\\"\"\"\"}\"\n";
+    let mut rotated = String::new();
+    GzDecoder::new(File::open(day.join(&names[1])).unwrap()).read_to_string(&mut rotated).unwrap();
+    assert_eq!(rotated, format!("{}{}{}", CSV_HEADER, row, row));
+    assert_eq!(fs::read_to_string(day.join(&names[0])).unwrap(), format!("{}{}", CSV_HEADER, row));
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::store::{GeoPacket, Param, Store, StoreError};
+
+const FIELDS: [&str; 6] = ["lat", "lon", "speed", "course", "height", "sats"];
+
+#[derive(Clone, Debug)]
+pub enum InfluxTarget {
+    /// Write endpoint, e.g. `http://localhost:8086/api/v2/write?org=ops&bucket=tracks` for InfluxDB 2
+    /// or `http://localhost:8428/write` for VictoriaMetrics.
+    Http(String),
+    /// Lines are appended to the file, e.g. for telegraf to tail.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct InfluxConfig {
+    pub target: InfluxTarget,
+    pub measurement: String,
+    pub timeout: Duration,
+    /// Sent with every HTTP request, e.g. `("Authorization", "Token ...")`.
+    pub headers: Vec<(String, String)>,
+}
+
+impl InfluxConfig {
+    pub fn http(url: &str) -> InfluxConfig {
+        InfluxConfig::new(InfluxTarget::Http(url.to_string()))
+    }
+
+    pub fn file(path: &str) -> InfluxConfig {
+        InfluxConfig::new(InfluxTarget::File(PathBuf::from(path)))
+    }
+
+    fn new(target: InfluxTarget) -> InfluxConfig {
+        InfluxConfig {
+            target,
+            measurement: String::from("position"),
+            timeout: Duration::from_secs(10),
+            headers: Vec::new(),
+        }
+    }
+}
+
+enum Output {
+    Http(ureq::Agent, String),
+    File(Mutex<File>),
+}
+
+/// Writes packets in InfluxDB line protocol, one line per packet with the IMEI as a tag.
+///
+/// Position fields are written as integers (lat and lon as floats), numeric params always as floats, so a
+/// param sent as an integer by one device and as a double by another doesn't conflict in the database.
+/// String params are left out. Timestamps are in nanoseconds.
+pub struct InfluxStore {
+    output: Output,
+    config: InfluxConfig,
+}
+
+impl InfluxStore {
+    pub fn new(config: InfluxConfig) -> Result<InfluxStore, StoreError> {
+        let output = match &config.target {
+            InfluxTarget::Http(url) => {
+                let agent = ureq::AgentBuilder::new().timeout(config.timeout).build();
+                Output::Http(agent, url.clone())
+            }
+            InfluxTarget::File(path) => {
+                if let Some(dir) = path.parent() {
+                    fs::create_dir_all(dir).map_err(|e| StoreError::Permanent(e.to_string()))?;
+                }
+                let file = OpenOptions::new().create(true).append(true).open(path)
+                    .map_err(|e| StoreError::Permanent(e.to_string()))?;
+                Output::File(Mutex::new(file))
+            }
+        };
+        Ok(InfluxStore { output, config })
+    }
+
+    fn line(&self, p: &GeoPacket) -> String {
+        let mut line = format!("{},imei={} lat={},lon={},speed={}i,course={}i,height={}i,sats={}i",
+                               escape(&self.config.measurement, &[',', ' ']), escape(&p.imei, &[',', '=', ' ']),
+                               p.lat, p.lon, p.speed, p.course, p.height, p.sats);
+
+        for (name, value) in &p.params {
+            let value = match value {
+                Param::Int(v) => *v as f64,
+                Param::Float(v) if v.is_finite() => *v,
+                _ => continue,
+            };
+            // a second field with the same key would be ambiguous, the position wins
+            if name.is_empty() || FIELDS.contains(&name.as_str()) {
+                continue;
+            }
+            line.push_str(&format!(",{}={:?}", escape(name, &[',', '=', ' ']), value));
+        }
+
+        line.push_str(&format!(" {}\n", p.timestamp.and_utc().timestamp() * 1_000_000_000));
+        line
+    }
+}
+
+impl Store for InfluxStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let body: String = batch.iter().map(|p| self.line(p)).collect();
+
+        match &self.output {
+            Output::File(file) => {
+                file.lock().unwrap().write_all(body.as_bytes()).map_err(|e| StoreError::Transient(e.to_string()))
+            }
+            Output::Http(agent, url) => {
+                let mut request = agent.post(url).set("Content-Type", "text/plain; charset=utf-8");
+                for (name, value) in &self.config.headers {
+                    request = request.set(name, value);
+                }
+                match request.send_string(&body) {
+                    Ok(_) => Ok(()),
+                    Err(ureq::Error::Status(code, _)) if code == 429 || code >= 500 => {
+                        Err(StoreError::Transient(format!("influx answered {}", code)))
+                    }
+                    Err(ureq::Error::Status(code, response)) => {
+                        let message = response.into_string().unwrap_or_default();
+                        Err(StoreError::Permanent(format!("influx answered {}: {}", code, message.trim())))
+                    }
+                    Err(err) => Err(StoreError::Transient(err.to_string())),
+                }
+            }
+        }
+    }
+}
+
+// Backslash before the characters with a meaning in this part of the line.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\n' | '\r' => escaped.push(' '),
+            c if special.contains(&c) || c == '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[test]
+fn test_influx_store_file() {
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("influx/positions.lp");
+    let store = InfluxStore::new(InfluxConfig::file(path.to_str().unwrap())).unwrap();
+
+    let mut p = GeoPacket { speed: 10, course: 90, ..test_packet("86 1,=") };
+    p.params.insert(String::from("fuel"), Param::Int(40));
+    p.params.insert(String::from("pwr ext"), Param::Float(12.5));
+    p.params.insert(String::from("driver"), Param::String(String::from("Ivanov")));
+    p.params.insert(String::from("speed"), Param::Int(1));
+    store.save_batch(&[p]).unwrap();
+
+    // 53.918°N 27.540°E
+    assert_eq!(fs::read_to_string(&path).unwrap(),
+               "position,imei=86\\ 1\\,\\= lat=53.91821,lon=27.540165,speed=10i,course=90i,height=300i,sats=7i,\
+                fuel=40.0,pwr\\ ext=12.5 1619589140000000000\n");
+}
+
+#[test]
+fn test_influx_store_http() {
+    use std::net::TcpListener;
+    use crate::store::{test_http_server, test_packet};
+
+    let (url, received) = test_http_server(vec![("204 No Content", ""), ("503 Service Unavailable", ""),
+                                                ("400 Bad Request", "partial write: field type conflict")]);
+    let mut config = InfluxConfig::http(&format!("{}/write", url));
+    config.headers.push((String::from("Authorization"), String::from("Token secret")));
+    let store = InfluxStore::new(config).unwrap();
+
+    store.save_batch(&[test_packet("1"), test_packet("2")]).unwrap();
+    let (headers, body) = received.recv().unwrap();
+    assert!(headers.contains(&String::from("authorization: token secret")));
+    assert_eq!(String::from_utf8(body).unwrap().lines().count(), 2);
+
+    // a busy server is retried by the pipeline, a line it can't take is not
+    assert!(matches!(store.save(test_packet("1")), Err(StoreError::Transient(_))));
+    match store.save(test_packet("1")) {
+        Err(StoreError::Permanent(message)) => assert!(message.contains("field type conflict")),
+        other => panic!("{:?}", other),
+    }
+
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let store = InfluxStore::new(InfluxConfig::http(&format!("http://{}/write", closed))).unwrap();
+    assert!(matches!(store.save(test_packet("1")), Err(StoreError::Transient(_))));
+}
@@ -0,0 +1,290 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+use rdkafka::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::{KafkaError, RDKafkaErrorCode};
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+
+use log::error;
+use crate::store::{GeoPacket, Param, Store, StoreError};
+
+/// Schema of the records written with `KafkaFormat::Avro`.
+pub const AVRO_SCHEMA: &str = r#"{"type": "record", "name": "GeoPacket", "namespace": "wialon", "fields": [
+    {"name": "imei", "type": "string"},
+    {"name": "timestamp", "type": {"type": "long", "logicalType": "timestamp-millis"}},
+    {"name": "lat", "type": "double"},
+    {"name": "lon", "type": "double"},
+    {"name": "speed", "type": "int"},
+    {"name": "course", "type": "int"},
+    {"name": "height", "type": "int"},
+    {"name": "sats", "type": "int"},
+    {"name": "ptype", "type": "string"},
+    {"name": "params", "type": {"type": "map", "values": ["int", "double", "string"]}}
+]}"#;
+
+pub use crate::config::KafkaFormat;
+
+#[derive(Clone, Debug)]
+pub struct KafkaConfig {
+    /// `host:port` list of bootstrap brokers.
+    pub brokers: String,
+    pub topic: String,
+    pub format: KafkaFormat,
+    /// How long a batch may wait for its delivery reports.
+    pub delivery_timeout: Duration,
+    /// Passed to librdkafka as is, e.g. `("compression.type", "lz4")`.
+    pub options: Vec<(String, String)>,
+}
+
+impl KafkaConfig {
+    pub fn new(brokers: &str, topic: &str) -> KafkaConfig {
+        KafkaConfig {
+            brokers: brokers.to_string(),
+            topic: topic.to_string(),
+            format: KafkaFormat::Json,
+            delivery_timeout: Duration::from_secs(30),
+            options: Vec::new(),
+        }
+    }
+}
+
+// Delivery report together with the number of the batch the message was sent in.
+type Report = (usize, Result<(), KafkaError>);
+
+// Hands delivery reports to the thread waiting for its batch.
+struct DeliveryContext {
+    reports: Mutex<Sender<Report>>,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = usize;
+
+    fn delivery(&self, result: &DeliveryResult<'_>, batch: usize) {
+        let report = result.as_ref().map(|_| ()).map_err(|(err, _)| err.clone());
+        let _ = self.reports.lock().unwrap().send((batch, report));
+    }
+}
+
+struct Batches {
+    reports: Receiver<Report>,
+    current: usize,
+}
+
+/// Produces packets keyed by IMEI, so positions of a device stay ordered within one partition.
+/// A batch is saved only when the broker acknowledged every message of it.
+pub struct KafkaStore {
+    producer: BaseProducer<DeliveryContext>,
+    batches: Mutex<Batches>,
+    topic: String,
+    format: KafkaFormat,
+    delivery_timeout: Duration,
+}
+
+impl KafkaStore {
+    pub fn new(config: &KafkaConfig) -> Result<KafkaStore, StoreError> {
+        let mut client_config = ClientConfig::new();
+        client_config
+            .set("bootstrap.servers", &config.brokers)
+            // retries inside librdkafka must not reorder messages of a device
+            .set("enable.idempotence", "true")
+            .set("message.timeout.ms", config.delivery_timeout.as_millis().to_string());
+        for (key, value) in &config.options {
+            client_config.set(key, value);
+        }
+
+        let (sender, receiver) = channel();
+        let context = DeliveryContext { reports: Mutex::new(sender) };
+        let producer = client_config.create_with_context(context).map_err(kafka_error)?;
+
+        Ok(KafkaStore {
+            producer,
+            batches: Mutex::new(Batches { reports: receiver, current: 0 }),
+            topic: config.topic.clone(),
+            format: config.format,
+            delivery_timeout: config.delivery_timeout,
+        })
+    }
+
+    fn encode(&self, p: &GeoPacket) -> Result<Vec<u8>, StoreError> {
+        match self.format {
+            KafkaFormat::Json => serde_json::to_vec(p).map_err(|e| StoreError::Permanent(e.to_string())),
+            KafkaFormat::Avro => Ok(avro_encode(p)),
+        }
+    }
+}
+
+impl Store for KafkaStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        // one batch at a time, late reports of a batch which timed out are told apart by its number
+        let mut batches = self.batches.lock().unwrap();
+        batches.current += 1;
+        let current = batches.current;
+
+        for p in batch {
+            let payload = self.encode(p)?;
+            let mut record = BaseRecord::with_opaque_to(&self.topic, current).key(&p.imei).payload(&payload);
+            loop {
+                match self.producer.send(record) {
+                    Ok(_) => break,
+                    Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), r)) => {
+                        // serving delivery reports makes room in the local queue
+                        self.producer.poll(Duration::from_millis(100));
+                        record = r;
+                    }
+                    Err((err, _)) => return Err(kafka_error(err)),
+                }
+            }
+        }
+
+        self.producer.flush(self.delivery_timeout).map_err(kafka_error)?;
+
+        let mut failed = None;
+        let mut pending = batch.len();
+        while pending > 0 {
+            match batches.reports.recv_timeout(self.delivery_timeout) {
+                Ok((n, _)) if n != current => {}
+                Ok((_, Ok(_))) => pending -= 1,
+                Ok((_, Err(err))) => {
+                    error!("kafka delivery failed: {}", err);
+                    failed = Some(err);
+                    pending -= 1;
+                }
+                Err(_) => return Err(StoreError::Transient(String::from("kafka delivery report timed out"))),
+            }
+        }
+        match failed {
+            Some(err) => Err(kafka_error(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+// Rejected messages won't get through on a retry, anything else (broker away, timeouts) may.
+fn kafka_error(err: KafkaError) -> StoreError {
+    let permanent = matches!(err.rdkafka_error_code(),
+        Some(RDKafkaErrorCode::MessageSizeTooLarge)
+        | Some(RDKafkaErrorCode::InvalidMessage)
+        | Some(RDKafkaErrorCode::InvalidMessageSize)
+        | Some(RDKafkaErrorCode::TopicAuthorizationFailed)
+        | Some(RDKafkaErrorCode::InvalidArgument))
+        || matches!(err, KafkaError::ClientConfig(..));
+
+    if permanent {
+        StoreError::Permanent(err.to_string())
+    } else {
+        StoreError::Transient(err.to_string())
+    }
+}
+
+fn avro_encode(p: &GeoPacket) -> Vec<u8> {
+    let mut buf = Vec::new();
+    avro_string(&mut buf, &p.imei);
+    avro_long(&mut buf, p.timestamp.and_utc().timestamp_millis());
+    buf.extend_from_slice(&p.lat.to_le_bytes());
+    buf.extend_from_slice(&p.lon.to_le_bytes());
+    for v in &[p.speed, p.course, p.height, p.sats] {
+        avro_long(&mut buf, *v as i64);
+    }
+    avro_string(&mut buf, &p.ptype);
+
+    // a map is a block of entries closed by an empty block, values are union branches
+    if !p.params.is_empty() {
+        avro_long(&mut buf, p.params.len() as i64);
+        for (name, value) in &p.params {
+            avro_string(&mut buf, name);
+            match value {
+                Param::Int(v) => {
+                    avro_long(&mut buf, 0);
+                    avro_long(&mut buf, *v as i64);
+                }
+                Param::Float(v) => {
+                    avro_long(&mut buf, 1);
+                    buf.extend_from_slice(&v.to_le_bytes());
+                }
+                Param::String(v) => {
+                    avro_long(&mut buf, 2);
+                    avro_string(&mut buf, v);
+                }
+            }
+        }
+    }
+    avro_long(&mut buf, 0);
+    buf
+}
+
+// Zigzag encoded variable length integer.
+fn avro_long(buf: &mut Vec<u8>, v: i64) {
+    let mut n = ((v << 1) ^ (v >> 63)) as u64;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn avro_string(buf: &mut Vec<u8>, s: &str) {
+    avro_long(buf, s.len() as i64);
+    buf.extend_from_slice(s.as_bytes());
+}
+
+#[test]
+fn test_avro_encode() {
+    use std::convert::TryFrom;
+    use crate::wialon::ShortDataPacket;
+
+    let spd = ShortDataPacket::try_from(vec!("010170", "000001", "0", "N", "0", "E", "1", "-1", "300", "7")).unwrap();
+    let mut p = GeoPacket::new(b"12".to_vec(), &spd);
+    p.params.insert(String::from("a"), Param::Int(-2));
+
+    let mut expected = vec![4, b'1', b'2', 0xd0, 0x0f];
+    expected.extend_from_slice(&[0; 16]);
+    expected.extend_from_slice(&[2, 1, 0xd8, 0x04, 14, 4, b'S', b'D']);
+    expected.extend_from_slice(&[2, 2, b'a', 0, 3, 0]);
+    assert_eq!(avro_encode(&p), expected);
+}
+
+// Needs a broker, run with
+// `WIALON_TEST_KAFKA=localhost:9092 cargo test --features kafka -- --ignored`
+#[test]
+#[ignore]
+fn test_kafka_store() {
+    use crate::store::test_packet;
+
+    let brokers = std::env::var("WIALON_TEST_KAFKA").unwrap_or_else(|_| String::from("localhost:9092"));
+    let mut config = KafkaConfig::new(&brokers, "wialon-test");
+    config.options.push((String::from("allow.auto.create.topics"), String::from("true")));
+    let store = KafkaStore::new(&config).unwrap();
+
+    let batch: Vec<GeoPacket> = (0..10).map(|i| test_packet(&i.to_string())).collect();
+    store.save_batch(&batch).unwrap();
+}
+
+#[test]
+fn test_kafka_store_errors() {
+    use std::net::TcpListener;
+    use crate::store::test_packet;
+
+    let permanent = |err: KafkaError| matches!(kafka_error(err), StoreError::Permanent(_));
+    assert!(permanent(KafkaError::MessageProduction(RDKafkaErrorCode::MessageSizeTooLarge)));
+    assert!(permanent(KafkaError::MessageProduction(RDKafkaErrorCode::TopicAuthorizationFailed)));
+    assert!(!permanent(KafkaError::MessageProduction(RDKafkaErrorCode::MessageTimedOut)));
+    assert!(!permanent(KafkaError::Flush(RDKafkaErrorCode::OperationTimedOut)));
+
+    let mut config = KafkaConfig::new("localhost:9092", "wialon-test");
+    config.options.push((String::from("no.such.option"), String::from("1")));
+    assert!(matches!(KafkaStore::new(&config), Err(StoreError::Permanent(_))));
+
+    // messages not delivered in time are retried by the pipeline
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let mut config = KafkaConfig::new(&closed.to_string(), "wialon-test");
+    config.delivery_timeout = Duration::from_millis(500);
+    let store = KafkaStore::new(&config).unwrap();
+    assert!(matches!(store.save_batch(&[test_packet("1")]), Err(StoreError::Transient(_))));
+}
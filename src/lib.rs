@@ -0,0 +1,41 @@
+pub mod wialon;
+pub mod server;
+pub mod store;
+pub mod pipeline;
+pub mod ack;
+pub mod wal;
+pub mod queue;
+pub mod default_store;
+pub mod composite_store;
+pub mod file_store;
+pub mod retranslator_store;
+pub mod listener;
+pub mod auth;
+pub mod capture;
+pub mod config;
+pub mod replay;
+pub mod simulator;
+pub mod client;
+
+mod connection;
+mod worker;
+mod transport;
+
+#[cfg(feature = "async")]
+pub mod async_server;
+#[cfg(feature = "postgres")]
+pub mod postgres_store;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_store;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_store;
+#[cfg(feature = "kafka")]
+pub mod kafka_store;
+#[cfg(feature = "webhook")]
+pub mod webhook_store;
+#[cfg(feature = "influx")]
+pub mod influx_store;
+#[cfg(feature = "redis")]
+pub mod redis_store;
+#[cfg(feature = "arrow")]
+pub mod arrow_store;
@@ -0,0 +1,75 @@
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// Certificate chain and private key (both PEM) used to terminate TLS on a listener.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Address the server accepts device connections on and how they are treated.
+#[derive(Clone, Debug)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsConfig>,
+    /// Accept logins over unencrypted connections. Has no effect on TLS listeners.
+    pub allow_plain_login: bool,
+}
+
+impl ListenerConfig {
+    pub fn plain(addr: &str) -> ListenerConfig {
+        ListenerConfig {
+            addr: addr.parse().unwrap(),
+            tls: None,
+            allow_plain_login: true,
+        }
+    }
+
+    pub fn tls(addr: &str, cert_path: &str, key_path: &str) -> ListenerConfig {
+        ListenerConfig {
+            addr: addr.parse().unwrap(),
+            tls: Some(TlsConfig {
+                cert_path: PathBuf::from(cert_path),
+                key_path: PathBuf::from(key_path),
+            }),
+            allow_plain_login: false,
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl TlsConfig {
+    pub fn server_config(&self) -> io::Result<Arc<rustls::ServerConfig>> {
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+        use rustls::pki_types::pem::PemObject;
+
+        let invalid = |err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err));
+
+        let certs = CertificateDer::pem_file_iter(&self.cert_path)
+            .map_err(invalid)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        let key = PrivateKeyDer::from_pem_file(&self.key_path).map_err(invalid)?;
+
+        let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+
+        Ok(Arc::new(config))
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+impl TlsConfig {
+    pub fn check(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "TLS support is not compiled in, rebuild with --features tls"))
+    }
+}
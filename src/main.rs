@@ -1,42 +1,305 @@
-use std::io;
+use std::io::{self, BufReader, Read};
 use std::env;
-use std::str;
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
+use wialon_protocol::capture::{self, Capture};
+use wialon_protocol::config::{self, Config, LogSection};
+use wialon_protocol::replay::{self, ReplayConfig};
+use wialon_protocol::simulator::{self, SimulatorConfig, Track};
+use wialon_protocol::wialon::Packet;
 
-mod wialon;
-mod server;
-mod connection;
-mod store;
-mod default_store;
+const USAGE: &str = "usage: wialon-protocol [serve] [--config <path>] [--check-config]
+       wialon-protocol parse <packet>... | --file <path>
+       wialon-protocol replay <addr> <capture> [--speed <factor>] [--max-gap <duration>] [--timeout <duration>]
+       wialon-protocol replay --in-process <file.wcap>
+       wialon-protocol simulate <addr> [--devices <n>] [--packets <n>] [--interval <duration>] [--duration <duration>]
+                                       [--imei <first>] [--password <password>] [--speed <km/h>] [--gpx <file>]
+                                       [--data-ratio <0..1>] [--black-box <n>] [--ping <duration>] [--timeout <duration>]
 
+commands:
+  serve     run the server, the default
+  parse     print packets as JSON, one document per packet, `--file -` reads them from stdin
+  replay    send a capture, one packet per line, to a running server paced by the packet timestamps,
+            or feed a raw traffic capture through a connection in this process and compare the answers
+  simulate  connect fake devices driving along generated or GPX routes, then report acks, latency and errors
 
-use crate::server::Server;
-use crate::default_store::ConsoleStore;
+serve options:
+  -c, --config <path>  TOML configuration, wialon.toml by default
+      --check-config   validate the configuration and exit";
 
 fn main() -> io::Result<()> {
-    env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+
+    let command = match args.first().map(String::as_str) {
+        Some("serve") | Some("parse") | Some("replay") | Some("simulate") => args.remove(0),
+        _ => String::from("serve"),
+    };
+    match command.as_str() {
+        "parse" => parse(args),
+        "replay" => replay(args),
+        "simulate" => simulate(args),
+        _ => serve(args),
+    }
+}
+
+fn serve(args: Vec<String>) -> io::Result<()> {
+    let mut path = String::from("wialon.toml");
+    let mut check_only = false;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" | "--config" => path = value(&mut args, &arg),
+            "--check-config" => check_only = true,
+            _ => usage_error(&format!("unknown argument {}", arg)),
+        }
+    }
 
-    let args: Vec<String> = env::args().collect();
+    let config = match Config::load(Path::new(&path)) {
+        Ok(c) => c,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+    };
 
-    if args.len() < 3 {
-        println!("usage: wialon-protocol <listner_addr> <buf_size>");
+    if check_only {
+        match config.check() {
+            Ok(_) => println!("{}: ok", path),
+            Err(err) => {
+                eprintln!("{}: {}", path, err);
+                process::exit(1);
+            }
+        }
         return Ok(());
     }
 
-    let addr: &str = &args[1];
+    init_logging(&config.log);
+    let mut s = config.build()?;
+
+    let shutdown = s.shutdown_handle();
+    if let Err(err) = ctrlc::set_handler(move || shutdown.shutdown()) {
+        println!("Failed to install signal handler: {}", err);
+    }
+
+    s.start()
+}
+
+fn parse(args: Vec<String>) -> io::Result<()> {
+    let mut file = None;
+    let mut packets = Vec::new();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-f" | "--file" => file = Some(value::<String>(&mut args, &arg)),
+            _ if arg.starts_with("--") => usage_error(&format!("unknown argument {}", arg)),
+            _ => packets.push(arg),
+        }
+    }
+
+    if let Some(path) = file {
+        let mut raw = Vec::new();
+        open(&path)?.read_to_end(&mut raw)?;
+        packets.extend(String::from_utf8_lossy(&raw).lines().map(String::from));
+    } else if packets.is_empty() {
+        usage_error("parse needs a packet or --file");
+    }
+
+    let mut failed = 0;
+    for (n, line) in packets.iter().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match decode(line) {
+            Ok(json) => println!("{}", json),
+            Err(err) => {
+                eprintln!("line {}: {}: {}", n + 1, err, line);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+// Pretty JSON of a packet, the line ending is optional.
+fn decode(line: &str) -> Result<String, String> {
+    let raw = format!("{}\r\n", line.trim_end());
+    Packet::from(raw.as_bytes())
+        .map_err(String::from)
+        .and_then(|p| serde_json::to_string_pretty(&p).map_err(|err| err.to_string()))
+}
+
+fn replay(args: Vec<String>) -> io::Result<()> {
+    let mut config = ReplayConfig::default();
+    let mut positional = Vec::new();
+    let mut in_process = false;
 
-    let buf_size: usize = match args[2].parse() {
-        Ok(n) => n,
-        Err(_) => {
-            println!("Required param buf size not found");
-            return Ok(());
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--in-process" => in_process = true,
+            "--speed" => config.speed = value(&mut args, &arg),
+            "--max-gap" => config.max_gap = duration(&mut args, &arg),
+            "--timeout" => config.timeout = duration(&mut args, &arg),
+            _ if arg.starts_with("--") => usage_error(&format!("unknown argument {}", arg)),
+            _ => positional.push(arg),
         }
+    }
+    if in_process {
+        return match positional.as_slice() {
+            [capture] => replay_in_process(Path::new(capture)),
+            _ => usage_error("replay --in-process needs a capture file"),
+        };
+    }
+    let (addr, capture) = match positional.as_slice() {
+        [addr, capture] => (addr, capture),
+        _ => usage_error("replay needs a server address and a capture"),
     };
+    if config.speed <= 0.0 {
+        usage_error("--speed must be positive");
+    }
 
-    let db = ConsoleStore::new();
-    let mut s = Server::new(addr, buf_size, db);
-    s.start()
+    init_tool_logging();
+    let report = replay::replay(addr, BufReader::new(open(capture)?), &config, |line, answer| {
+        println!("> {}", line);
+        match answer {
+            Some(answer) => println!("< {}", answer),
+            None => println!("< no answer"),
+        }
+    })?;
+    eprintln!("sent {} lines, {} answered", report.sent, report.answered);
+    Ok(())
+}
+
+// Feeds a `.wcap` file through a connection of this build and compares the answers.
+fn replay_in_process(path: &Path) -> io::Result<()> {
+    init_tool_logging();
+    let capture = Capture::read(path)?;
+    let replay = capture::replay(&capture)?;
+    println!("{} connected at {}", capture.peer, capture.started);
+
+    let mut differences = 0;
+    let recorded = capture.answers();
+    for (i, record) in capture.inbound().enumerate() {
+        println!("> {}", String::from_utf8_lossy(&record.data).escape_debug());
+        let answer = recorded.get(i).cloned().unwrap_or_default();
+        println!("< {}", String::from_utf8_lossy(&answer).escape_debug());
+        match replay.answers.get(i) {
+            Some(replayed) if *replayed == answer => {}
+            Some(replayed) => {
+                differences += 1;
+                println!("! {}", String::from_utf8_lossy(replayed).escape_debug());
+            }
+            None => {
+                differences += 1;
+                println!("! connection closed");
+            }
+        }
+    }
+    eprintln!("{} packets stored, {} answers differ", replay.packets.len(), differences);
+    if differences > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+fn simulate(args: Vec<String>) -> io::Result<()> {
+    let mut addr = None;
+    let mut config = SimulatorConfig::new("");
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--devices" => config.devices = value(&mut args, &arg),
+            "--packets" => config.packets = value(&mut args, &arg),
+            "--interval" => config.interval = duration(&mut args, &arg),
+            "--imei" => config.first_imei = value(&mut args, &arg),
+            "--password" => config.password = value(&mut args, &arg),
+            "--speed" => config.speed = value(&mut args, &arg),
+            "--duration" => config.duration = Some(duration(&mut args, &arg)),
+            "--gpx" => {
+                let path: String = value(&mut args, &arg);
+                config.track = Track::from_gpx(Path::new(&path))?;
+            }
+            "--data-ratio" => config.data_ratio = value(&mut args, &arg),
+            "--black-box" => config.black_box = value(&mut args, &arg),
+            "--ping" => config.ping_interval = Some(duration(&mut args, &arg)),
+            "--timeout" => config.timeout = duration(&mut args, &arg),
+            _ if arg.starts_with("--") || addr.is_some() => usage_error(&format!("unknown argument {}", arg)),
+            _ => addr = Some(arg),
+        }
+    }
+    config.addr = addr.unwrap_or_else(|| usage_error("simulate needs a server address"));
+    if !(0.0..=1.0).contains(&config.data_ratio) {
+        usage_error("--data-ratio must be between 0 and 1");
+    }
+
+    init_tool_logging();
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    if let Err(err) = ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed)) {
+        println!("Failed to install signal handler: {}", err);
+    }
+
+    let report = simulator::simulate(&config, stop);
+    println!("{}", report);
+    Ok(())
+}
+
+// Reads a file or stdin for `-`.
+fn open(path: &str) -> io::Result<Box<dyn Read>> {
+    if path == "-" {
+        return Ok(Box::new(io::stdin()));
+    }
+    File::open(path)
+        .map(|f| Box::new(f) as Box<dyn Read>)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path, err)))
+}
+
+fn value<T: FromStr>(args: &mut impl Iterator<Item = String>, name: &str) -> T {
+    match args.next().map(|v| v.parse()) {
+        Some(Ok(v)) => v,
+        Some(Err(_)) => usage_error(&format!("invalid value of {}", name)),
+        None => usage_error(&format!("{} needs a value", name)),
+    }
+}
+
+fn duration(args: &mut impl Iterator<Item = String>, name: &str) -> Duration {
+    let v: String = value(args, name);
+    config::parse_duration(&v).unwrap_or_else(|err| usage_error(&format!("{}: {}", name, err)))
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn init_logging(log: &LogSection) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(&log.level));
+    if log.stdout {
+        builder.target(env_logger::Target::Stdout);
+    }
+    builder.init();
+}
+
+// Debugging commands only report problems unless RUST_LOG asks for more.
+fn init_tool_logging() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 }
 
 #[test]
@@ -44,18 +307,15 @@ fn test_server() {
     env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
-    use std::{thread, time};
+    use std::thread;
     use std::io::prelude::*;
     use std::net::TcpStream;
+    use wialon_protocol::server::Server;
+    use wialon_protocol::default_store::ConsoleStore;
 
-    let addr = "0.0.0.0:5555";
-    thread::spawn(move || {
-        let db = ConsoleStore::new();
-        let mut s = Server::new(addr, 100, db);
-        s.start()
-    });
-    // TODO: replace to channel
-    thread::sleep(time::Duration::from_secs(1));
+    let mut s = Server::new("127.0.0.1:0", 100, 2, ConsoleStore::new()).unwrap();
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
 
     let mut stream = TcpStream::connect(addr).unwrap();
     let rlt = &mut [0; 128];
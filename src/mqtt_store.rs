@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use rumqttc::{Client, ClientError, Connection, Event, MqttOptions, Outgoing, Packet, QoS};
+
+use log::{info, error};
+use crate::store::{GeoPacket, Store, StoreError};
+
+#[derive(Clone, Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Session on the broker, unique per instance: a second client with the same id takes it over.
+    pub client_id: String,
+    /// Topic of a packet, `{imei}` is replaced with the device IMEI.
+    pub topic: String,
+    /// 0, 1 or 2.
+    pub qos: u8,
+    /// Keep the last position on the broker for new subscribers.
+    pub retain: bool,
+    pub credentials: Option<(String, String)>,
+    pub keep_alive: Duration,
+    /// Pause between attempts to reach the broker again.
+    pub reconnect_delay: Duration,
+    /// Publishes queued for the connection thread.
+    pub queue_size: usize,
+    /// Longest wait for the broker to acknowledge a batch published with QoS 1 or 2.
+    pub ack_timeout: Duration,
+}
+
+impl MqttConfig {
+    pub fn new(host: &str, port: u16, client_id: &str) -> MqttConfig {
+        MqttConfig {
+            host: host.to_string(),
+            port,
+            client_id: client_id.to_string(),
+            topic: String::from("wialon/{imei}/position"),
+            qos: 1,
+            retain: true,
+            credentials: None,
+            keep_alive: Duration::from_secs(30),
+            reconnect_delay: Duration::from_secs(1),
+            queue_size: 1000,
+            ack_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// Publishes the connection thread handed to the broker and those the broker hasn't acknowledged yet.
+// A publish resent after a reconnect keeps its packet id, so it is counted once.
+#[derive(Default)]
+struct Acks {
+    published: u64,
+    unacked: HashSet<u16>,
+}
+
+/// Publishes every packet as JSON to its device's topic.
+///
+/// With QoS 1 or 2 a batch is saved once the broker acknowledged every publish of it, with QoS 0 as soon
+/// as it is queued for the connection, so `AckMode::AfterStore` gives no guarantee then.
+pub struct MqttStore {
+    client: Client,
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    ack_timeout: Duration,
+    connected: Arc<AtomicBool>,
+    stopping: Arc<AtomicBool>,
+    acks: Arc<(Mutex<Acks>, Condvar)>,
+    // one batch at a time, so the publishes counted after it started are its own
+    publishing: Mutex<()>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl MqttStore {
+    pub fn new(config: &MqttConfig) -> Result<MqttStore, StoreError> {
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            2 => QoS::ExactlyOnce,
+            q => return Err(StoreError::Permanent(format!("invalid MQTT QoS: {}", q))),
+        };
+
+        let mut options = MqttOptions::new(config.client_id.as_str(), config.host.as_str(), config.port);
+        options.set_keep_alive(config.keep_alive);
+        // unacknowledged publishes are resent after a reconnect
+        options.set_clean_session(false);
+        if let Some((user, password)) = &config.credentials {
+            options.set_credentials(user.as_str(), password.as_str());
+        }
+
+        let (client, connection) = Client::new(options, config.queue_size.max(1));
+        let connected = Arc::new(AtomicBool::new(false));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let acks = Arc::new((Mutex::new(Acks::default()), Condvar::new()));
+        let thread = {
+            let (connected, stopping, acks) = (connected.clone(), stopping.clone(), acks.clone());
+            let delay = config.reconnect_delay;
+            thread::Builder::new()
+                .name(String::from("wialon-mqtt"))
+                .spawn(move || run_connection(connection, connected, stopping, acks, delay))
+                .map_err(|e| StoreError::Permanent(e.to_string()))?
+        };
+
+        Ok(MqttStore {
+            client,
+            topic: config.topic.clone(),
+            qos,
+            retain: config.retain,
+            ack_timeout: config.ack_timeout,
+            connected,
+            stopping,
+            acks,
+            publishing: Mutex::new(()),
+            thread: Some(thread),
+        })
+    }
+
+    fn topic(&self, p: &GeoPacket) -> String {
+        // wildcards and separators from a device must not change the topic structure
+        let imei: String = p.imei.chars()
+            .map(|c| if c == '/' || c == '+' || c == '#' { '_' } else { c })
+            .collect();
+        self.topic.replace("{imei}", &imei)
+    }
+}
+
+impl Store for MqttStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        // the pipeline retries with a backoff while the broker is away
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(StoreError::Transient(String::from("not connected to MQTT broker")));
+        }
+
+        let _publishing = self.publishing.lock().unwrap();
+        let published = self.acks.0.lock().unwrap().published;
+        for p in batch {
+            let payload = serde_json::to_vec(p).map_err(|e| StoreError::Permanent(e.to_string()))?;
+            self.client.try_publish(self.topic(p), self.qos, self.retain, payload).map_err(|e| match e {
+                ClientError::TryRequest(_) => StoreError::Transient(String::from("MQTT publish queue is full")),
+                e => StoreError::Transient(e.to_string()),
+            })?;
+        }
+        if self.qos == QoS::AtMostOnce {
+            return Ok(());
+        }
+        self.wait_for_acks(published + batch.len() as u64)
+    }
+}
+
+impl MqttStore {
+    // Waits until the connection thread handed `published` publishes to the broker and none of them is
+    // unacknowledged. A batch which isn't confirmed in time is saved again, its publishes may arrive twice.
+    fn wait_for_acks(&self, published: u64) -> Result<(), StoreError> {
+        let deadline = Instant::now() + self.ack_timeout;
+        let (acks, changed) = &*self.acks;
+        let mut acks = acks.lock().unwrap();
+        while acks.published < published || !acks.unacked.is_empty() {
+            if !self.connected.load(Ordering::SeqCst) {
+                return Err(StoreError::Transient(String::from("connection to MQTT broker lost")));
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(StoreError::Transient(String::from("no acknowledgement from MQTT broker")));
+            }
+            acks = changed.wait_timeout(acks, left.min(Duration::from_millis(100))).unwrap().0;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MqttStore {
+    // Publishes already queued are sent before the disconnect.
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let _ = self.client.try_disconnect();
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+// Drives the client, the event loop reconnects by itself on the next poll after an error.
+fn run_connection(mut connection: Connection, connected: Arc<AtomicBool>, stopping: Arc<AtomicBool>,
+                  acks: Arc<(Mutex<Acks>, Condvar)>, delay: Duration) {
+    let acked = |pkid: u16| {
+        acks.0.lock().unwrap().unacked.remove(&pkid);
+        acks.1.notify_all();
+    };
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker");
+                connected.store(true, Ordering::SeqCst);
+            }
+            Ok(Event::Outgoing(Outgoing::Publish(pkid))) => {
+                let mut state = acks.0.lock().unwrap();
+                // QoS 0 publishes have no id and no acknowledgement
+                if pkid == 0 || state.unacked.insert(pkid) {
+                    state.published += 1;
+                }
+                acks.1.notify_all();
+            }
+            Ok(Event::Incoming(Packet::PubAck(ack))) => acked(ack.pkid),
+            Ok(Event::Incoming(Packet::PubComp(comp))) => acked(comp.pkid),
+            Ok(Event::Outgoing(Outgoing::Disconnect)) if stopping.load(Ordering::SeqCst) => break,
+            Ok(_) => {}
+            Err(err) => {
+                connected.store(false, Ordering::SeqCst);
+                acks.1.notify_all();
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                error!("MQTT connection error: {}, reconnecting in {:?}", err, delay);
+                thread::sleep(delay);
+            }
+        }
+    }
+    info!("MQTT connection closed");
+}
+
+// Needs a broker such as mosquitto, run with
+// `WIALON_TEST_MQTT=localhost:1883 cargo test --features mqtt -- --ignored`
+#[test]
+#[ignore]
+fn test_mqtt_store_retained() {
+    use std::time::Instant;
+    use crate::store::test_packet;
+
+    let addr = std::env::var("WIALON_TEST_MQTT").unwrap_or_else(|_| String::from("localhost:1883"));
+    let (host, port) = addr.split_once(':').unwrap();
+    let config = MqttConfig::new(host, port.parse().unwrap(), "wialon-test-store");
+    let store = MqttStore::new(&config).unwrap();
+
+    let p = test_packet("mqtt/test");
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while let Err(err) = store.save(p.clone()) {
+        assert!(Instant::now() < deadline, "{}", err);
+        thread::sleep(Duration::from_millis(100));
+    }
+    drop(store);
+
+    // a new subscriber gets the retained last position
+    let (client, mut connection) = Client::new(MqttOptions::new("wialon-test", host, port.parse().unwrap()), 10);
+    client.subscribe("wialon/mqtt_test/position", QoS::AtLeastOnce).unwrap();
+    for event in connection.iter() {
+        if let Event::Incoming(Packet::Publish(publish)) = event.unwrap() {
+            let received: GeoPacket = serde_json::from_slice(&publish.payload).unwrap();
+            assert_eq!(received, p);
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_mqtt_store_waits_for_puback() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use crate::store::test_packet;
+
+    // broker holding back its PUBACKs until `acking` is set
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let acking = Arc::new(AtomicBool::new(false));
+    {
+        let acking = acking.clone();
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+            let mut held = Vec::new();
+            loop {
+                let mut header = [0; 1];
+                match socket.read_exact(&mut header) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        if acking.load(Ordering::SeqCst) {
+                            socket.write_all(&held).unwrap();
+                            held.clear();
+                        }
+                        continue;
+                    }
+                    Err(_) => return,
+                }
+                socket.set_read_timeout(None).unwrap();
+                let (mut len, mut shift, mut byte) = (0, 0, [0x80]);
+                while byte[0] & 0x80 != 0 {
+                    socket.read_exact(&mut byte).unwrap();
+                    len |= ((byte[0] & 0x7f) as usize) << shift;
+                    shift += 7;
+                }
+                let mut body = vec![0; len];
+                socket.read_exact(&mut body).unwrap();
+                socket.set_read_timeout(Some(Duration::from_millis(20))).unwrap();
+                match header[0] >> 4 {
+                    1 => socket.write_all(&[0x20, 2, 0, 0]).unwrap(),
+                    3 => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        held.extend_from_slice(&[0x40, 2, body[2 + topic_len], body[3 + topic_len]]);
+                    }
+                    12 => socket.write_all(&[0xd0, 0]).unwrap(),
+                    _ => return,
+                }
+            }
+        });
+    }
+
+    let mut config = MqttConfig::new("127.0.0.1", port, "wialon-test");
+    config.ack_timeout = Duration::from_millis(300);
+    let store = MqttStore::new(&config).unwrap();
+    let p = test_packet("1");
+    let save = || {
+        let start = Instant::now();
+        while let Err(err) = store.save(p.clone()) {
+            if !err.to_string().contains("not connected") {
+                return Err(err);
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        Ok(())
+    };
+
+    // queued for the broker is not saved yet
+    let err = save().unwrap_err();
+    assert!(err.is_transient() && err.to_string().contains("acknowledgement"), "{}", err);
+    acking.store(true, Ordering::SeqCst);
+    save().unwrap();
+}
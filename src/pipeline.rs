@@ -0,0 +1,233 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+use log::{info, warn, error};
+use crate::ack::{Ack, Delivery};
+use crate::store::{GeoPacket, Store, StoreError};
+
+#[derive(Clone, Debug)]
+pub struct PipelineConfig {
+    /// Batch is saved as soon as it has this many packets...
+    pub batch_size: usize,
+    /// ...or when its first packet waited this long.
+    pub batch_timeout: Duration,
+    /// Delay before the first retry, doubled on every next one.
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+    /// Transient failures tolerated before the batch goes to the dead-letter sink. Batches read from
+    /// a write-ahead log are retried until the store takes them.
+    pub max_retries: u32,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> PipelineConfig {
+        PipelineConfig {
+            batch_size: 100,
+            batch_timeout: Duration::from_secs(1),
+            retry_delay: Duration::from_millis(100),
+            max_retry_delay: Duration::from_secs(30),
+            max_retries: 10,
+        }
+    }
+}
+
+/// Moves packets from the bus into a `Store` in batches, retrying transient failures.
+pub struct Pipeline<T: Store> {
+    db: T,
+    config: PipelineConfig,
+    dead_letter: Option<Box<dyn Store + Send>>,
+}
+
+impl<T: Store> Pipeline<T> {
+    pub fn new(db: T, config: PipelineConfig) -> Pipeline<T> {
+        Pipeline {
+            db,
+            config,
+            dead_letter: None,
+        }
+    }
+
+    pub fn config(&self) -> &PipelineConfig {
+        &self.config
+    }
+
+    /// Where batches that can't be saved end up. Without it they are only logged.
+    pub fn set_dead_letter<D: 'static + Store + Send>(&mut self, sink: D) {
+        self.dead_letter = Some(Box::new(sink));
+    }
+
+    /// Runs until every sender of the bus is dropped, the last batch is flushed before returning.
+    pub fn run(&self, bus: Receiver<Delivery>) {
+        let batch_size = self.config.batch_size.max(1);
+        let mut batch: Vec<GeoPacket> = Vec::with_capacity(batch_size);
+        let mut acks: Vec<Ack> = Vec::new();
+        let mut deadline = Instant::now();
+
+        loop {
+            let received = if batch.is_empty() {
+                bus.recv().map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                bus.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            };
+
+            match received {
+                Ok(d) => {
+                    if batch.is_empty() {
+                        deadline = Instant::now() + self.config.batch_timeout;
+                    }
+                    batch.push(d.packet);
+                    acks.extend(d.ack);
+                    if batch.len() < batch_size {
+                        continue;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    if !batch.is_empty() {
+                        self.save(&batch, acks);
+                    }
+                    info!("Store pipeline stopped");
+                    return;
+                }
+            }
+
+            self.save(&batch, std::mem::take(&mut acks));
+            batch.clear();
+        }
+    }
+
+    // Devices waiting for the store get their response only when the batch is persisted,
+    // either in the store or in the dead-letter sink. Otherwise they resend it later.
+    pub(crate) fn save(&self, batch: &[GeoPacket], acks: Vec<Ack>) {
+        if self.save_batch(batch, Some(self.config.max_retries)) {
+            for ack in acks {
+                ack.confirm();
+            }
+        }
+    }
+
+    // The log keeps the batch until it is confirmed, so there is no point in giving up on an outage.
+    // Returns whether the batch is persisted in the store or the dead-letter sink.
+    pub(crate) fn save_logged(&self, batch: &[GeoPacket]) -> bool {
+        self.save_batch(batch, None)
+    }
+
+    // A batch the store refuses is split up, so only the packets it refuses on their own are dead-lettered.
+    fn save_batch(&self, batch: &[GeoPacket], max_retries: Option<u32>) -> bool {
+        let err = match self.retry(batch, max_retries) {
+            Ok(_) => return true,
+            Err(err) => err,
+        };
+        if err.is_transient() || batch.len() == 1 {
+            error!("{}, {} packets sent to dead letter", err, batch.len());
+            return self.to_dead_letter(batch, err);
+        }
+
+        warn!("{}, saving {} packets one by one", err, batch.len());
+        let mut persisted = true;
+        for p in batch {
+            let packet = std::slice::from_ref(p);
+            if let Err(err) = self.retry(packet, max_retries) {
+                error!("{}, packet of {} sent to dead letter", err, p.imei);
+                persisted &= self.to_dead_letter(packet, err);
+            }
+        }
+        persisted
+    }
+
+    fn retry(&self, batch: &[GeoPacket], max_retries: Option<u32>) -> Result<(), StoreError> {
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 0;
+
+        loop {
+            match self.db.save_batch(batch) {
+                Ok(_) => return Ok(()),
+                Err(err) if err.is_transient() && max_retries.is_none_or(|max| attempt < max) => {
+                    attempt += 1;
+                    warn!("{}, retry {} in {:?}", err, attempt, delay);
+                    thread::sleep(delay);
+                    delay = (delay * 2).min(self.config.max_retry_delay);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn to_dead_letter(&self, batch: &[GeoPacket], err: StoreError) -> bool {
+        if let Some(sink) = &self.dead_letter {
+            match sink.save_batch(batch) {
+                Ok(_) => return true,
+                Err(e) => error!("dead letter failed: {}", e),
+            }
+        }
+
+        for p in batch {
+            error!("lost packet ({}): {}", err, serde_json::to_string(p).unwrap_or_default());
+        }
+        false
+    }
+}
+
+#[test]
+fn test_pipeline_retry_and_dead_letter() {
+    use std::cell::Cell;
+    use std::sync::mpsc::{channel, Sender};
+    use crate::store::test_packet;
+
+    // fails with a transient error a few times, then rejects anything from imei "bad"
+    struct FlakyStore {
+        failures: Cell<u32>,
+        saved: Sender<usize>,
+    }
+
+    impl Store for FlakyStore {
+        fn save(&self, _: GeoPacket) -> Result<(), StoreError> {
+            unreachable!()
+        }
+
+        fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(StoreError::Transient(String::from("db is down")));
+            }
+            if batch.iter().any(|p| p.imei == "bad") {
+                return Err(StoreError::Permanent(String::from("bad imei")));
+            }
+            self.saved.send(batch.len()).unwrap();
+            Ok(())
+        }
+    }
+
+    struct ChannelStore(Sender<GeoPacket>);
+
+    impl Store for ChannelStore {
+        fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+            self.0.send(p).unwrap();
+            Ok(())
+        }
+    }
+
+    let (saved_sender, saved) = channel();
+    let (dead_sender, dead) = channel();
+    let config = PipelineConfig {
+        batch_size: 3,
+        batch_timeout: Duration::from_millis(50),
+        retry_delay: Duration::from_millis(1),
+        max_retry_delay: Duration::from_millis(4),
+        max_retries: 5,
+    };
+    let mut pipeline = Pipeline::new(FlakyStore { failures: Cell::new(4), saved: saved_sender }, config);
+    pipeline.set_dead_letter(ChannelStore(dead_sender));
+
+    let (bus, receiver) = crossbeam_channel::bounded(10);
+    for imei in &["1", "2", "3", "4", "bad"] {
+        bus.send(Delivery::new(test_packet(imei))).unwrap();
+    }
+    drop(bus);
+    pipeline.run(receiver);
+
+    // full batch survives the outage, of the rejected remainder only the bad packet is dead-lettered
+    assert_eq!(saved.try_iter().collect::<Vec<_>>(), vec![3, 1]);
+    assert_eq!(dead.try_iter().map(|p| p.imei).collect::<Vec<_>>(), vec!["bad"]);
+}
@@ -0,0 +1,184 @@
+use std::io::Write;
+use postgres::NoTls;
+use postgres::error::SqlState;
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager;
+
+use log::info;
+use crate::store::{GeoPacket, Store, StoreError};
+
+#[derive(Clone, Debug)]
+pub struct PostgresConfig {
+    /// Connection string, e.g. `host=localhost user=wialon dbname=tracking`.
+    pub url: String,
+    /// Table name, optionally with a schema: `tracking.positions`.
+    pub table: String,
+    pub pool_size: u32,
+    /// Create the PostGIS extension, the table and its index if they don't exist.
+    pub bootstrap: bool,
+}
+
+impl PostgresConfig {
+    pub fn new(url: &str) -> PostgresConfig {
+        PostgresConfig {
+            url: url.to_string(),
+            table: String::from("positions"),
+            pool_size: 4,
+            bootstrap: true,
+        }
+    }
+}
+
+/// Saves packets into PostgreSQL with PostGIS, every batch is loaded with a single COPY.
+pub struct PostgresStore {
+    pool: Pool<PostgresConnectionManager<NoTls>>,
+    copy: String,
+}
+
+impl PostgresStore {
+    pub fn new(config: &PostgresConfig) -> Result<PostgresStore, StoreError> {
+        if !is_identifier(&config.table) {
+            return Err(StoreError::Permanent(format!("invalid table name: {}", config.table)));
+        }
+
+        let pg_config = config.url.parse::<postgres::Config>()
+            .map_err(|e| StoreError::Permanent(e.to_string()))?;
+        let pool = Pool::builder()
+            .max_size(config.pool_size.max(1))
+            .build(PostgresConnectionManager::new(pg_config, NoTls))
+            .map_err(|e| StoreError::Transient(e.to_string()))?;
+
+        let store = PostgresStore {
+            pool,
+            copy: format!("COPY {} (imei, ts, geom, speed, course, height, sats, ptype, params) \
+                           FROM STDIN", config.table),
+        };
+        if config.bootstrap {
+            store.bootstrap(&config.table)?;
+        }
+        Ok(store)
+    }
+
+    fn bootstrap(&self, table: &str) -> Result<(), StoreError> {
+        let index = table.replace('.', "_");
+        let sql = format!("CREATE EXTENSION IF NOT EXISTS postgis;
+            CREATE TABLE IF NOT EXISTS {table} (
+                id bigserial PRIMARY KEY,
+                imei text NOT NULL,
+                ts timestamp NOT NULL,
+                geom geometry(Point, 4326) NOT NULL,
+                speed smallint NOT NULL,
+                course smallint NOT NULL,
+                height smallint NOT NULL,
+                sats smallint NOT NULL,
+                ptype text NOT NULL,
+                params jsonb NOT NULL DEFAULT '{{}}'
+            );
+            CREATE INDEX IF NOT EXISTS {index}_imei_ts_idx ON {table} (imei, ts);", table = table, index = index);
+
+        let mut client = self.pool.get().map_err(|e| StoreError::Transient(e.to_string()))?;
+        client.batch_execute(&sql).map_err(pg_error)?;
+        info!("Table {} is ready", table);
+        Ok(())
+    }
+}
+
+impl Store for PostgresStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let mut data = Vec::new();
+        for p in batch {
+            copy_row(&mut data, p)?;
+        }
+
+        let mut client = self.pool.get().map_err(|e| StoreError::Transient(e.to_string()))?;
+        let mut writer = client.copy_in(self.copy.as_str()).map_err(pg_error)?;
+        writer.write_all(&data).map_err(|e| StoreError::Transient(e.to_string()))?;
+        writer.finish().map_err(pg_error)?;
+        Ok(())
+    }
+}
+
+// One line of COPY text format, the geometry goes in as EWKT.
+fn copy_row(data: &mut Vec<u8>, p: &GeoPacket) -> Result<(), StoreError> {
+    let params = serde_json::to_string(&p.params).map_err(|e| StoreError::Permanent(e.to_string()))?;
+    let fields = [
+        copy_escape(&p.imei),
+        p.timestamp.format("%Y-%m-%d %H:%M:%S").to_string(),
+        format!("SRID=4326;POINT({} {})", p.lon, p.lat),
+        p.speed.to_string(),
+        p.course.to_string(),
+        p.height.to_string(),
+        p.sats.to_string(),
+        copy_escape(&p.ptype),
+        copy_escape(&params),
+    ];
+    data.extend_from_slice(fields.join("\t").as_bytes());
+    data.push(b'\n');
+    Ok(())
+}
+
+fn copy_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n").replace('\r', "\\r")
+}
+
+// Lost connections, deadlocks and an overloaded server are worth a retry, anything else is not.
+fn pg_error(err: postgres::Error) -> StoreError {
+    let transient = match err.code() {
+        Some(code) => is_transient(code),
+        None => true,
+    };
+    if transient {
+        StoreError::Transient(err.to_string())
+    } else {
+        StoreError::Permanent(err.to_string())
+    }
+}
+
+fn is_transient(code: &SqlState) -> bool {
+    ["08", "40", "53", "57"].iter().any(|class| code.code().starts_with(class))
+}
+
+// Table names go straight into SQL, so only plain identifiers are accepted.
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.split('.').all(|part| {
+        !part.is_empty()
+            && !part.starts_with(|c: char| c.is_ascii_digit())
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+// Needs a PostgreSQL with PostGIS, run with
+// `WIALON_TEST_POSTGRES="host=localhost user=postgres" cargo test --features postgres -- --ignored`
+#[test]
+#[ignore]
+fn test_postgres_store() {
+    use crate::store::Param;
+    use crate::store::test_packet;
+
+    let url = std::env::var("WIALON_TEST_POSTGRES").unwrap_or_else(|_| String::from("host=localhost user=postgres"));
+    let mut config = PostgresConfig::new(&url);
+    config.table = String::from("wialon_test_positions");
+    let store = PostgresStore::new(&config).unwrap();
+
+    let mut p = test_packet("pg-test");
+    p.params.insert(String::from("text"), Param::String(String::from("tab\there")));
+    p.params.insert(String::from("var"), Param::Float(4.5));
+    store.save_batch(&[p.clone(), p]).unwrap();
+
+    let mut client = store.pool.get().unwrap();
+    let row = client.query_one("SELECT count(*), max(ST_SRID(geom)), max(params ->> 'text'), max(ST_X(geom)), max(ST_Y(geom)) \
+                                FROM wialon_test_positions WHERE imei = 'pg-test'", &[]).unwrap();
+    let count: i64 = row.get(0);
+    let srid: i32 = row.get(1);
+    let text: String = row.get(2);
+    let (lon, lat): (f64, f64) = (row.get(3), row.get(4));
+    assert!(count >= 2);
+    assert_eq!((srid, text.as_str()), (4326, "tab\there"));
+    // 53.918°N 27.540°E, x is the longitude
+    assert!((lon - 27.540165).abs() < 1e-6 && (lat - 53.91821).abs() < 1e-6);
+    client.execute("DELETE FROM wialon_test_positions WHERE imei = 'pg-test'", &[]).unwrap();
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use serde::Deserialize;
+
+use log::{warn, error};
+use crate::ack::Delivery;
+
+/// What a connection does with a packet when the store queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Wait for a free slot. Stalls every connection of the worker thread meanwhile.
+    #[default]
+    Block,
+    /// Drop the packet and answer with an error code, the device keeps it and sends it again.
+    Reject,
+    /// Keep the packet in the connection and stop reading from it until the queue has room.
+    Pause,
+}
+
+/// Result of handing a packet to the queue.
+pub(crate) enum Offer {
+    Queued,
+    Rejected,
+    /// Queue is full, the delivery is given back to retry later.
+    Full(Delivery),
+    Closed,
+}
+
+#[derive(Default)]
+struct Counters {
+    blocked: AtomicU64,
+    rejected: AtomicU64,
+    paused: AtomicUsize,
+}
+
+/// Bounded queue of packets between connections and the store.
+pub(crate) fn channel(capacity: usize) -> (Bus, Receiver<Delivery>, QueueMetrics) {
+    let (sender, receiver) = bounded(capacity);
+    let counters = Arc::new(Counters::default());
+    let metrics = QueueMetrics { queue: receiver.clone(), counters: counters.clone() };
+    (Bus { sender, policy: OverflowPolicy::default(), counters }, receiver, metrics)
+}
+
+/// Sending side of the queue, shared by all connections.
+#[derive(Clone)]
+pub(crate) struct Bus {
+    sender: Sender<Delivery>,
+    policy: OverflowPolicy,
+    counters: Arc<Counters>,
+}
+
+impl Bus {
+    pub fn set_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn offer(&self, delivery: Delivery) -> Offer {
+        let delivery = match self.sender.try_send(delivery) {
+            Ok(_) => return Offer::Queued,
+            Err(TrySendError::Disconnected(_)) => return Offer::Closed,
+            Err(TrySendError::Full(d)) => d,
+        };
+
+        match self.policy {
+            OverflowPolicy::Block => {
+                self.counters.blocked.fetch_add(1, Ordering::Relaxed);
+                warn!("store queue is full, waiting");
+                match self.sender.send(delivery) {
+                    Ok(_) => Offer::Queued,
+                    Err(_) => Offer::Closed,
+                }
+            }
+            OverflowPolicy::Reject => {
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                error!("store queue is full, packet from {} rejected", delivery.packet.imei);
+                Offer::Rejected
+            }
+            OverflowPolicy::Pause => Offer::Full(delivery),
+        }
+    }
+
+    /// Connection stopped (`true`) or resumed (`false`) reading because of a full queue.
+    pub fn set_paused(&self, paused: bool) {
+        if paused {
+            self.counters.paused.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.counters.paused.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Live view of the store queue.
+#[derive(Clone)]
+pub struct QueueMetrics {
+    // only used to look at the length, never received from
+    queue: Receiver<Delivery>,
+    counters: Arc<Counters>,
+}
+
+impl QueueMetrics {
+    /// Packets waiting for the store.
+    pub fn depth(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.queue.capacity().unwrap_or(0)
+    }
+
+    /// How full the queue is, from 0 to 1.
+    pub fn fill_ratio(&self) -> f64 {
+        match self.capacity() {
+            0 => 0.0,
+            c => self.depth() as f64 / c as f64,
+        }
+    }
+
+    /// Times a connection had to wait for room with `OverflowPolicy::Block`.
+    pub fn blocked(&self) -> u64 {
+        self.counters.blocked.load(Ordering::Relaxed)
+    }
+
+    /// Packets dropped with `OverflowPolicy::Reject`.
+    pub fn rejected(&self) -> u64 {
+        self.counters.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Connections currently not read because of `OverflowPolicy::Pause`.
+    pub fn paused(&self) -> usize {
+        self.counters.paused.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,232 @@
+use std::sync::Mutex;
+use std::time::Duration;
+use redis::{Client, Connection, ErrorKind, RedisError, Script};
+
+use log::{debug, warn};
+use crate::store::{GeoPacket, Store, StoreError};
+
+// KEYS: hash of the device, geo index
+// ARGV: imei, timestamp, add to the index (1/0), lon, lat, channel, message, field/value pairs of the hash
+const UPDATE_SCRIPT: &str = r"
+local last = redis.call('HGET', KEYS[1], 'timestamp')
+if last and tonumber(last) > tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('DEL', KEYS[1])
+redis.call('HSET', KEYS[1], unpack(ARGV, 8))
+if ARGV[3] == '1' then
+    redis.call('GEOADD', KEYS[2], ARGV[4], ARGV[5], ARGV[1])
+end
+redis.call('PUBLISH', ARGV[6], ARGV[7])
+return 1
+";
+
+// Coordinates GEOADD accepts.
+const MAX_LAT: f64 = 85.05112878;
+
+#[derive(Clone, Debug)]
+pub struct RedisConfig {
+    /// e.g. `redis://127.0.0.1/` or `redis://:password@host:6379/2`.
+    pub url: String,
+    /// The hash of a device is `{prefix}:last:{imei}`, the geo index `{prefix}:geo`.
+    pub prefix: String,
+    /// Every update is published here as JSON.
+    pub channel: String,
+    pub timeout: Duration,
+}
+
+impl RedisConfig {
+    pub fn new(url: &str) -> RedisConfig {
+        RedisConfig {
+            url: url.to_string(),
+            prefix: String::from("wialon"),
+            channel: String::from("wialon:positions"),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Keeps the last known position of every device in Redis.
+///
+/// A device's hash holds the fields of its latest packet (`timestamp` in unix seconds, params as
+/// `param:<name>`), packets older than the stored one are skipped. Updates also go to a geo index for
+/// `GEOSEARCH` by radius and are published on a channel. Every update runs as one script, so concurrent
+/// writers can't put an older position over a newer one.
+pub struct RedisStore {
+    client: Client,
+    connection: Mutex<Option<Connection>>,
+    script: Script,
+    config: RedisConfig,
+}
+
+impl RedisStore {
+    pub fn new(config: &RedisConfig) -> Result<RedisStore, StoreError> {
+        let client = Client::open(config.url.as_str()).map_err(|e| StoreError::Permanent(e.to_string()))?;
+        Ok(RedisStore {
+            client,
+            connection: Mutex::new(None),
+            script: Script::new(UPDATE_SCRIPT),
+            config: config.clone(),
+        })
+    }
+
+    fn connect(&self) -> Result<Connection, RedisError> {
+        let mut connection = self.client.get_connection_with_timeout(self.config.timeout)?;
+        connection.set_read_timeout(Some(self.config.timeout))?;
+        connection.set_write_timeout(Some(self.config.timeout))?;
+        self.script.prepare_invoke().load(&mut connection)?;
+        Ok(connection)
+    }
+
+    fn update(&self, connection: &mut Connection, batch: &[GeoPacket], messages: &[String]) -> Result<usize, RedisError> {
+        let geo_key = format!("{}:geo", self.config.prefix);
+        let mut pipe = redis::pipe();
+        for (p, message) in batch.iter().zip(messages) {
+            let geo = p.lon.abs() <= 180.0 && p.lat.abs() <= MAX_LAT;
+
+            let cmd = pipe.cmd("EVALSHA").arg(self.script.get_hash()).arg(2)
+                .arg(format!("{}:last:{}", self.config.prefix, p.imei)).arg(&geo_key)
+                .arg(&p.imei).arg(p.timestamp.and_utc().timestamp()).arg(if geo { 1 } else { 0 }).arg(p.lon).arg(p.lat)
+                .arg(&self.config.channel).arg(message)
+                .arg("imei").arg(&p.imei)
+                .arg("timestamp").arg(p.timestamp.and_utc().timestamp())
+                .arg("lat").arg(p.lat)
+                .arg("lon").arg(p.lon)
+                .arg("speed").arg(p.speed)
+                .arg("course").arg(p.course)
+                .arg("height").arg(p.height)
+                .arg("sats").arg(p.sats)
+                .arg("ptype").arg(&p.ptype);
+            for (name, value) in &p.params {
+                cmd.arg(format!("param:{}", name)).arg(value.to_string());
+            }
+        }
+
+        let updated: Vec<i64> = pipe.query(connection)?;
+        Ok(updated.iter().filter(|&&u| u == 1).count())
+    }
+}
+
+impl Store for RedisStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let messages = batch.iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| StoreError::Permanent(e.to_string()))?;
+
+        let mut guard = self.connection.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(self.connect().map_err(redis_error)?);
+        }
+        let connection = guard.as_mut().unwrap();
+
+        let result = match self.update(connection, batch, &messages) {
+            // the script cache is gone after a restart or SCRIPT FLUSH
+            Err(err) if err.kind() == ErrorKind::NoScriptError => {
+                warn!("Redis lost the update script, loading it again");
+                self.script.prepare_invoke().load(connection).and_then(|_| self.update(connection, batch, &messages))
+            }
+            result => result,
+        };
+
+        match result {
+            Ok(updated) => {
+                debug!("{} of {} positions updated in Redis", updated, batch.len());
+                Ok(())
+            }
+            Err(err) => {
+                if err.is_io_error() {
+                    *guard = None;
+                }
+                Err(redis_error(err))
+            }
+        }
+    }
+}
+
+// Connection problems and a server which is busy or failing over may pass, anything else won't.
+fn redis_error(err: RedisError) -> StoreError {
+    let transient = err.is_io_error() || matches!(err.kind(),
+        ErrorKind::BusyLoadingError | ErrorKind::TryAgain | ErrorKind::ClusterDown
+        | ErrorKind::MasterDown | ErrorKind::ReadOnly | ErrorKind::NoScriptError);
+
+    if transient {
+        StoreError::Transient(err.to_string())
+    } else {
+        StoreError::Permanent(err.to_string())
+    }
+}
+
+// Needs a redis-server, run with
+// `WIALON_TEST_REDIS=redis://127.0.0.1/ cargo test --features redis -- --ignored`
+#[test]
+#[ignore]
+fn test_redis_store_last_position() {
+    use std::collections::HashMap;
+    use crate::store::Param;
+    use crate::store::test_packet;
+
+    let url = std::env::var("WIALON_TEST_REDIS").unwrap_or_else(|_| String::from("redis://127.0.0.1/"));
+    let mut config = RedisConfig::new(&url);
+    config.prefix = String::from("wialon-test");
+    config.channel = String::from("wialon-test:positions");
+    let store = RedisStore::new(&config).unwrap();
+
+    let client = Client::open(url.as_str()).unwrap();
+    let mut subscriber = client.get_connection().unwrap();
+    let mut pubsub = subscriber.as_pubsub();
+    pubsub.subscribe(&config.channel).unwrap();
+    let mut con = client.get_connection().unwrap();
+    redis::cmd("DEL").arg("wialon-test:last:1").arg("wialon-test:geo").query::<()>(&mut con).unwrap();
+
+    let mut p = GeoPacket { speed: 10, ..test_packet("1") };
+    p.params.insert(String::from("fuel"), Param::Int(40));
+    let older = GeoPacket { speed: 20, timestamp: p.timestamp - chrono::Duration::seconds(140), ..test_packet("1") };
+    store.save_batch(&[p.clone(), older]).unwrap();
+
+    let last: HashMap<String, String> = redis::cmd("HGETALL").arg("wialon-test:last:1").query(&mut con).unwrap();
+    assert_eq!(last["speed"], "10");
+    assert_eq!(last["timestamp"], "1619589140");
+    assert_eq!(last["param:fuel"], "40");
+
+    // the device is at 53.918°N 27.540°E
+    let search = |lon: f64, lat: f64| -> Vec<String> {
+        redis::cmd("GEOSEARCH").arg("wialon-test:geo")
+            .arg("FROMLONLAT").arg(lon).arg(lat).arg("BYRADIUS").arg(1).arg("km")
+            .query(&mut client.get_connection().unwrap()).unwrap()
+    };
+    assert_eq!(search(27.5402, 53.9182), vec!["1"]);
+    assert!(search(53.5509, 27.3241).is_empty());
+
+    // only the applied update is published
+    let message: String = pubsub.get_message().unwrap().get_payload().unwrap();
+    assert_eq!(serde_json::from_str::<GeoPacket>(&message).unwrap(), p);
+    pubsub.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+    assert!(pubsub.get_message().is_err());
+}
+
+#[test]
+fn test_redis_store_errors() {
+    use std::io;
+    use std::net::TcpListener;
+    use crate::store::test_packet;
+
+    let transient = |err: RedisError| matches!(redis_error(err), StoreError::Transient(_));
+    assert!(transient(RedisError::from(io::Error::from(io::ErrorKind::ConnectionReset))));
+    assert!(transient(RedisError::from((ErrorKind::BusyLoadingError, "loading the dataset"))));
+    assert!(transient(RedisError::from((ErrorKind::ReadOnly, "replica"))));
+    assert!(!transient(RedisError::from((ErrorKind::TypeError, "WRONGTYPE"))));
+    assert!(!transient(RedisError::from((ErrorKind::AuthenticationFailed, "invalid password"))));
+
+    assert!(matches!(RedisStore::new(&RedisConfig::new("http://localhost/")), Err(StoreError::Permanent(_))));
+
+    // an unreachable server is tried again, with a new connection
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let store = RedisStore::new(&RedisConfig::new(&format!("redis://{}/", closed))).unwrap();
+    assert!(matches!(store.save(test_packet("1")), Err(StoreError::Transient(_))));
+    assert!(store.connection.lock().unwrap().is_none());
+}
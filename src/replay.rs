@@ -0,0 +1,139 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+
+/// How a capture is played back, see `replay`.
+#[derive(Clone, Debug)]
+pub struct ReplayConfig {
+    /// 2.0 plays the capture twice as fast as it was recorded.
+    pub speed: f64,
+    /// Longest pause between two packets, so gaps in the capture don't stall the replay.
+    pub max_gap: Duration,
+    /// How long to wait for the server's answer to a line.
+    pub timeout: Duration,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> ReplayConfig {
+        ReplayConfig {
+            speed: 1.0,
+            max_gap: Duration::from_secs(10),
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayReport {
+    pub sent: usize,
+    pub answered: usize,
+}
+
+/// Sends the lines of a capture (one packet per line, like `test/test_packet.txt`) to the server
+/// at `addr` over one connection and waits for the answer to each of them. Between data packets
+/// it sleeps as long as the device timestamps of the packets lie apart. `on_answer` gets every
+/// line sent together with the server's answer, `None` if the server didn't answer in time.
+pub fn replay<R, F>(addr: &str, capture: R, config: &ReplayConfig, mut on_answer: F) -> io::Result<ReplayReport>
+    where R: BufRead, F: FnMut(&str, Option<&str>)
+{
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(config.timeout))?;
+    let mut answers = BufReader::new(stream.try_clone()?);
+
+    let mut report = ReplayReport::default();
+    let mut last_time: Option<NaiveDateTime> = None;
+    for line in capture.lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(time) = packet_time(line) {
+            if let Some(last) = last_time {
+                if let Ok(gap) = (time - last).to_std() {
+                    thread::sleep(gap.div_f64(config.speed).min(config.max_gap));
+                }
+            }
+            last_time = Some(time);
+        }
+
+        stream.write_all(format!("{}\r\n", line).as_bytes())?;
+        report.sent += 1;
+
+        let mut answer = String::new();
+        match answers.read_line(&mut answer) {
+            Ok(0) => {
+                on_answer(line, None);
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"));
+            }
+            Ok(_) => {
+                report.answered += 1;
+                on_answer(line, Some(answer.trim_end()));
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => {
+                on_answer(line, None)
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(report)
+}
+
+// Device time of SD and D packets, the first two fields of their body.
+fn packet_time(line: &str) -> Option<NaiveDateTime> {
+    let body = line.strip_prefix("#SD#").or_else(|| line.strip_prefix("#D#"))?;
+    let mut fields = body.split(';');
+    let date = fields.next()?;
+    let time = fields.next()?;
+    NaiveDateTime::parse_from_str(&format!("{}{}", date, time), "%d%m%y%H%M%S").ok()
+}
+
+#[test]
+fn test_replay_capture() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (socket, _) = listener.accept().unwrap();
+        let mut writer = socket.try_clone().unwrap();
+        let mut received = Vec::new();
+        for line in BufReader::new(socket).lines() {
+            let line = line.unwrap();
+            // garbage stays unanswered
+            if line.starts_with("#SD#") {
+                writer.write_all(b"#ASD#1\r\n").unwrap();
+            } else if line.starts_with("#L#") {
+                writer.write_all(b"#AL#1\r\n").unwrap();
+            }
+            received.push(line);
+        }
+        received
+    });
+
+    let capture = "#L#1;1\n\
+                   #SD#280421;055220;5355.09260;N;02732.40990;E;0;0;300;7\n\
+                   \n\
+                   #SD#280421;055222;5355.09260;N;02732.40990;E;0;0;300;7\n\
+                   wewe\n";
+    let config = ReplayConfig { speed: 4.0, timeout: Duration::from_millis(200), ..ReplayConfig::default() };
+    let started = std::time::Instant::now();
+    let mut answers = Vec::new();
+    let report = replay(&addr, capture.as_bytes(), &config, |line, answer| {
+        answers.push((line.to_string(), answer.map(String::from)))
+    }).unwrap();
+
+    // two seconds between the positions at four times the speed
+    assert!(started.elapsed() >= Duration::from_millis(500));
+    assert_eq!(report, ReplayReport { sent: 4, answered: 3 });
+    assert_eq!(answers[0].1.as_deref(), Some("#AL#1"));
+    assert_eq!(answers[2].1.as_deref(), Some("#ASD#1"));
+    assert_eq!(answers[3], (String::from("wewe"), None));
+
+    let received = server.join().unwrap();
+    assert_eq!(received.len(), 4);
+}
@@ -0,0 +1,480 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{bounded, Receiver, Sender};
+
+use log::{info, warn, error};
+use crate::client::{Client, ClientConfig, ClientError, ProtocolVersion};
+use crate::composite_store::Route;
+use crate::store::{GeoPacket, Store, StoreError};
+use crate::wal::{self, WalConfig, WalReader, WalWriter};
+
+// Records taken from the queue at once and how long the forwarder sleeps when it is empty.
+const READ_BATCH: usize = 100;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// IPS server positions are forwarded to.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub addr: String,
+    pub password: String,
+    pub version: ProtocolVersion,
+}
+
+impl Upstream {
+    pub fn new(addr: &str) -> Upstream {
+        Upstream {
+            addr: addr.to_string(),
+            password: String::from("NA"),
+            version: ProtocolVersion::V1_1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetranslatorConfig {
+    /// A position goes to the upstream of the first matching route, without any match it isn't forwarded.
+    pub rules: Vec<(Route, Upstream)>,
+    /// Positions waiting to be forwarded, kept across outages and restarts.
+    pub queue: WalConfig,
+    /// Longest wait for connecting to an upstream and for its answer.
+    pub timeout: Duration,
+    pub reconnect_delay: Duration,
+    pub max_reconnect_delay: Duration,
+    /// Positions of a device held in memory while its upstream is unreachable, later ones wait in the queue.
+    pub black_box_size: usize,
+    /// Positions per `#B#` packet after reconnecting.
+    pub batch_size: usize,
+    /// Sessions of devices which sent nothing for this long are closed.
+    pub idle_timeout: Duration,
+}
+
+impl RetranslatorConfig {
+    pub fn new(dir: &str) -> RetranslatorConfig {
+        let client = ClientConfig::new("", "");
+        RetranslatorConfig {
+            rules: Vec::new(),
+            queue: WalConfig::new(dir),
+            timeout: client.timeout,
+            reconnect_delay: client.reconnect_delay,
+            max_reconnect_delay: client.max_reconnect_delay,
+            black_box_size: client.black_box_size,
+            batch_size: client.batch_size,
+            idle_timeout: Duration::from_secs(300),
+        }
+    }
+
+    fn upstream(&self, p: &GeoPacket) -> Option<&Upstream> {
+        self.rules.iter().find(|(route, _)| route.matches(p)).map(|(_, upstream)| upstream)
+    }
+}
+
+/// Forwards positions to other IPS servers, e.g. a customer's Wialon Hosting account.
+///
+/// Saving appends the positions matching a rule to a write-ahead log, a forwarder thread reads it and sends
+/// every device over its own session per upstream, logged in with the device's IMEI. While an upstream is
+/// down the positions of its devices wait in the black box of their session and go out as `#B#` packets
+/// after reconnecting. Once a black box is full the device's next positions are left in the log and read
+/// again when there is room. The log is only trimmed up to the oldest position not yet confirmed, so whatever
+/// was undelivered at shutdown is sent after a restart, some positions possibly twice.
+///
+/// All sessions share the forwarder thread and talk to their upstreams one after another. An unresponsive
+/// upstream holds up the others for up to `timeout` per session it has, once per reconnect delay: with 100
+/// devices going to it and a `timeout` of 10 s that is a stall of 1000 s.
+pub struct RetranslatorStore {
+    config: Arc<RetranslatorConfig>,
+    writer: Arc<Mutex<WalWriter>>,
+    wake: Sender<()>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl RetranslatorStore {
+    pub fn new(config: RetranslatorConfig) -> io::Result<RetranslatorStore> {
+        let (writer, reader) = wal::open(&config.queue)?;
+        let config = Arc::new(config);
+        let writer = Arc::new(Mutex::new(writer));
+        let (wake, woken) = bounded(1);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let forwarder = Forwarder {
+            config: config.clone(),
+            reader,
+            writer: writer.clone(),
+            sessions: HashMap::new(),
+            last_read: 0,
+            committed: 0,
+            rewound_to: None,
+        };
+        let thread = {
+            let stop = stop.clone();
+            thread::Builder::new()
+                .name(String::from("wialon-retranslator"))
+                .spawn(move || forwarder.run(woken, &stop))?
+        };
+        Ok(RetranslatorStore { config, writer, wake, stop, thread: Some(thread) })
+    }
+}
+
+impl Store for RetranslatorStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let mut writer = self.writer.lock().unwrap();
+        let mut queued = false;
+        for p in batch.iter().filter(|p| self.config.upstream(p).is_some()) {
+            writer.append(p).map_err(|e| StoreError::Transient(format!("retranslator queue: {}", e)))?;
+            queued = true;
+        }
+        if queued {
+            writer.commit().map_err(|e| StoreError::Transient(format!("retranslator queue: {}", e)))?;
+            let _ = self.wake.try_send(());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RetranslatorStore {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.wake.try_send(());
+        if let Some(t) = self.thread.take() {
+            if t.join().is_err() {
+                error!("retranslator panicked");
+            }
+        }
+        if let Err(err) = self.writer.lock().unwrap().sync() {
+            error!("retranslator queue: {}", err);
+        }
+        info!("Retranslator stopped");
+    }
+}
+
+struct Session {
+    client: Client,
+    // queue positions handed to the client and not confirmed by the upstream yet, oldest first
+    pending: VecDeque<u64>,
+    // newest position handed to the client, older ones read again after a rewind are skipped
+    handled: u64,
+    // the black box was full when a position came, later ones are skipped until the next rewind
+    skipping: bool,
+    // oldest position left in the queue for a rewind
+    unhandled_from: Option<u64>,
+    last_used: Instant,
+}
+
+impl Session {
+    // Nothing leaves the black box before the upstream confirmed it: it never overflows (see `is_full`)
+    // and batches the upstream took nothing of are kept (`max_retries` is `None`). Only a position the
+    // upstream finds wrong itself, e.g. `#ASD#0` for its time, is dropped as it would be refused again.
+    fn settle(&mut self) {
+        while self.pending.len() > self.client.buffered() {
+            self.pending.pop_front();
+        }
+    }
+
+    fn is_full(&self, black_box_size: usize) -> bool {
+        self.client.buffered() >= black_box_size.max(1)
+    }
+}
+
+struct Forwarder {
+    config: Arc<RetranslatorConfig>,
+    reader: WalReader,
+    writer: Arc<Mutex<WalWriter>>,
+    // one per device and upstream, a device may go to several upstreams by packet type
+    sessions: HashMap<(String, String), Session>,
+    last_read: u64,
+    committed: u64,
+    // newest position read before the current rewind
+    rewound_to: Option<u64>,
+}
+
+impl Forwarder {
+    fn run(mut self, woken: Receiver<()>, stop: &AtomicBool) {
+        while !stop.load(Ordering::SeqCst) {
+            match self.reader.read(READ_BATCH) {
+                Ok(records) if !records.is_empty() => {
+                    for (seq, p) in records {
+                        self.forward(seq, &p);
+                    }
+                }
+                Ok(_) => {
+                    self.rewound();
+                    let _ = woken.recv_timeout(POLL_INTERVAL);
+                }
+                Err(err) => {
+                    error!("retranslator queue: {}", err);
+                    let _ = woken.recv_timeout(POLL_INTERVAL);
+                }
+            }
+            self.retry();
+            self.checkpoint();
+        }
+    }
+
+    fn forward(&mut self, seq: u64, p: &GeoPacket) {
+        self.last_read = self.last_read.max(seq);
+        if self.rewound_to.is_some_and(|to| seq >= to) {
+            self.rewound();
+        }
+        // rules may have changed since the position was queued
+        let upstream = match self.config.upstream(p) {
+            Some(u) => u,
+            None => return,
+        };
+
+        let config = &self.config;
+        let session = self.sessions.entry((p.imei.clone(), upstream.addr.clone())).or_insert_with(|| {
+            let mut client = ClientConfig::new(&upstream.addr, &p.imei);
+            client.password = upstream.password.clone();
+            client.version = upstream.version;
+            client.timeout = config.timeout;
+            client.reconnect_delay = config.reconnect_delay;
+            client.max_reconnect_delay = config.max_reconnect_delay;
+            client.black_box_size = config.black_box_size;
+            client.batch_size = config.batch_size;
+            client.max_retries = None;
+            Session {
+                client: Client::new(client),
+                pending: VecDeque::new(),
+                handled: 0,
+                skipping: false,
+                unhandled_from: None,
+                last_used: Instant::now(),
+            }
+        });
+
+        session.last_used = Instant::now();
+        if seq <= session.handled {
+            return;
+        }
+        // the position stays in the queue instead of pushing an older one out of the black box
+        if session.skipping || session.is_full(config.black_box_size) {
+            if !session.skipping {
+                session.skipping = true;
+                session.unhandled_from = Some(seq);
+            }
+            return;
+        }
+
+        session.handled = seq;
+        session.pending.push_back(seq);
+        if let Err(err) = session.client.send(p) {
+            log_error(&p.imei, &err);
+        }
+        session.settle();
+    }
+
+    // Resends black boxes of reachable upstreams, rewinds the queue for sessions with room again
+    // and closes idle sessions.
+    fn retry(&mut self) {
+        for ((imei, _), session) in &mut self.sessions {
+            if session.pending.is_empty() {
+                continue;
+            }
+            if let Err(err) = session.client.flush() {
+                log_error(imei, &err);
+            }
+            session.settle();
+        }
+
+        let black_box_size = self.config.black_box_size;
+        let ready = self.sessions.values().any(|s| s.skipping && !s.is_full(black_box_size));
+        if ready && self.rewound_to.is_none() {
+            for session in self.sessions.values_mut().filter(|s| !s.is_full(black_box_size)) {
+                session.skipping = false;
+            }
+            self.rewound_to = Some(self.last_read);
+            self.reader.rewind();
+        }
+
+        let (idle_timeout, committed) = (self.config.idle_timeout, self.committed);
+        self.sessions.retain(|_, s| {
+            !s.pending.is_empty() || s.unhandled_from.is_some() || s.handled > committed
+                || s.last_used.elapsed() < idle_timeout
+        });
+    }
+
+    // Positions read again after a rewind were either handed over now or are left for the next one.
+    fn rewound(&mut self) {
+        if self.rewound_to.take().is_some() {
+            for session in self.sessions.values_mut().filter(|s| !s.skipping) {
+                session.unhandled_from = None;
+            }
+        }
+    }
+
+    // Trims the queue up to the oldest position not confirmed yet and syncs it when the fsync interval is due.
+    fn checkpoint(&mut self) {
+        let oldest = self.sessions.values()
+            .flat_map(|s| s.pending.front().copied().into_iter().chain(s.unhandled_from))
+            .min();
+        let confirmed = match oldest {
+            Some(oldest) => oldest - 1,
+            None => self.last_read,
+        };
+        match self.reader.commit(confirmed) {
+            Ok(()) => self.committed = self.committed.max(confirmed),
+            Err(err) => error!("retranslator queue: {}", err),
+        }
+
+        let mut writer = self.writer.lock().unwrap();
+        if writer.sync_due_in() == Some(Duration::ZERO) {
+            if let Err(err) = writer.sync() {
+                error!("retranslator queue: {}", err);
+            }
+        }
+    }
+}
+
+fn log_error(imei: &str, err: &ClientError) {
+    match err {
+        ClientError::Rejected(_) => warn!("{}: upstream {}, position dropped", imei, err),
+        _ => error!("{}: upstream {}", imei, err),
+    }
+}
+
+#[test]
+fn test_retranslator_store() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use crate::store::Param;
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    // positions are told apart by their speed
+    let packet = |imei: &str, speed: i16| GeoPacket { speed, ..test_packet(imei) };
+    let config = |addr: &str| {
+        let mut config = RetranslatorConfig::new(dir.path().to_str().unwrap());
+        let route = Route { imei: vec![String::from("8612*")], ..Route::all() };
+        config.rules.push((route, Upstream::new(addr)));
+        config.timeout = Duration::from_secs(1);
+        config.reconnect_delay = Duration::from_millis(50);
+        config.max_reconnect_delay = Duration::from_millis(100);
+        config.black_box_size = 2;
+        config
+    };
+
+    // nobody listens on the upstream port yet, the outage outlasts the black box
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    drop(listener);
+    let store = RetranslatorStore::new(config(&addr)).unwrap();
+    for speed in 0..5 {
+        store.save_batch(&[packet("861230043907626", speed), packet("999", speed)]).unwrap();
+    }
+    thread::sleep(Duration::from_millis(300));
+
+    let listener = TcpListener::bind(&addr).unwrap();
+    let (received, lines) = channel();
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let socket = socket.unwrap();
+            let mut writer = socket.try_clone().unwrap();
+            for line in BufReader::new(socket).lines() {
+                let line = line.unwrap();
+                let (ptype, body) = line[1..].split_once('#').unwrap();
+                let answer = match ptype {
+                    "B" => format!("#AB#{}\r\n", body.matches('|').count()),
+                    _ => format!("#A{}#1\r\n", ptype),
+                };
+                writer.write_all(answer.as_bytes()).unwrap();
+                received.send(line).unwrap();
+            }
+        }
+    });
+
+    // the black box goes first, the positions left in the queue follow in order
+    let next = || lines.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(next(), "#L#861230043907626;NA");
+    let black_box = next();
+    assert!(black_box.starts_with("#B#") && black_box.matches('|').count() == 2);
+    assert!(black_box.contains(";E;0;") && black_box.contains(";E;1;"));
+    for speed in 2..5 {
+        assert!(next().starts_with(&format!("#SD#280421;055220;5355.09260;N;02732.40990;E;{};", speed)));
+    }
+
+    let mut d = packet("861230043907626", 5);
+    d.params.insert(String::from("fuel"), Param::Float(12.5));
+    store.save(d).unwrap();
+    assert!(next().ends_with(";NA;NA;NA;;NA;fuel:2:12.5"));
+    thread::sleep(Duration::from_millis(300));
+    drop(store);
+
+    // nothing is sent twice
+    let store = RetranslatorStore::new(config(&addr)).unwrap();
+    assert!(lines.recv_timeout(Duration::from_millis(300)).is_err());
+    drop(store);
+}
+
+#[test]
+fn test_retranslator_keeps_refused_positions() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use crate::store::test_packet;
+    use crate::wialon::ResponsePacket;
+
+    let dir = tempfile::tempdir().unwrap();
+    let packet = |speed: i16| GeoPacket { speed, ..test_packet("861230043907626") };
+    // asks to send every position again later when `busy`
+    let upstream = |busy: bool| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (received, lines) = channel();
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let socket = socket.unwrap();
+                let mut writer = socket.try_clone().unwrap();
+                for line in BufReader::new(socket).lines() {
+                    let line = line.unwrap();
+                    let (ptype, body) = line[1..].split_once('#').unwrap();
+                    let answer = match ptype {
+                        "SD" | "B" if busy => ResponsePacket::retry(&format!("A{}", ptype)).to_string(),
+                        "B" => format!("#AB#{}\r\n", body.matches('|').count()),
+                        _ => format!("#A{}#1\r\n", ptype),
+                    };
+                    writer.write_all(answer.as_bytes()).unwrap();
+                    if received.send(line).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (addr, lines)
+    };
+    let config = |addr: &str| {
+        let mut config = RetranslatorConfig::new(dir.path().to_str().unwrap());
+        config.rules.push((Route::all(), Upstream::new(addr)));
+        config.timeout = Duration::from_secs(1);
+        config.reconnect_delay = Duration::from_millis(10);
+        config.max_reconnect_delay = Duration::from_millis(20);
+        config
+    };
+
+    // the positions wait in the black box while the upstream refuses them over and over
+    let (addr, lines) = upstream(true);
+    let store = RetranslatorStore::new(config(&addr)).unwrap();
+    store.save_batch(&[packet(0), packet(1)]).unwrap();
+    let next = || lines.recv_timeout(Duration::from_secs(5)).unwrap();
+    while next().matches('|').count() < 2 {}
+    // more often than a client gives up by default
+    for _ in 0..ClientConfig::new("", "").max_retries.unwrap() + 1 {
+        assert!(next().starts_with("#B#"));
+    }
+    drop(store);
+
+    // nothing was confirmed, both are sent after a restart
+    let (addr, lines) = upstream(false);
+    let store = RetranslatorStore::new(config(&addr)).unwrap();
+    let sent: Vec<String> = (0..3).map(|_| lines.recv_timeout(Duration::from_secs(5)).unwrap()).collect();
+    assert!(sent.iter().any(|l| l.contains(";E;0;")) && sent.iter().any(|l| l.contains(";E;1;")));
+    drop(store);
+}
@@ -1,90 +1,747 @@
-use std::net::SocketAddr;
-use mio::{Events, Interest, Poll, Token};
-use mio::net::TcpListener;
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::net::{TcpListener, TcpStream};
 use std::io;
+use std::net::SocketAddr;
 use std::thread;
-use std::collections::HashMap;
-use std::sync::mpsc::{sync_channel, SyncSender};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+
+use log::{info, warn, error};
+use crate::ack::AckMode;
+use crate::auth::Authenticator;
+use crate::capture::{CaptureConfig, Recorder};
+use crate::connection::{Connection, ConnectionSettings};
+use crate::listener::ListenerConfig;
+use crate::pipeline::{Pipeline, PipelineConfig};
+use crate::queue;
+use crate::queue::{Bus, OverflowPolicy, QueueMetrics};
+use crate::store::Store;
+use crate::wal;
+use crate::wal::WalConfig;
+use crate::transport::Transport;
+use crate::worker::{Worker, WorkerHandle};
 
-use log::info;
-use crate::connection::Connection;
-use crate::store::{GeoPacket, Store};
+// Wakes the acceptor when shutdown is requested. Listener tokens start from zero.
+const SHUTDOWN: Token = Token(usize::MAX);
 
-// mod connection;
-// Setup some tokens to allow us to identify which event is for which socket.
-const SERVER: Token = Token(0);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Upper bound on how long a shutdown request can go unnoticed by the acceptor.
+const SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct Server {
-    addr: SocketAddr,
-    current_conn_token: Token,
-    connections: HashMap<Token, Connection>,
-    bus: SyncSender<GeoPacket>,
+    listeners: Vec<ListenerConfig>,
+    // set by `bind`, taken by `start`
+    acceptors: Option<Vec<Acceptor>>,
+    workers: usize,
+    poll: Poll,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    ack_mode: AckMode,
+    auth: Option<Arc<dyn Authenticator>>,
+    idle_timeout: Option<Duration>,
+    max_connections: Option<usize>,
+    max_message_size: Option<usize>,
+    active: Arc<AtomicUsize>,
+    capture: Option<Arc<CaptureConfig>>,
+    bus: Option<Bus>,
+    queue_metrics: QueueMetrics,
+    // signalled by the store thread once the bus is drained
+    store_done: Receiver<()>,
+}
+
+/// Stops a running server: no new connections are accepted, open connections are closed after
+/// the packet in progress and everything queued on the bus is handed to the `Store`.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+        if let Err(err) = self.waker.wake() {
+            error!("failed to wake server for shutdown: {:?}", err);
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+// Bound listener together with what is needed to wrap its accepted sockets.
+struct Acceptor {
+    socket: TcpListener,
+    config: ListenerConfig,
+    #[cfg(feature = "tls")]
+    tls: Option<std::sync::Arc<rustls::ServerConfig>>,
+}
+
+impl Acceptor {
+    fn bind(config: &ListenerConfig) -> io::Result<Acceptor> {
+        #[cfg(feature = "tls")]
+        let tls = match &config.tls {
+            Some(c) => Some(c.server_config()?),
+            None => None,
+        };
+        #[cfg(not(feature = "tls"))]
+        if let Some(c) = &config.tls {
+            c.check()?;
+        }
+
+        Ok(Acceptor {
+            socket: TcpListener::bind(config.addr)?,
+            config: config.clone(),
+            #[cfg(feature = "tls")]
+            tls,
+        })
+    }
+
+    fn transport(&self, socket: TcpStream) -> io::Result<Transport> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return Transport::tls(socket, tls.clone());
+        }
+        Ok(Transport::Plain(socket))
+    }
 }
 
 impl Server {
-    pub fn new<T: 'static + Store + Send>(addr: &str, buf_size: usize, db: T) -> Server {
-        let (sender, receiver) = sync_channel::<GeoPacket>(buf_size);
+    /// `workers` is the number of reactor threads connections are spread across (at least one).
+    pub fn new<T: 'static + Store + Send>(addr: &str, buf_size: usize, workers: usize, db: T) -> io::Result<Server> {
+        Server::with_pipeline(addr, buf_size, workers, Pipeline::new(db, PipelineConfig::default()))
+    }
+
+    /// Same as `new`, with control over batching, retries and the dead-letter sink.
+    pub fn with_pipeline<T: 'static + Store + Send>(addr: &str, buf_size: usize, workers: usize,
+                                                    pipeline: Pipeline<T>) -> io::Result<Server> {
+        let (bus, receiver, metrics) = queue::channel(buf_size);
+        let (done_sender, done_receiver) = channel();
 
         thread::spawn(move || {
-            loop {
-                let p = receiver.recv().unwrap();
-                db.save(p)
-            }
+            // ends once every sender is dropped and the queue is empty
+            pipeline.run(receiver);
+            // stores with their own threads finish in their drop
+            drop(pipeline);
+            let _ = done_sender.send(());
+        });
+
+        Server::with_bus(addr, workers, bus, metrics, done_receiver)
+    }
+
+    /// Same as `with_pipeline`, but packets are first written to an on-disk log which the store
+    /// reads from. A slow or unavailable store then doesn't block devices and nothing queued
+    /// is lost on restart: records the store didn't confirm are replayed on startup.
+    pub fn with_wal<T: 'static + Store + Send>(addr: &str, buf_size: usize, workers: usize,
+                                               pipeline: Pipeline<T>, wal_config: &WalConfig) -> io::Result<Server> {
+        let (writer, reader) = wal::open(wal_config)?;
+        let (bus, receiver, metrics) = queue::channel(buf_size);
+        let (appended_sender, appended) = crossbeam_channel::unbounded();
+        let (done_sender, done_receiver) = channel();
+
+        thread::spawn(move || wal::run_writer(receiver, writer, appended_sender));
+        thread::spawn(move || {
+            // ends once the writer is gone and the log is read to the end
+            wal::run_reader(&pipeline, reader, appended);
+            drop(pipeline);
+            let _ = done_sender.send(());
         });
 
-        Server {
-            addr: addr.parse().unwrap(),
-            current_conn_token: Token(SERVER.0 + 1),
-            connections: HashMap::new(),
-            bus: sender,
+        Server::with_bus(addr, workers, bus, metrics, done_receiver)
+    }
+
+    fn with_bus(addr: &str, workers: usize, bus: Bus, queue_metrics: QueueMetrics,
+                store_done: Receiver<()>) -> io::Result<Server> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), SHUTDOWN)?;
+
+        Ok(Server {
+            listeners: vec![ListenerConfig::plain(addr)],
+            acceptors: None,
+            workers: workers.max(1),
+            poll,
+            shutdown: ShutdownHandle {
+                requested: Arc::new(AtomicBool::new(false)),
+                waker: Arc::new(waker),
+            },
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            ack_mode: AckMode::Immediate,
+            auth: None,
+            idle_timeout: None,
+            max_connections: None,
+            max_message_size: None,
+            active: Arc::new(AtomicUsize::new(0)),
+            capture: None,
+            bus: Some(bus),
+            queue_metrics,
+            store_done,
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// With `AckMode::AfterStore` devices get responses for data packets only after they are saved.
+    pub fn set_ack_mode(&mut self, mode: AckMode) {
+        self.ack_mode = mode;
+    }
+
+    /// What connections do with packets while the store queue (`buf_size` long) is full.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        if let Some(bus) = &mut self.bus {
+            bus.set_policy(policy);
         }
     }
+
+    /// Depth of the store queue and overflow counters, can be polled from any thread.
+    pub fn queue_metrics(&self) -> QueueMetrics {
+        self.queue_metrics.clone()
+    }
+
+    /// How long `start` waits for workers and the store to finish after shutdown was requested.
+    pub fn set_shutdown_timeout(&mut self, timeout: Duration) {
+        self.shutdown_timeout = timeout;
+    }
+
+    /// Listen on one more address, e.g. a TLS port next to the plain one.
+    pub fn add_listener(&mut self, listener: ListenerConfig) {
+        self.listeners.push(listener);
+    }
+
+    /// Replace all listeners, including the one from the constructor.
+    pub fn set_listeners(&mut self, listeners: Vec<ListenerConfig>) {
+        self.listeners = listeners;
+    }
+
+    /// Check logins, devices which fail get `#AL#01` and are disconnected. Without it every login is accepted.
+    pub fn set_authenticator<A: 'static + Authenticator>(&mut self, auth: A) {
+        self.auth = Some(Arc::new(auth));
+    }
+
+    /// Close connections the device sent nothing on for this long.
+    pub fn set_idle_timeout(&mut self, timeout: Duration) {
+        self.idle_timeout = Some(timeout);
+    }
+
+    /// Connections accepted above this number are closed right away.
+    pub fn set_max_connections(&mut self, max: usize) {
+        self.max_connections = Some(max);
+    }
+
+    /// Close connections sending a packet longer than this many bytes, line end included.
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = Some(max);
+    }
+
+    /// Write the raw traffic of the devices and addresses selected by `config` to capture files.
+    pub fn set_capture(&mut self, config: CaptureConfig) {
+        self.capture = Some(Arc::new(config));
+    }
+
+    /// Number of open device connections.
+    pub fn connections(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Bind every listener and return the addresses in the order they were added, e.g. to learn
+    /// the port picked for `127.0.0.1:0`. Devices can connect right after it returns, `start`
+    /// binds by itself when this wasn't called.
+    pub fn bind(&mut self) -> io::Result<Vec<SocketAddr>> {
+        // listener tokens are their indexes in `acceptors`
+        let mut acceptors = Vec::with_capacity(self.listeners.len());
+        let mut addrs = Vec::with_capacity(self.listeners.len());
+        for (i, config) in self.listeners.iter().enumerate() {
+            let mut acceptor = Acceptor::bind(config)?;
+            self.poll.registry().register(&mut acceptor.socket, Token(i), Interest::READABLE)?;
+
+            let addr = acceptor.socket.local_addr()?;
+            info!("Start server: {} (tls: {})", addr, config.tls.is_some());
+            addrs.push(addr);
+            acceptors.push(acceptor);
+        }
+        self.acceptors = Some(acceptors);
+        Ok(addrs)
+    }
+
+    /// Serve until shutdown is requested through a `ShutdownHandle`.
     pub fn start(&mut self) -> io::Result<()> {
-        let mut poll = Poll::new()?;
+        let bus = match &self.bus {
+            Some(b) => b.to_owned(),
+            None => return Err(io::Error::other("server was already stopped")),
+        };
         let mut events = Events::with_capacity(128);
-        let mut server = TcpListener::bind(self.addr)?;
 
-        poll.registry().register(&mut server, SERVER, Interest::READABLE)?;
+        if self.acceptors.is_none() {
+            self.bind()?;
+        }
+        let acceptors = self.acceptors.take().unwrap_or_default();
+
+        let (mut handles, threads) = self.spawn_workers()?;
+        let mut next_worker = 0;
 
-        info!("Start server: {}", self.addr);
-        loop {
-            poll.poll(&mut events, None)?;
+        info!("Serving with {} workers", handles.len());
+        'serve: while !self.shutdown.is_requested() {
+            // a signal (e.g. the one requesting shutdown) interrupts the wait
+            match self.poll.poll(&mut events, Some(SHUTDOWN_CHECK_INTERVAL)) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
 
             for event in events.iter() {
-                match event.token() {
-                    SERVER => loop {
-                        let (mut connection, address) = match server.accept() {
-                            Ok((connection, address)) => (connection, address),
-                            Err(e) => match e.kind() {
-                                io::ErrorKind::WouldBlock => break,
-                                _ => return Err(e),
+                let acceptor = match acceptors.get(event.token().0) {
+                    Some(a) => a,
+                    None => continue,
+                };
+
+                loop {
+                    let (socket, address) = match acceptor.socket.accept() {
+                        Ok((socket, address)) => (socket, address),
+                        Err(e) => match e.kind() {
+                            io::ErrorKind::WouldBlock => break,
+                            io::ErrorKind::Interrupted => continue,
+                            // e.g. out of file descriptors, connections waiting are taken on the next event
+                            _ => {
+                                error!("failed to accept a connection: {}", e);
+                                break;
                             }
-                        };
-
-                        info!("Accepted connection from: {}", address);
-
-                        let token = self.next_token();
-                        poll.registry().register(&mut connection, token, Interest::READABLE)?;
-
-                        self.connections.insert(token, Connection::new(connection, self.bus.to_owned()));
-                    },
-                    token => {
-                        let connection = self.connections.get_mut(&token).unwrap();
-                        if event.is_readable() {
-                            let r = connection.get_message()?;
-                            if r {
-                                info!("Connection closed");
-                                self.connections.remove(&token);
+                        }
+                    };
+
+                    if self.max_connections.is_some_and(|max| self.active.load(Ordering::SeqCst) >= max) {
+                        warn!("Connection limit reached, refusing {}", address);
+                        continue;
+                    }
+                    info!("Accepted connection from: {}", address);
+
+                    let transport = match acceptor.transport(socket) {
+                        Ok(t) => t,
+                        Err(err) => {
+                            error!("failed to set up connection from {}: {:?}", address, err);
+                            continue;
+                        }
+                    };
+                    let settings = ConnectionSettings {
+                        allow_plain_login: acceptor.config.allow_plain_login,
+                        ack_mode: self.ack_mode,
+                        auth: self.auth.clone(),
+                        idle_timeout: self.idle_timeout,
+                        max_message_size: self.max_message_size,
+                        active: self.active.clone(),
+                    };
+                    let mut connection = Connection::new(transport, bus.to_owned(), settings);
+                    if let Some(capture) = &self.capture {
+                        connection.set_recorder(Recorder::new(capture.clone(), address));
+                    }
+
+                    // a worker which died is dropped, its share goes to the others
+                    let mut connection = Some(connection);
+                    while let Some(c) = connection.take() {
+                        if handles.is_empty() {
+                            error!("all workers stopped");
+                            break 'serve;
+                        }
+                        next_worker %= handles.len();
+                        match handles[next_worker].assign(c) {
+                            Ok(()) => next_worker += 1,
+                            Err(c) => {
+                                error!("worker stopped, {} left", handles.len() - 1);
+                                handles.remove(next_worker);
+                                connection = Some(*c);
                             }
                         }
                     }
                 }
             }
         }
+
+        info!("Shutting down");
+        drop(acceptors);
+        drop(bus);
+        let workers_left = !handles.is_empty();
+        self.stop(handles, threads)?;
+        if workers_left {
+            Ok(())
+        } else {
+            Err(io::Error::other("all workers stopped"))
+        }
+    }
+
+    // Stops workers and waits until the store has saved everything left on the bus.
+    fn stop(&mut self, handles: Vec<WorkerHandle>, threads: Vec<thread::JoinHandle<()>>) -> io::Result<()> {
+        let deadline = Instant::now() + self.shutdown_timeout;
+
+        for handle in handles {
+            if let Err(err) = handle.stop() {
+                error!("failed to stop worker: {:?}", err);
+            }
+        }
+        for t in threads {
+            while !t.is_finished() {
+                if Instant::now() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "workers did not stop in time"));
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            let _ = t.join();
+        }
+
+        // the last sender is gone now, the store thread exits when the queue is empty
+        self.bus = None;
+        let left = deadline.saturating_duration_since(Instant::now());
+        match self.store_done.recv_timeout(left) {
+            Ok(_) => {
+                info!("Server stopped");
+                Ok(())
+            }
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "store did not drain the queue in time")),
+        }
     }
 
-    fn next_token(&mut self) -> Token {
-        self.current_conn_token.0 += 1;
-        self.current_conn_token
+    fn spawn_workers(&self) -> io::Result<(Vec<WorkerHandle>, Vec<thread::JoinHandle<()>>)> {
+        let mut handles = Vec::with_capacity(self.workers);
+        let mut threads = Vec::with_capacity(self.workers);
+        for id in 0..self.workers {
+            let (mut worker, handle) = Worker::new(id)?;
+            threads.push(thread::Builder::new()
+                .name(format!("wialon-worker-{}", id))
+                .spawn(move || {
+                    if let Err(err) = worker.run() {
+                        error!("worker {} failed: {:?}", id, err);
+                    }
+                })?);
+            handles.push(handle);
+        }
+        Ok((handles, threads))
     }
 }
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_tls_listener() {
+    use std::io::prelude::*;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    use rustls::pki_types::{CertificateDer, ServerName};
+    use rustls::pki_types::pem::PemObject;
+    use crate::default_store::ConsoleStore;
+
+    let mut s = Server::new("127.0.0.1:0", 100, 1, ConsoleStore::new()).unwrap();
+    s.listeners[0].allow_plain_login = false;
+    s.add_listener(ListenerConfig::tls("127.0.0.1:0", "test/tls/cert.pem", "test/tls/key.pem"));
+    let addrs = s.bind().unwrap();
+    thread::spawn(move || s.start());
+
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_file("test/tls/ca.pem").unwrap()).unwrap();
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let conn = rustls::ClientConnection::new(Arc::new(config), ServerName::try_from("localhost").unwrap()).unwrap();
+    let mut stream = rustls::StreamOwned::new(conn, std::net::TcpStream::connect(addrs[1]).unwrap());
+    let rlt = &mut [0; 128];
+
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+
+    stream.write_all(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+
+    // plain listener refuses the login and drops the connection
+    let mut stream = std::net::TcpStream::connect(addrs[0]).unwrap();
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#0\r\n");
+    assert_eq!(stream.read(rlt).unwrap(), 0);
+}
+
+#[test]
+fn test_shutdown_drains_bus() {
+    use std::io::prelude::*;
+    use std::sync::mpsc::Sender;
+    use crate::store::GeoPacket;
+
+    struct ChannelStore(Sender<GeoPacket>);
+
+    impl Store for ChannelStore {
+        fn save(&self, p: GeoPacket) -> Result<(), crate::store::StoreError> {
+            self.0.send(p).unwrap();
+            Ok(())
+        }
+    }
+
+    let (sender, saved) = channel();
+    let mut s = Server::new("127.0.0.1:0", 100, 2, ChannelStore(sender)).unwrap();
+    s.set_shutdown_timeout(Duration::from_secs(5));
+    let shutdown = s.shutdown_handle();
+    let addr = s.bind().unwrap()[0];
+    let server = thread::spawn(move || s.start());
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    let rlt = &mut [0; 128];
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+    stream.write_all(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+
+    shutdown.shutdown();
+    server.join().unwrap().unwrap();
+
+    // the connection is closed by the server and the packet reached the store
+    assert_eq!(stream.read(rlt).unwrap(), 0);
+    assert!(saved.try_recv().is_ok());
+    assert!(std::net::TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn test_ack_after_store() {
+    use std::io::prelude::*;
+    use std::sync::mpsc::Receiver;
+    use crate::store::{GeoPacket, StoreError};
+
+    // saves a packet only when the test allows it
+    struct GatedStore(Receiver<()>);
+
+    impl Store for GatedStore {
+        fn save(&self, _: GeoPacket) -> Result<(), StoreError> {
+            self.0.recv().map_err(|e| StoreError::Permanent(e.to_string()))
+        }
+    }
+
+    let (gate, gate_receiver) = channel();
+    let config = PipelineConfig { batch_size: 1, ..PipelineConfig::default() };
+    let mut s = Server::with_pipeline("127.0.0.1:0", 100, 1, Pipeline::new(GatedStore(gate_receiver), config)).unwrap();
+    s.set_ack_mode(AckMode::AfterStore);
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let rlt = &mut [0; 128];
+
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+
+    stream.write_all(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n").unwrap();
+    assert!(stream.read(rlt).is_err());
+
+    gate.send(()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+
+    // a black box is answered once every message is saved, a ping right away
+    stream.write_all(b"#B#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7|280421;055450;5355.09260;N;02732.40990;E;60;0;300;7|\r\n").unwrap();
+    gate.send(()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    assert!(stream.read(rlt).is_err());
+
+    stream.write_all(b"#P#\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AP#\r\n");
+
+    gate.send(()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AB#2\r\n");
+}
+
+#[test]
+fn test_overflow_pause() {
+    use std::io::prelude::*;
+    use std::sync::mpsc::{Receiver, Sender};
+    use crate::store::{GeoPacket, StoreError};
+
+    // tells when it got a packet and saves it only when the test allows it
+    struct GatedStore(Sender<()>, Receiver<()>);
+
+    impl Store for GatedStore {
+        fn save(&self, _: GeoPacket) -> Result<(), StoreError> {
+            let _ = self.0.send(());
+            self.1.recv().map_err(|e| StoreError::Permanent(e.to_string()))
+        }
+    }
+
+    let (entered_sender, entered) = channel();
+    let (gate, gate_receiver) = channel();
+    let config = PipelineConfig { batch_size: 1, ..PipelineConfig::default() };
+    let store = GatedStore(entered_sender, gate_receiver);
+    let mut s = Server::with_pipeline("127.0.0.1:0", 1, 1, Pipeline::new(store, config)).unwrap();
+    s.set_overflow_policy(OverflowPolicy::Pause);
+    let metrics = s.queue_metrics();
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let rlt = &mut [0; 128];
+    let packet = b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n";
+
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+
+    // the first packet is stuck in the store, the second one fills the queue
+    stream.write_all(packet).unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+    entered.recv_timeout(Duration::from_secs(5)).unwrap();
+    stream.write_all(packet).unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+
+    stream.write_all(packet).unwrap();
+    assert!(stream.read(rlt).is_err());
+    assert_eq!((metrics.depth(), metrics.capacity(), metrics.paused()), (1, 1, 1));
+
+    for _ in 0..3 {
+        gate.send(()).unwrap();
+    }
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+    assert_eq!(metrics.paused(), 0);
+}
+
+#[test]
+fn test_authenticator_and_limits() {
+    use std::io::prelude::*;
+    use crate::auth::DeviceList;
+    use crate::default_store::ConsoleStore;
+
+    let mut devices = DeviceList::new();
+    devices.add("861230043907626", "secret");
+    let mut s = Server::new("127.0.0.1:0", 100, 1, ConsoleStore::new()).unwrap();
+    s.set_authenticator(devices);
+    s.set_max_connections(1);
+    s.set_idle_timeout(Duration::from_secs(1));
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
+
+    let rlt = &mut [0; 128];
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"#L#861230043907626;wrong\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#01\r\n");
+    assert_eq!(stream.read(rlt).unwrap(), 0);
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"#L#861230043907626;secret\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+
+    // the only allowed connection is taken
+    let mut second = std::net::TcpStream::connect(addr).unwrap();
+    second.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    assert_eq!(second.read(rlt).unwrap(), 0);
+
+    // a silent device is disconnected
+    assert_eq!(stream.read(rlt).unwrap(), 0);
+}
+
+#[test]
+fn test_malformed_packet() {
+    use std::io::prelude::*;
+    use crate::default_store::ConsoleStore;
+
+    let mut s = Server::new("127.0.0.1:0", 100, 1, ConsoleStore::new()).unwrap();
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
+
+    // only the connection sending garbage is closed
+    let rlt = &mut [0; 128];
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+    stream.write_all(b"#SD#280421;055447;5355.x;N;02732.40990;E;60;0;300;7\r\n").unwrap();
+    assert_eq!(stream.read(rlt).unwrap(), 0);
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"#L#1;1\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#AL#1\r\n");
+    stream.write_all(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n").unwrap();
+    let sz = stream.read(rlt).unwrap();
+    assert_eq!(&rlt[0..sz], b"#ASD#1\r\n");
+}
+
+#[test]
+fn test_data_before_login() {
+    use std::io::prelude::*;
+    use std::sync::mpsc::Sender;
+    use std::sync::Mutex;
+    use crate::store::{GeoPacket, StoreError};
+
+    struct ChannelStore(Mutex<Sender<GeoPacket>>);
+
+    impl Store for ChannelStore {
+        fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+            self.0.lock().unwrap().send(p).map_err(|e| StoreError::Permanent(e.to_string()))
+        }
+    }
+
+    // without a device list a login is still required before any position is taken
+    let (saved, packets) = channel();
+    let mut s = Server::new("127.0.0.1:0", 100, 1, ChannelStore(Mutex::new(saved))).unwrap();
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
+
+    let rlt = &mut [0; 128];
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream.write_all(b"#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n").unwrap();
+    assert_eq!(stream.read(rlt).unwrap(), 0);
+    assert!(packets.recv_timeout(Duration::from_millis(300)).is_err());
+}
+
+#[test]
+fn test_packets_split_across_reads() {
+    use std::io::prelude::*;
+    use crate::default_store::ConsoleStore;
+
+    let mut s = Server::new("127.0.0.1:0", 100, 1, ConsoleStore::new()).unwrap();
+    // limits a packet, not what arrives in one read
+    s.set_max_message_size(80);
+    let addr = s.bind().unwrap()[0];
+    thread::spawn(move || s.start());
+
+    let mut stream = std::net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let sd = "#SD#280421;055447;5355.09260;N;02732.40990;E;60;0;300;7\r\n";
+    let mut answers = String::new();
+    let mut expect = |stream: &mut std::net::TcpStream, answer: &str| {
+        let rlt = &mut [0; 128];
+        while answers.len() < answer.len() {
+            let sz = stream.read(rlt).unwrap();
+            assert!(sz > 0);
+            answers.push_str(std::str::from_utf8(&rlt[..sz]).unwrap());
+        }
+        assert_eq!(answers, answer);
+        answers.clear();
+    };
+
+    // two packets in one write
+    stream.write_all(format!("#L#861230043907626;NA\r\n{}", sd).as_bytes()).unwrap();
+    expect(&mut stream, "#AL#1\r\n#ASD#1\r\n");
+    // a packet in two writes, its end together with the next one
+    stream.write_all(&sd.as_bytes()[..20]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(format!("{}{}", &sd[20..], sd).as_bytes()).unwrap();
+    expect(&mut stream, "#ASD#1\r\n#ASD#1\r\n");
+}
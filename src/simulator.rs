@@ -0,0 +1,510 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{debug, error};
+use crate::client;
+use crate::store::{GeoPacket, Param};
+
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+// Devices run on their own threads, thousands of them need small stacks.
+const DEVICE_STACK_SIZE: usize = 256 * 1024;
+
+// Longest sleep between two looks at the stop flag.
+const STOP_CHECK: Duration = Duration::from_millis(100);
+
+/// Where simulated devices drive. Every route is driven in a loop.
+#[derive(Clone, Debug)]
+pub enum Track {
+    /// A circle of `radius` metres, devices get circles next to each other.
+    Generated { radius: f64 },
+    /// Points (latitude, longitude) of a route shared by all devices, each starts at another place.
+    Points(Vec<(f64, f64)>),
+}
+
+impl Track {
+    /// Track points (`trkpt`) or route points (`rtept`) of a GPX file.
+    pub fn from_gpx(path: &Path) -> io::Result<Track> {
+        let gpx = fs::read_to_string(path)?;
+        let mut points = Vec::new();
+        for tag in gpx.split('<').filter(|t| t.starts_with("trkpt") || t.starts_with("rtept")) {
+            let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+            match (attribute(tag, "lat"), attribute(tag, "lon")) {
+                (Some(lat), Some(lon)) => points.push((lat, lon)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                               format!("{}: point without lat and lon: <{}>", path.display(), tag))),
+            }
+        }
+        if points.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("{}: needs at least two track points", path.display())));
+        }
+        Ok(Track::Points(points))
+    }
+}
+
+fn attribute(tag: &str, name: &str) -> Option<f64> {
+    let start = tag.find(&format!(" {}=", name))? + name.len() + 2;
+    let quote = tag[start..].chars().next()?;
+    let value = &tag[start + 1..];
+    value[..value.find(quote)?].parse().ok()
+}
+
+/// Fake devices started by `simulate`.
+#[derive(Clone, Debug)]
+pub struct SimulatorConfig {
+    pub addr: String,
+    pub devices: usize,
+    /// Devices get the IMEIs `first_imei`, `first_imei + 1`, ...
+    pub first_imei: u64,
+    pub password: String,
+    /// Pause between two positions of a device. Devices start spread over one interval.
+    pub interval: Duration,
+    /// Positions every device sends before it disconnects, 0 sends until stopped.
+    pub packets: usize,
+    /// Stop all devices after this long.
+    pub duration: Option<Duration>,
+    /// Speed in km/h.
+    pub speed: f64,
+    pub track: Track,
+    /// Share of positions sent as D packets with parameters instead of SD packets, from 0 to 1.
+    pub data_ratio: f64,
+    /// Positions collected into one `#B#` black box packet, 0 sends every position right away.
+    pub black_box: usize,
+    /// Send a `#P#` ping this often.
+    pub ping_interval: Option<Duration>,
+    /// Longest wait for an answer, devices reconnect after a timeout.
+    pub timeout: Duration,
+}
+
+impl SimulatorConfig {
+    pub fn new(addr: &str) -> SimulatorConfig {
+        SimulatorConfig {
+            addr: addr.to_string(),
+            devices: 1,
+            first_imei: 860000000000000,
+            password: String::from("NA"),
+            interval: Duration::from_secs(1),
+            packets: 0,
+            duration: None,
+            speed: 60.0,
+            track: Track::Generated { radius: 2000.0 },
+            data_ratio: 0.0,
+            black_box: 0,
+            ping_interval: None,
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// What the devices sent and how the server answered.
+#[derive(Clone, Debug, Default)]
+pub struct SimulatorReport {
+    /// Devices which logged in at least once.
+    pub devices: usize,
+    /// Packets sent by type, `L`, `SD`, `D`, `B` and `P`.
+    pub sent: BTreeMap<String, usize>,
+    /// Packets answered with the expected code.
+    pub acked: usize,
+    /// Positions in acknowledged packets.
+    pub positions: usize,
+    /// Unexpected answers (like `#ASD#0`), timeouts and connection errors.
+    pub errors: BTreeMap<String, usize>,
+    // answer times of acknowledged packets in microseconds
+    latencies: Vec<u64>,
+}
+
+impl SimulatorReport {
+    /// Answer time below which `percentile` (0 to 100) of the acknowledged packets were answered.
+    pub fn latency(&self, percentile: f64) -> Option<Duration> {
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        latency(&sorted, percentile)
+    }
+
+    fn merge(&mut self, other: SimulatorReport) {
+        self.devices += other.devices;
+        self.acked += other.acked;
+        self.positions += other.positions;
+        for (ptype, n) in other.sent {
+            *self.sent.entry(ptype).or_insert(0) += n;
+        }
+        for (error, n) in other.errors {
+            *self.errors.entry(error).or_insert(0) += n;
+        }
+        self.latencies.extend(other.latencies);
+    }
+
+    fn error(&mut self, error: String) {
+        *self.errors.entry(error).or_insert(0) += 1;
+    }
+}
+
+fn latency(sorted: &[u64], percentile: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(Duration::from_micros(sorted[rank.clamp(1, sorted.len()) - 1]))
+}
+
+impl fmt::Display for SimulatorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sent: Vec<String> = self.sent.iter().map(|(t, n)| format!("{} {}", t, n)).collect();
+        writeln!(f, "devices    {} logged in", self.devices)?;
+        writeln!(f, "sent       {} ({})", self.sent.values().sum::<usize>(), sent.join(", "))?;
+        writeln!(f, "acked      {} packets, {} positions", self.acked, self.positions)?;
+
+        let mut sorted = self.latencies.clone();
+        sorted.sort_unstable();
+        let ms = |p: f64| latency(&sorted, p).map_or(0.0, |l| l.as_secs_f64() * 1000.0);
+        writeln!(f, "latency    p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms, max {:.2}ms", ms(50.0), ms(90.0), ms(99.0), ms(100.0))?;
+
+        let errors: Vec<String> = self.errors.iter().map(|(e, n)| format!("{} {}", e, n)).collect();
+        write!(f, "errors     {}", if errors.is_empty() { String::from("none") } else { errors.join(", ") })
+    }
+}
+
+/// Runs `config.devices` devices, each on its own thread and connection, until they sent their
+/// packets, `config.duration` passed or `stop` is set. Devices losing their connection or
+/// missing an answer reconnect after an interval, devices refused at login give up.
+pub fn simulate(config: &SimulatorConfig, stop: Arc<AtomicBool>) -> SimulatorReport {
+    let deadline = config.duration.map(|d| Instant::now() + d);
+    let devices: Vec<_> = (0..config.devices).filter_map(|n| {
+        let config = config.clone();
+        let stop = stop.clone();
+        let spawned = thread::Builder::new()
+            .name(format!("device-{}", n))
+            .stack_size(DEVICE_STACK_SIZE)
+            .spawn(move || Device::new(&config, n, &stop, deadline).run());
+        match spawned {
+            Ok(device) => Some(device),
+            Err(err) => {
+                error!("failed to start device {}: {}", n, err);
+                None
+            }
+        }
+    }).collect();
+
+    let mut total = SimulatorReport::default();
+    for device in devices {
+        if let Ok(report) = device.join() {
+            total.merge(report);
+        }
+    }
+    total
+}
+
+struct Device<'a> {
+    config: &'a SimulatorConfig,
+    n: usize,
+    imei: String,
+    stop: &'a AtomicBool,
+    deadline: Option<Instant>,
+    route: Route,
+    // metres driven along the route
+    distance: f64,
+    positions: usize,
+    // positions waiting for the next black box
+    black_box: Vec<String>,
+    report: SimulatorReport,
+}
+
+impl<'a> Device<'a> {
+    fn new(config: &'a SimulatorConfig, n: usize, stop: &'a AtomicBool, deadline: Option<Instant>) -> Device<'a> {
+        let route = Route::new(&config.track, n);
+        // devices on a shared route start spread along it
+        let distance = match config.track {
+            Track::Points(_) => route.length * n as f64 / config.devices as f64,
+            Track::Generated { .. } => 0.0,
+        };
+        Device {
+            config,
+            n,
+            imei: (config.first_imei + n as u64).to_string(),
+            stop,
+            deadline,
+            route,
+            distance,
+            positions: 0,
+            black_box: Vec::new(),
+            report: SimulatorReport::default(),
+        }
+    }
+
+    fn run(mut self) -> SimulatorReport {
+        let start = self.config.interval.mul_f64(self.n as f64 / self.config.devices as f64);
+        if !self.sleep_until(Instant::now() + start) {
+            return self.report;
+        }
+
+        let mut logged_in = false;
+        loop {
+            match self.session(&mut logged_in) {
+                Ok(()) => break,
+                Err(err) => {
+                    debug!("device {}: {}", self.imei, err);
+                    self.report.error(error_name(&err));
+                    if !self.sleep_until(Instant::now() + self.config.interval) {
+                        break;
+                    }
+                }
+            }
+        }
+        self.report
+    }
+
+    // One connection, returns Ok once the device is done.
+    fn session(&mut self, logged_in: &mut bool) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.config.addr)?;
+        stream.set_read_timeout(Some(self.config.timeout))?;
+        let mut answers = BufReader::new(stream.try_clone()?);
+
+        let login = format!("#L#{};{}\r\n", self.imei, self.config.password);
+        if !self.request(&mut stream, &mut answers, "L", &login, "#AL#1", 0)? {
+            // a refused device won't get in by trying again
+            return Ok(());
+        }
+        if !*logged_in {
+            *logged_in = true;
+            self.report.devices += 1;
+        }
+
+        let mut next_position = Instant::now();
+        let mut next_ping = self.config.ping_interval.map(|i| Instant::now() + i);
+        while self.config.packets == 0 || self.positions < self.config.packets {
+            let ping_first = next_ping.is_some_and(|p| p < next_position);
+            let wake = if ping_first { next_ping.unwrap_or(next_position) } else { next_position };
+            if !self.sleep_until(wake) {
+                return Ok(());
+            }
+
+            if ping_first {
+                self.request(&mut stream, &mut answers, "P", "#P#\r\n", "#AP#", 0)?;
+                next_ping = self.config.ping_interval.map(|i| wake + i);
+                continue;
+            }
+
+            self.send_position(&mut stream, &mut answers)?;
+            next_position += self.config.interval;
+        }
+        Ok(())
+    }
+
+    fn send_position(&mut self, stream: &mut TcpStream, answers: &mut BufReader<TcpStream>) -> io::Result<()> {
+        let (lat, lon, course) = self.route.position(self.distance);
+        self.distance += self.config.speed / 3.6 * self.config.interval.as_secs_f64();
+
+        // positions spread evenly between SD and D packets by `data_ratio`
+        let n = self.positions as f64;
+        let data = (n * self.config.data_ratio).floor() != ((n + 1.0) * self.config.data_ratio).floor();
+        let mut position = GeoPacket {
+            imei: self.imei.clone(),
+            timestamp: Utc::now().naive_utc(),
+            lat,
+            lon,
+            speed: self.config.speed.round() as i16,
+            course: course.round() as i16,
+            height: 200,
+            sats: 12,
+            ptype: String::from(if data { "D" } else { "SD" }),
+            params: BTreeMap::new(),
+        };
+        if data {
+            let odometer = (self.distance / 100.0).round() / 10.0;
+            position.params.insert(String::from("hdop"), Param::Float(1.0));
+            position.params.insert(String::from("inputs"), Param::Int(0));
+            position.params.insert(String::from("outputs"), Param::Int(0));
+            position.params.insert(String::from("odometer"), Param::Float(odometer));
+            position.params.insert(String::from("ignition"), Param::Int(1));
+        }
+        let body = client::encode(&position);
+        self.positions += 1;
+
+        if self.config.black_box == 0 {
+            let (ptype, expected) = if data { ("D", "#AD#1") } else { ("SD", "#ASD#1") };
+            self.request(stream, answers, ptype, &format!("#{}#{}\r\n", ptype, body), expected, 1)?;
+            return Ok(());
+        }
+
+        self.black_box.push(body);
+        let last = self.config.packets != 0 && self.positions == self.config.packets;
+        if self.black_box.len() >= self.config.black_box || last {
+            let count = self.black_box.len();
+            let packet = format!("#B#{}|\r\n", self.black_box.join("|"));
+            // on failure the device keeps its black box and sends it again
+            if self.request(stream, answers, "B", &packet, &format!("#AB#{}", count), count)? {
+                self.black_box.clear();
+            }
+        }
+        Ok(())
+    }
+
+    // Sends a packet and checks the answer, returns whether it was the expected one.
+    fn request(&mut self, stream: &mut TcpStream, answers: &mut BufReader<TcpStream>, ptype: &str, packet: &str,
+               expected: &str, positions: usize) -> io::Result<bool>
+    {
+        let sent = Instant::now();
+        stream.write_all(packet.as_bytes())?;
+        *self.report.sent.entry(ptype.to_string()).or_insert(0) += 1;
+
+        let mut answer = String::new();
+        if answers.read_line(&mut answer)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let answer = answer.trim_end();
+        if answer != expected {
+            self.report.error(answer.to_string());
+            return Ok(false);
+        }
+        self.report.acked += 1;
+        self.report.positions += positions;
+        self.report.latencies.push(sent.elapsed().as_micros() as u64);
+        Ok(true)
+    }
+
+    // Sleeps until `until` unless the device is stopped first, returns false if it is.
+    fn sleep_until(&self, until: Instant) -> bool {
+        loop {
+            let now = Instant::now();
+            if self.stop.load(Ordering::Relaxed) || self.deadline.is_some_and(|d| now >= d) {
+                return false;
+            }
+            if now >= until {
+                return true;
+            }
+            thread::sleep((until - now).min(STOP_CHECK));
+        }
+    }
+}
+
+fn error_name(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => String::from("timeout"),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe => {
+            String::from("disconnected")
+        }
+        io::ErrorKind::ConnectionRefused => String::from("connection refused"),
+        _ => err.to_string(),
+    }
+}
+
+// Closed polyline with the distance of every point from the start.
+struct Route {
+    points: Vec<(f64, f64)>,
+    distances: Vec<f64>,
+    length: f64,
+}
+
+impl Route {
+    fn new(track: &Track, n: usize) -> Route {
+        let mut points = match track {
+            Track::Generated { radius } => {
+                // circles on a grid of about 1 km, a hundred per row
+                let center = (53.9 + (n % 100) as f64 * 0.01, 27.56 + (n / 100) as f64 * 0.015);
+                (0..36).map(|i| {
+                    let angle = (i as f64 * 10.0).to_radians();
+                    let lat = center.0 + (radius * angle.cos() / EARTH_RADIUS).to_degrees();
+                    let lon = center.1 + (radius * angle.sin() / (EARTH_RADIUS * center.0.to_radians().cos())).to_degrees();
+                    (lat, lon)
+                }).collect()
+            }
+            Track::Points(points) => points.clone(),
+        };
+        points.push(points[0]);
+
+        let mut distances = vec![0.0];
+        for w in points.windows(2) {
+            distances.push(distances[distances.len() - 1] + distance(w[0], w[1]));
+        }
+        let length = distances[distances.len() - 1];
+        Route { points, distances, length }
+    }
+
+    // Latitude, longitude and course after driving `driven` metres.
+    fn position(&self, driven: f64) -> (f64, f64, f64) {
+        let d = if self.length > 0.0 { driven % self.length } else { 0.0 };
+        let i = self.distances.partition_point(|&x| x <= d).clamp(1, self.points.len() - 1);
+        let (a, b) = (self.points[i - 1], self.points[i]);
+        let segment = self.distances[i] - self.distances[i - 1];
+        let k = if segment > 0.0 { (d - self.distances[i - 1]) / segment } else { 0.0 };
+        (a.0 + (b.0 - a.0) * k, a.1 + (b.1 - a.1) * k, bearing(a, b))
+    }
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let x = (b.1 - a.1).to_radians() * ((a.0 + b.0) / 2.0).to_radians().cos();
+    let y = (b.0 - a.0).to_radians();
+    (x * x + y * y).sqrt() * EARTH_RADIUS
+}
+
+fn bearing(a: (f64, f64), b: (f64, f64)) -> f64 {
+    let x = (b.1 - a.1).to_radians() * ((a.0 + b.0) / 2.0).to_radians().cos();
+    let y = (b.0 - a.0).to_radians();
+    x.atan2(y).to_degrees().rem_euclid(360.0)
+}
+
+#[test]
+fn test_simulate_devices() {
+    use std::net::TcpListener;
+    use crate::wialon::Packet;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    thread::spawn(move || {
+        for socket in listener.incoming() {
+            let socket = socket.unwrap();
+            thread::spawn(move || {
+                let mut writer = socket.try_clone().unwrap();
+                for line in BufReader::new(socket).lines() {
+                    let line = format!("{}\r\n", line.unwrap());
+                    let p = Packet::from(line.as_bytes()).unwrap();
+                    writer.write_all(p.response(1).unwrap().to_string().as_bytes()).unwrap();
+                }
+            });
+        }
+    });
+
+    let mut config = SimulatorConfig::new(&addr);
+    config.devices = 3;
+    config.packets = 5;
+    config.interval = Duration::from_millis(20);
+    config.data_ratio = 0.5;
+    config.black_box = 2;
+    config.ping_interval = Some(Duration::from_millis(30));
+    let report = simulate(&config, Arc::new(AtomicBool::new(false)));
+
+    // every device: a login, black boxes of 2, 2 and 1 positions, and some pings
+    assert_eq!(report.devices, 3);
+    assert_eq!(report.sent["L"], 3);
+    assert_eq!(report.sent["B"], 9);
+    assert!(report.sent["P"] > 0);
+    assert_eq!(report.positions, 15);
+    assert_eq!(report.acked, report.sent.values().sum::<usize>());
+    assert!(report.errors.is_empty());
+    assert!(report.latency(50.0) <= report.latency(99.0));
+
+    let dir = tempfile::tempdir().unwrap();
+    let gpx = dir.path().join("route.gpx");
+    fs::write(&gpx, r#"<gpx><trk><trkseg><trkpt lat="53.9" lon="27.5"><ele>200</ele></trkpt>
+                       <trkpt lon='27.51' lat='53.9'/></trkseg></trk></gpx>"#).unwrap();
+    match Track::from_gpx(&gpx).unwrap() {
+        Track::Points(points) => assert_eq!(points, vec![(53.9, 27.5), (53.9, 27.51)]),
+        track => panic!("{:?}", track),
+    }
+    let route = Route::new(&Track::Points(vec![(53.9, 27.5), (53.9, 27.51)]), 0);
+    let (lat, lon, course) = route.position(route.length / 4.0);
+    assert!((lat - 53.9).abs() < 1e-9 && (lon - 27.505).abs() < 1e-6 && (course - 90.0).abs() < 1e-6);
+
+    assert_eq!(client::degrees_minutes(53.918210, 2), "5355.09260");
+    assert_eq!(client::degrees_minutes(27.540165, 3), "02732.40990");
+}
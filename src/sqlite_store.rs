@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+use chrono::{NaiveDateTime, Utc};
+use rusqlite::{params, Connection, ErrorCode};
+
+use log::{info, error};
+use crate::store::{GeoPacket, Store, StoreError};
+
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS positions (
+        imei TEXT NOT NULL,
+        timestamp TEXT NOT NULL,
+        lat REAL NOT NULL,
+        lon REAL NOT NULL,
+        speed INTEGER NOT NULL,
+        course INTEGER NOT NULL,
+        height INTEGER NOT NULL,
+        sats INTEGER NOT NULL,
+        ptype TEXT NOT NULL,
+        params TEXT NOT NULL DEFAULT '{}'
+    );
+    CREATE INDEX IF NOT EXISTS positions_imei_timestamp ON positions (imei, timestamp);";
+
+#[derive(Clone, Debug)]
+pub struct SqliteConfig {
+    pub path: PathBuf,
+    /// Positions older than this (by device time) are deleted by a background job.
+    pub retention: Option<Duration>,
+    pub cleanup_interval: Duration,
+}
+
+impl SqliteConfig {
+    pub fn new(path: &str) -> SqliteConfig {
+        SqliteConfig {
+            path: PathBuf::from(path),
+            retention: None,
+            cleanup_interval: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Keeps positions in a local SQLite file, for single box installations without a database server.
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    // dropping the sender stops the cleanup job
+    cleanup: Option<(Sender<()>, thread::JoinHandle<()>)>,
+}
+
+impl SqliteStore {
+    pub fn new(config: &SqliteConfig) -> Result<SqliteStore, StoreError> {
+        let conn = open(config)?;
+        conn.execute_batch(SCHEMA).map_err(sqlite_error)?;
+
+        let cleanup = match config.retention {
+            Some(retention) => Some(spawn_cleanup(open(config)?, retention, config.cleanup_interval)?),
+            None => None,
+        };
+
+        Ok(SqliteStore { conn: Mutex::new(conn), cleanup })
+    }
+
+    /// Positions of a device between `from` and `to` (inclusive), oldest first.
+    pub fn track(&self, imei: &str, from: NaiveDateTime, to: NaiveDateTime) -> Result<Vec<GeoPacket>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare_cached(
+            "SELECT timestamp, lat, lon, speed, course, height, sats, ptype, params FROM positions
+             WHERE imei = ?1 AND timestamp BETWEEN ?2 AND ?3 ORDER BY timestamp").map_err(sqlite_error)?;
+
+        let from = from.format(TIME_FORMAT).to_string();
+        let to = to.format(TIME_FORMAT).to_string();
+        let rows = stmt.query_map(params![imei, from, to], |row| {
+            let timestamp: String = row.get(0)?;
+            let params: String = row.get(8)?;
+            Ok(GeoPacket {
+                imei: imei.to_string(),
+                timestamp: NaiveDateTime::parse_from_str(&timestamp, TIME_FORMAT)
+                    .map_err(|e| conversion_error(0, Box::new(e)))?,
+                lat: row.get(1)?,
+                lon: row.get(2)?,
+                speed: row.get(3)?,
+                course: row.get(4)?,
+                height: row.get(5)?,
+                sats: row.get(6)?,
+                ptype: row.get(7)?,
+                params: serde_json::from_str(&params).map_err(|e| conversion_error(8, Box::new(e)))?,
+            })
+        }).map_err(sqlite_error)?;
+
+        rows.collect::<Result<Vec<_>, _>>().map_err(sqlite_error)
+    }
+
+    /// Deletes positions older than `before`, returns how many.
+    pub fn cleanup(&self, before: NaiveDateTime) -> Result<usize, StoreError> {
+        delete_before(&self.conn.lock().unwrap(), before)
+    }
+}
+
+impl Store for SqliteStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction().map_err(sqlite_error)?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO positions (imei, timestamp, lat, lon, speed, course, height, sats, ptype, params)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)").map_err(sqlite_error)?;
+            for p in batch {
+                let params = serde_json::to_string(&p.params).map_err(|e| StoreError::Permanent(e.to_string()))?;
+                stmt.execute(params![p.imei, p.timestamp.format(TIME_FORMAT).to_string(), p.lat, p.lon,
+                                     p.speed, p.course, p.height, p.sats, p.ptype, params])
+                    .map_err(sqlite_error)?;
+            }
+        }
+        tx.commit().map_err(sqlite_error)
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        if let Some((stop, thread)) = self.cleanup.take() {
+            drop(stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+fn open(config: &SqliteConfig) -> Result<Connection, StoreError> {
+    let conn = Connection::open(&config.path).map_err(sqlite_error)?;
+    // readers don't block the writer, and a commit doesn't wait for a full fsync
+    conn.pragma_update(None, "journal_mode", "WAL").map_err(sqlite_error)?;
+    conn.pragma_update(None, "synchronous", "NORMAL").map_err(sqlite_error)?;
+    conn.busy_timeout(Duration::from_secs(5)).map_err(sqlite_error)?;
+    Ok(conn)
+}
+
+fn spawn_cleanup(conn: Connection, retention: Duration, interval: Duration)
+                 -> Result<(Sender<()>, thread::JoinHandle<()>), StoreError> {
+    let retention = chrono::Duration::from_std(retention).map_err(|e| StoreError::Permanent(e.to_string()))?;
+    let (stop, stopped) = channel();
+
+    let thread = thread::Builder::new()
+        .name(String::from("wialon-sqlite-cleanup"))
+        .spawn(move || loop {
+            match delete_before(&conn, Utc::now().naive_utc() - retention) {
+                Ok(n) if n > 0 => info!("Deleted {} old positions", n),
+                Ok(_) => {}
+                Err(err) => error!("retention cleanup failed: {}", err),
+            }
+            if let Err(RecvTimeoutError::Disconnected) = stopped.recv_timeout(interval) {
+                return;
+            }
+        })
+        .map_err(|e| StoreError::Permanent(e.to_string()))?;
+
+    Ok((stop, thread))
+}
+
+fn delete_before(conn: &Connection, before: NaiveDateTime) -> Result<usize, StoreError> {
+    conn.execute("DELETE FROM positions WHERE timestamp < ?1", params![before.format(TIME_FORMAT).to_string()])
+        .map_err(sqlite_error)
+}
+
+fn conversion_error(column: usize, err: Box<dyn std::error::Error + Send + Sync>) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, err)
+}
+
+// A locked database is worth a retry, anything else is not.
+fn sqlite_error(err: rusqlite::Error) -> StoreError {
+    match err.sqlite_error_code() {
+        Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => StoreError::Transient(err.to_string()),
+        _ => StoreError::Permanent(err.to_string()),
+    }
+}
+
+#[test]
+fn test_sqlite_store_track() {
+    use crate::store::Param;
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = SqliteConfig::new(dir.path().join("track.db").to_str().unwrap());
+    let store = SqliteStore::new(&config).unwrap();
+
+    let time = |t: &str| NaiveDateTime::parse_from_str(t, TIME_FORMAT).unwrap();
+    let packet = |imei: &str, t: &str| GeoPacket { timestamp: time(t), ..test_packet(imei) };
+
+    let mut with_params = packet("1", "2021-04-28 06:00:00");
+    with_params.params.insert(String::from("var"), Param::Float(4.5));
+    store.save_batch(&[
+        packet("1", "2021-04-28 05:00:00"),
+        with_params.clone(),
+        packet("2", "2021-04-28 06:00:00"),
+        packet("1", "2021-04-28 07:00:00"),
+    ]).unwrap();
+
+    let track = store.track("1", time("2021-04-28 05:30:00"), time("2021-04-28 07:00:00")).unwrap();
+    assert_eq!(track.len(), 2);
+    assert_eq!(track[0], with_params);
+
+    assert_eq!(store.cleanup(time("2021-04-28 06:30:00")).unwrap(), 3);
+    assert_eq!(store.track("1", time("2021-04-28 00:00:00"), time("2021-04-29 00:00:00")).unwrap().len(), 1);
+}
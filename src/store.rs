@@ -1,27 +1,114 @@
 use chrono::NaiveDateTime;
-use serde::{Serialize};
+use serde::{Serialize, Deserialize};
+use std::fmt;
+use std::io;
+use std::collections::BTreeMap;
 
-use crate::wialon::ShortDataPacket;
+use crate::wialon::{Packet, Params, ShortDataPacket};
+
+#[derive(Debug)]
+pub enum StoreError {
+    /// Backend is temporary unavailable (connection lost, timeout), saving may be retried.
+    Transient(String),
+    /// Data can't be saved at all (invalid packet, schema mismatch), retrying won't help.
+    Permanent(String),
+}
+
+impl StoreError {
+    pub fn is_transient(&self) -> bool {
+        matches!(self, StoreError::Transient(_))
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Transient(e) => write!(f, "transient store error: {}", e),
+            StoreError::Permanent(e) => write!(f, "permanent store error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<StoreError> for io::Error {
+    fn from(err: StoreError) -> io::Error {
+        io::Error::other(err.to_string())
+    }
+}
 
 pub trait Store {
-    fn save(&self, p: GeoPacket);
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError>;
+
+    /// Save several packets at once. Backends with bulk inserts should override it.
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        for p in batch {
+            self.save(p.clone())?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Store + ?Sized> Store for Box<S> {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        (**self).save(p)
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        (**self).save_batch(batch)
+    }
 }
 
-#[derive(Serialize, Debug)]
+/// Extra parameter of a D packet, serialized as a plain JSON number or string.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Param {
+    Int(i32),
+    Float(f64),
+    String(String),
+}
+
+impl From<&Params<'_>> for Param {
+    fn from(p: &Params<'_>) -> Param {
+        match p {
+            Params::Int(v) => Param::Int(*v),
+            Params::Float(v) => Param::Float(*v),
+            Params::String(v) => Param::String(v.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Param::Int(v) => write!(f, "{}", v),
+            Param::Float(v) => write!(f, "{}", v),
+            Param::String(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GeoPacket {
-    imei: String,
-    timestamp: NaiveDateTime,
-    lat: f64,
-    lon: f64,
-    speed: i16,
-    course: i16,
-    height: i16,
-    sats: i16,
+    pub imei: String,
+    pub timestamp: NaiveDateTime,
+    pub lat: f64,
+    pub lon: f64,
+    pub speed: i16,
+    pub course: i16,
+    pub height: i16,
+    pub sats: i16,
+    /// Type of the packet the position came from: "SD" or "D".
+    #[serde(default)]
+    pub ptype: String,
+    /// Extra parameters of a D packet by name.
+    #[serde(default)]
+    pub params: BTreeMap<String, Param>,
 }
 
 impl GeoPacket {
     pub fn new(client: Vec<u8>, data: &ShortDataPacket) -> GeoPacket {
-        return GeoPacket {
+        GeoPacket {
             imei: String::from_utf8(client).unwrap(),
             timestamp: data.timestamp,
             lat: data.lat,
@@ -30,6 +117,66 @@ impl GeoPacket {
             course: data.course,
             height: data.height,
             sats: data.sats,
-        };
+            ptype: String::from("SD"),
+            params: BTreeMap::new(),
+        }
+    }
+
+    /// Position from a SD or D packet, keeps the packet type and the extra parameters.
+    pub fn from_packet<'a>(client: Vec<u8>, p: &'a Packet) -> Result<GeoPacket, &'a str> {
+        let mut packet = GeoPacket::new(client, p.get_navigate_data()?);
+        packet.ptype = p.ptype.clone();
+        packet.params = p.get_params().into_iter().map(|(k, v)| (k.to_string(), Param::from(v))).collect();
+        Ok(packet)
     }
-}
\ No newline at end of file
+}
+
+/// Position of a standing device near Minsk at 2021-04-28 05:52:20, for store tests.
+#[cfg(test)]
+pub(crate) fn test_packet(imei: &str) -> GeoPacket {
+    use std::convert::TryFrom;
+
+    let spd = ShortDataPacket::try_from(vec!("280421", "055220", "5355.09260", "N", "02732.40990", "E", "0", "0", "300", "7")).unwrap();
+    GeoPacket::new(imei.as_bytes().to_vec(), &spd)
+}
+
+// Lowercase header lines and body of a request to `test_http_server`.
+#[cfg(all(test, any(feature = "webhook", feature = "influx")))]
+pub(crate) type TestRequest = (Vec<String>, Vec<u8>);
+
+/// Serves one HTTP request per `(status, body)` answer and hands over each request, for stores posting to
+/// an endpoint. Returns the base URL.
+#[cfg(all(test, any(feature = "webhook", feature = "influx")))]
+pub(crate) fn test_http_server(answers: Vec<(&'static str, &'static str)>) -> (String, std::sync::mpsc::Receiver<TestRequest>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, received) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for (status, body) in answers {
+            let mut reader = BufReader::new(listener.accept().unwrap().0);
+            let (mut headers, mut length) = (Vec::new(), 0);
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(v) = line.strip_prefix("content-length: ") {
+                    length = v.parse().unwrap();
+                }
+                headers.push(line);
+            }
+            let mut request = vec![0; length];
+            reader.read_exact(&mut request).unwrap();
+            sender.send((headers, request)).unwrap();
+
+            write!(reader.get_mut(), "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                   status, body.len(), body).unwrap();
+        }
+    });
+    (url, received)
+}
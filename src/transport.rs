@@ -0,0 +1,206 @@
+use mio::{Interest, Registry, Token};
+use mio::net::TcpStream;
+use mio::event::Source;
+
+use std::io;
+use std::io::{Read, Write};
+
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+/// Byte stream of a device connection, either plain TCP or TLS on top of it.
+pub enum Transport {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    /// Stream without a socket, fed by `capture::replay`.
+    Memory(MemoryStream),
+}
+
+impl Transport {
+    #[cfg(feature = "tls")]
+    pub fn tls(socket: TcpStream, config: Arc<rustls::ServerConfig>) -> io::Result<Transport> {
+        let conn = rustls::ServerConnection::new(config)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(Transport::Tls(Box::new(TlsStream { conn, socket })))
+    }
+
+    pub fn is_tls(&self) -> bool {
+        match self {
+            Transport::Plain(_) | Transport::Memory(_) => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls(_) => true,
+        }
+    }
+
+    /// Data was accepted by `write` but still has to reach the socket.
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Transport::Plain(_) | Transport::Memory(_) => false,
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.conn.wants_write(),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.read(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.read(buf),
+            Transport::Memory(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.write(buf),
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.write(buf),
+            Transport::Memory(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(s) => s.flush(),
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.flush(),
+            Transport::Memory(s) => s.flush(),
+        }
+    }
+}
+
+impl Source for Transport {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest)
+                -> io::Result<()>
+    {
+        match self {
+            Transport::Plain(s) => s.register(registry, token, interests),
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.socket.register(registry, token, interests),
+            Transport::Memory(_) => Ok(()),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest)
+                  -> io::Result<()>
+    {
+        match self {
+            Transport::Plain(s) => s.reregister(registry, token, interests),
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.socket.reregister(registry, token, interests),
+            Transport::Memory(_) => Ok(()),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Transport::Plain(s) => s.deregister(registry),
+            #[cfg(feature = "tls")]
+            Transport::Tls(s) => s.socket.deregister(registry),
+            Transport::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// Bytes queued in `input` are read like from a non-blocking socket, writes collect in `output`.
+#[derive(Default)]
+pub struct MemoryStream {
+    pub input: Vec<u8>,
+    pub output: Vec<u8>,
+    /// Read returns the end of the stream once `input` is empty.
+    pub closed: bool,
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.input.is_empty() {
+            return if self.closed { Ok(0) } else { Err(io::ErrorKind::WouldBlock.into()) };
+        }
+        let n = buf.len().min(self.input.len());
+        buf[..n].copy_from_slice(&self.input[..n]);
+        self.input.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Non-blocking rustls session over a mio socket.
+#[cfg(feature = "tls")]
+pub struct TlsStream {
+    conn: rustls::ServerConnection,
+    socket: TcpStream,
+}
+
+#[cfg(feature = "tls")]
+impl TlsStream {
+    // Push encrypted records to the socket until it would block.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.socket) {
+                Ok(_) => {}
+                Err(err) => match err.kind() {
+                    io::ErrorKind::WouldBlock => break,
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+
+            match self.conn.read_tls(&mut self.socket) {
+                Ok(0) => return Ok(0),
+                Ok(_) => {}
+                Err(err) => return Err(err),
+            }
+
+            if let Err(err) = self.conn.process_new_packets() {
+                // let the peer know why the session is being dropped
+                let _ = self.write_tls();
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+
+            // handshake messages have to go out before the peer sends application data
+            self.write_tls()?;
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.conn.writer().write(buf)?;
+        self.write_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()
+    }
+}
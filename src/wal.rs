@@ -0,0 +1,601 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+
+use log::{info, warn, error};
+use crate::ack::{Ack, Delivery};
+use crate::pipeline::Pipeline;
+use crate::store::{GeoPacket, Store};
+
+// Record layout: payload length (u32), crc32 of seq + payload (u32), seq (u64), JSON payload.
+const HEADER_LEN: usize = 16;
+const SEGMENT_EXT: &str = "wal";
+const CHECKPOINT_FILE: &str = "checkpoint";
+
+/// When appended records are forced to disk.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// After every group of records, nothing acknowledged is lost on power failure.
+    Always,
+    /// At most once per interval, the last interval may be lost on power failure.
+    Interval(Duration),
+    /// Leave it to the OS, survives process crashes only.
+    Never,
+}
+
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    /// A new segment file is started once the current one grows past this size.
+    pub segment_size: u64,
+    pub fsync: FsyncPolicy,
+}
+
+impl WalConfig {
+    pub fn new(dir: &str) -> WalConfig {
+        WalConfig {
+            dir: PathBuf::from(dir),
+            segment_size: 64 * 1024 * 1024,
+            fsync: FsyncPolicy::Always,
+        }
+    }
+}
+
+/// Opens the log in `config.dir`. The reader starts at the first record the store hasn't
+/// confirmed, so whatever was left from the previous run is replayed.
+pub fn open(config: &WalConfig) -> io::Result<(WalWriter, WalReader)> {
+    fs::create_dir_all(&config.dir)?;
+
+    let checkpoint = read_checkpoint(&config.dir)?;
+    let segments = list_segments(&config.dir)?;
+
+    // the last segment may end with a record torn by a crash
+    let mut next_seq = checkpoint + 1;
+    if let Some(&first_seq) = segments.last() {
+        let path = segment_path(&config.dir, first_seq);
+        let (last_seq, valid_len) = scan_segment(&path)?;
+        let file = OpenOptions::new().write(true).open(&path)?;
+        if file.metadata()?.len() != valid_len {
+            warn!("truncating torn tail of {}", path.display());
+            file.set_len(valid_len)?;
+        }
+        next_seq = next_seq.max(last_seq.map(|s| s + 1).unwrap_or(first_seq));
+    }
+
+    let pending = next_seq - checkpoint - 1;
+    if pending > 0 {
+        info!("{} records to replay from {}", pending, config.dir.display());
+    }
+
+    let writer = WalWriter::new(config, next_seq)?;
+    let reader = WalReader {
+        dir: config.dir.clone(),
+        checkpoint,
+        next_seq: checkpoint + 1,
+        segment: None,
+    };
+    Ok((writer, reader))
+}
+
+/// Appending half of the log.
+pub struct WalWriter {
+    dir: PathBuf,
+    segment_size: u64,
+    fsync: FsyncPolicy,
+    file: File,
+    file_size: u64,
+    next_seq: u64,
+    last_sync: Instant,
+    unsynced: bool,
+}
+
+impl WalWriter {
+    fn new(config: &WalConfig, next_seq: u64) -> io::Result<WalWriter> {
+        let file = create_segment(&config.dir, next_seq)?;
+        Ok(WalWriter {
+            dir: config.dir.clone(),
+            segment_size: config.segment_size,
+            fsync: config.fsync,
+            file,
+            file_size: 0,
+            next_seq,
+            last_sync: Instant::now(),
+            unsynced: false,
+        })
+    }
+
+    /// Appends the packet and returns its sequence number. Call `commit` to make it durable.
+    pub fn append(&mut self, p: &GeoPacket) -> io::Result<u64> {
+        if self.file_size >= self.segment_size {
+            self.sync()?;
+            self.file = create_segment(&self.dir, self.next_seq)?;
+            self.file_size = 0;
+        }
+
+        let seq = self.next_seq;
+        let payload = serde_json::to_vec(p).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(seq, &payload).to_le_bytes());
+        record.extend_from_slice(&seq.to_le_bytes());
+        record.extend_from_slice(&payload);
+        self.file.write_all(&record)?;
+
+        self.file_size += record.len() as u64;
+        self.next_seq += 1;
+        self.unsynced = true;
+        Ok(seq)
+    }
+
+    /// Syncs according to the fsync policy. Returns true when everything appended so far is durable.
+    pub fn commit(&mut self) -> io::Result<bool> {
+        match self.fsync {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Interval(d) if self.last_sync.elapsed() >= d => self.sync()?,
+            FsyncPolicy::Interval(_) => {}
+            FsyncPolicy::Never => self.unsynced = false,
+        }
+        Ok(!self.unsynced)
+    }
+
+    /// Time left until `commit` syncs pending records, if any are waiting.
+    pub fn sync_due_in(&self) -> Option<Duration> {
+        match self.fsync {
+            FsyncPolicy::Interval(d) if self.unsynced => Some(d.saturating_sub(self.last_sync.elapsed())),
+            _ => None,
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+/// Reading half of the log, feeds the store and remembers what it confirmed.
+pub struct WalReader {
+    dir: PathBuf,
+    checkpoint: u64,
+    next_seq: u64,
+    // first seq of the segment being read and the file positioned at the next record
+    segment: Option<(u64, File)>,
+}
+
+impl WalReader {
+    /// Reads up to `max` records which are completely written, oldest first.
+    pub fn read(&mut self, max: usize) -> io::Result<Vec<(u64, GeoPacket)>> {
+        let mut records = Vec::new();
+
+        while records.len() < max {
+            if self.segment.is_none() && !self.open_segment()? {
+                break;
+            }
+
+            let (_, file) = self.segment.as_mut().unwrap();
+            match read_record(file)? {
+                Some((seq, p)) => {
+                    if seq >= self.next_seq {
+                        self.next_seq = seq + 1;
+                        records.push((seq, p));
+                    }
+                }
+                None => {
+                    // either the writer is still filling this segment or it moved on
+                    if !self.has_segment_after()? {
+                        break;
+                    }
+                    self.segment = None;
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    /// Starts reading again from the first record after the last commit.
+    pub(crate) fn rewind(&mut self) {
+        self.next_seq = self.checkpoint + 1;
+        self.segment = None;
+    }
+
+    /// Marks everything up to `seq` as saved and removes segments nobody needs anymore.
+    pub fn commit(&mut self, seq: u64) -> io::Result<()> {
+        if seq <= self.checkpoint {
+            return Ok(());
+        }
+        write_checkpoint(&self.dir, seq)?;
+        self.checkpoint = seq;
+
+        let segments = list_segments(&self.dir)?;
+        for pair in segments.windows(2) {
+            // every record of a segment is older than the first record of the next one
+            if pair[1] <= seq + 1 {
+                fs::remove_file(segment_path(&self.dir, pair[0]))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Opens the segment holding `next_seq`. Returns false if it doesn't exist yet.
+    fn open_segment(&mut self) -> io::Result<bool> {
+        let segments = list_segments(&self.dir)?;
+        let first_seq = match segments.iter().rev().find(|&&s| s <= self.next_seq) {
+            Some(&s) => s,
+            None => match segments.first() {
+                Some(&s) => s,
+                None => return Ok(false),
+            },
+        };
+
+        let file = File::open(segment_path(&self.dir, first_seq))?;
+        self.segment = Some((first_seq, file));
+        Ok(true)
+    }
+
+    fn has_segment_after(&self) -> io::Result<bool> {
+        let current = match &self.segment {
+            Some((s, _)) => *s,
+            None => return Ok(false),
+        };
+        Ok(list_segments(&self.dir)?.iter().any(|&s| s > current))
+    }
+}
+
+/// Writes packets from the bus into the log. Packets waiting for an ack are confirmed as soon
+/// as their records are durable, the store catches up from the log on its own pace.
+pub(crate) fn run_writer(bus: Receiver<Delivery>, mut writer: WalWriter, appended: Sender<()>) {
+    let mut acks: Vec<Ack> = Vec::new();
+    let append = |writer: &mut WalWriter, d: Delivery, acks: &mut Vec<Ack>| {
+        match writer.append(&d.packet) {
+            Ok(_) => acks.extend(d.ack),
+            // without an ack the device sends the packet again
+            Err(err) => error!("failed to write packet to log: {}", err),
+        }
+    };
+
+    loop {
+        let received = match writer.sync_due_in() {
+            Some(d) => bus.recv_timeout(d),
+            None => bus.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        let disconnected = match received {
+            Ok(d) => {
+                append(&mut writer, d, &mut acks);
+                // group commit of whatever else is already queued
+                while let Ok(d) = bus.try_recv() {
+                    append(&mut writer, d, &mut acks);
+                }
+                false
+            }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+
+        let durable = if disconnected { writer.sync().map(|_| true) } else { writer.commit() };
+        match durable {
+            Ok(true) => acks.drain(..).for_each(|ack| ack.confirm()),
+            Ok(false) => {}
+            Err(err) => error!("failed to sync log: {}", err),
+        }
+        let _ = appended.send(());
+
+        if disconnected {
+            info!("Log writer stopped");
+            return;
+        }
+    }
+}
+
+/// Feeds the store from the log in batches and moves the checkpoint after every saved batch.
+/// Transient store failures are retried without a limit, only a batch the store took or the
+/// pipeline dead-lettered is checkpointed. Returns once the writer is gone and everything it
+/// wrote is handled, or when a batch can't be persisted at all: it stays in the log for the
+/// next start.
+pub(crate) fn run_reader<T: Store>(pipeline: &Pipeline<T>, mut reader: WalReader, appended: Receiver<()>) {
+    let config = pipeline.config();
+    let batch_size = config.batch_size.max(1);
+    let mut writer_alive = true;
+
+    loop {
+        let mut records = match reader.read(batch_size) {
+            Ok(r) => r,
+            Err(err) => {
+                error!("failed to read log: {}", err);
+                thread::sleep(config.retry_delay);
+                continue;
+            }
+        };
+
+        if records.is_empty() {
+            if !writer_alive {
+                info!("Log reader stopped");
+                return;
+            }
+            if appended.recv().is_err() {
+                writer_alive = false;
+            }
+            continue;
+        }
+
+        // wait a bit for the batch to fill up
+        let deadline = Instant::now() + config.batch_timeout;
+        while writer_alive && records.len() < batch_size {
+            match appended.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => writer_alive = false,
+            }
+            match reader.read(batch_size - records.len()) {
+                Ok(r) => records.extend(r),
+                Err(err) => {
+                    error!("failed to read log: {}", err);
+                    break;
+                }
+            }
+        }
+
+        let first_seq = records.first().map(|(s, _)| *s).unwrap_or(0);
+        let last_seq = records.last().map(|(s, _)| *s).unwrap_or(0);
+        let batch: Vec<GeoPacket> = records.into_iter().map(|(_, p)| p).collect();
+        if !pipeline.save_logged(&batch) {
+            error!("Log reader stopped, records from {} on are kept for the next start", first_seq);
+            return;
+        }
+
+        if let Err(err) = reader.commit(last_seq) {
+            error!("failed to write log checkpoint: {}", err);
+        }
+    }
+}
+
+fn checksum(seq: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+// Reads the next record. On a partial or corrupted record the file is left at its start
+// and None is returned.
+fn read_record(file: &mut File) -> io::Result<Option<(u64, GeoPacket)>> {
+    let start = file.stream_position()?;
+
+    let mut header = [0u8; HEADER_LEN];
+    if !read_full(file, &mut header)? {
+        file.seek(SeekFrom::Start(start))?;
+        return Ok(None);
+    }
+
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let crc = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut seq_bytes = [0u8; 8];
+    seq_bytes.copy_from_slice(&header[8..]);
+    let seq = u64::from_le_bytes(seq_bytes);
+
+    let mut payload = vec![0u8; len];
+    if !read_full(file, &mut payload)? || checksum(seq, &payload) != crc {
+        file.seek(SeekFrom::Start(start))?;
+        return Ok(None);
+    }
+
+    match serde_json::from_slice(&payload) {
+        Ok(p) => Ok(Some((seq, p))),
+        Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+    }
+}
+
+// Like read_exact, but returns false instead of failing on EOF.
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => return Ok(false),
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(true)
+}
+
+// Returns the seq of the last valid record and the length of the valid part of the segment.
+fn scan_segment(path: &Path) -> io::Result<(Option<u64>, u64)> {
+    let mut file = File::open(path)?;
+    let mut last_seq = None;
+    while let Some((seq, _)) = read_record(&mut file)? {
+        last_seq = Some(seq);
+    }
+    Ok((last_seq, file.stream_position()?))
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_seq, SEGMENT_EXT))
+}
+
+fn create_segment(dir: &Path, first_seq: u64) -> io::Result<File> {
+    let file = OpenOptions::new().create(true).append(true).open(segment_path(dir, first_seq))?;
+    // make the new file itself survive a crash
+    File::open(dir)?.sync_all()?;
+    Ok(file)
+}
+
+// First seqs of all segments, oldest first.
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXT) {
+            continue;
+        }
+        if let Some(seq) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            segments.push(seq);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+fn read_checkpoint(dir: &Path) -> io::Result<u64> {
+    match fs::read_to_string(dir.join(CHECKPOINT_FILE)) {
+        Ok(s) => s.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(err) => Err(err),
+    }
+}
+
+fn write_checkpoint(dir: &Path, seq: u64) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", CHECKPOINT_FILE));
+    let mut file = File::create(&tmp)?;
+    file.write_all(seq.to_string().as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(CHECKPOINT_FILE))
+}
+
+#[test]
+fn test_wal_replay() {
+    use crate::store::test_packet;
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = WalConfig::new(dir.path().to_str().unwrap());
+    config.segment_size = 200;
+
+    let (mut writer, mut reader) = open(&config).unwrap();
+    for imei in &["1", "2", "3", "4", "5"] {
+        writer.append(&test_packet(imei)).unwrap();
+    }
+    assert!(writer.commit().unwrap());
+    assert!(list_segments(dir.path()).unwrap().len() > 1);
+
+    let records = reader.read(2).unwrap();
+    assert_eq!(records.iter().map(|(s, _)| *s).collect::<Vec<_>>(), vec![1, 2]);
+    reader.commit(2).unwrap();
+    drop(writer);
+
+    // crash in the middle of the next record
+    let last = *list_segments(dir.path()).unwrap().last().unwrap();
+    let mut file = OpenOptions::new().append(true).open(segment_path(dir.path(), last)).unwrap();
+    file.write_all(&[42, 0, 0, 0, 1, 2]).unwrap();
+
+    let (mut writer, mut reader) = open(&config).unwrap();
+    writer.append(&test_packet("6")).unwrap();
+    writer.commit().unwrap();
+
+    let records = reader.read(10).unwrap();
+    assert_eq!(records.iter().map(|(s, _)| *s).collect::<Vec<_>>(), vec![3, 4, 5, 6]);
+    assert_eq!(records[0].1, test_packet("3"));
+    assert!(reader.read(10).unwrap().is_empty());
+
+    reader.commit(6).unwrap();
+    assert_eq!(list_segments(dir.path()).unwrap().len(), 1);
+}
+
+#[test]
+fn test_wal_reader_outlasts_retries() {
+    use std::cell::Cell;
+    use std::sync::mpsc::{channel, Sender};
+    use crate::pipeline::PipelineConfig;
+    use crate::store::StoreError;
+    use crate::store::test_packet;
+
+    // down for longer than the pipeline retries a batch
+    struct FlakyStore {
+        failures: Cell<u32>,
+        saved: Sender<String>,
+    }
+
+    impl Store for FlakyStore {
+        fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+            if self.failures.get() > 0 {
+                self.failures.set(self.failures.get() - 1);
+                return Err(StoreError::Transient(String::from("db is down")));
+            }
+            self.saved.send(p.imei).unwrap();
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = WalConfig::new(dir.path().to_str().unwrap());
+    let (mut writer, reader) = open(&config).unwrap();
+    writer.append(&test_packet("1")).unwrap();
+    writer.commit().unwrap();
+    drop(writer);
+
+    let (saved_sender, saved) = channel();
+    let pipeline_config = PipelineConfig {
+        retry_delay: Duration::from_millis(1),
+        max_retry_delay: Duration::from_millis(2),
+        max_retries: 2,
+        ..PipelineConfig::default()
+    };
+    let mut pipeline = Pipeline::new(FlakyStore { failures: Cell::new(5), saved: saved_sender }, pipeline_config);
+    let (dead_sender, dead) = channel();
+    pipeline.set_dead_letter(FlakyStore { failures: Cell::new(0), saved: dead_sender });
+    let (appended_sender, appended) = crossbeam_channel::unbounded();
+    drop(appended_sender);
+    run_reader(&pipeline, reader, appended);
+
+    assert_eq!(saved.try_iter().collect::<Vec<_>>(), vec!["1"]);
+    assert!(dead.try_iter().next().is_none());
+    let (_, mut reader) = open(&config).unwrap();
+    assert!(reader.read(10).unwrap().is_empty());
+}
+
+#[test]
+fn test_wal_reader_keeps_rejected_batch() {
+    use std::sync::mpsc::{channel, Sender};
+    use crate::pipeline::PipelineConfig;
+    use crate::store::StoreError;
+    use crate::store::test_packet;
+
+    // refuses anything while it is broken
+    struct BrokenStore {
+        broken: bool,
+        saved: Sender<String>,
+    }
+
+    impl Store for BrokenStore {
+        fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+            if self.broken {
+                return Err(StoreError::Permanent(String::from("schema mismatch")));
+            }
+            self.saved.send(p.imei).unwrap();
+            Ok(())
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = WalConfig::new(dir.path().to_str().unwrap());
+    let (mut writer, reader) = open(&config).unwrap();
+    for imei in &["1", "2"] {
+        writer.append(&test_packet(imei)).unwrap();
+    }
+    writer.commit().unwrap();
+    drop(writer);
+
+    let run = |broken: bool, reader: WalReader| {
+        let (saved_sender, saved) = channel();
+        let pipeline = Pipeline::new(BrokenStore { broken, saved: saved_sender }, PipelineConfig::default());
+        let (appended_sender, appended) = crossbeam_channel::unbounded();
+        drop(appended_sender);
+        run_reader(&pipeline, reader, appended);
+        saved.try_iter().collect::<Vec<_>>()
+    };
+
+    // without a dead-letter sink the rejected batch stays in the log and is saved after a restart
+    assert!(run(true, reader).is_empty());
+    let (_, reader) = open(&config).unwrap();
+    assert_eq!(run(false, reader), vec!["1", "2"]);
+    let (_, mut reader) = open(&config).unwrap();
+    assert!(reader.read(10).unwrap().is_empty());
+}
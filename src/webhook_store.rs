@@ -0,0 +1,154 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::store::{GeoPacket, Store, StoreError};
+
+/// Header with the hex HMAC-SHA256 of the request body, prefixed with `sha256=`.
+pub const SIGNATURE_HEADER: &str = "X-Wialon-Signature";
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// Key for the body signature, requests are not signed without it.
+    pub secret: Option<String>,
+    /// Packets per request, bigger batches are split.
+    pub batch_size: usize,
+    /// Requests to the endpoint running at the same time.
+    pub concurrency: usize,
+    pub timeout: Duration,
+    pub headers: Vec<(String, String)>,
+}
+
+impl WebhookConfig {
+    pub fn new(url: &str) -> WebhookConfig {
+        WebhookConfig {
+            url: url.to_string(),
+            secret: None,
+            batch_size: 100,
+            concurrency: 2,
+            timeout: Duration::from_secs(10),
+            headers: Vec::new(),
+        }
+    }
+}
+
+/// POSTs packets as JSON arrays to an HTTP endpoint.
+///
+/// A batch is saved once every request of it was answered with a 2xx status. Server errors, 429 and
+/// network failures are transient and left to the pipeline retries, other statuses fail the batch
+/// permanently. When a batch fails after some of its requests went through, the retry sends those again.
+pub struct WebhookStore {
+    agent: ureq::Agent,
+    config: WebhookConfig,
+}
+
+impl WebhookStore {
+    pub fn new(config: WebhookConfig) -> WebhookStore {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(config.timeout)
+            .max_idle_connections_per_host(config.concurrency.max(1))
+            .build();
+        WebhookStore { agent, config }
+    }
+
+    fn post(&self, chunk: &[GeoPacket]) -> Result<(), StoreError> {
+        let body = serde_json::to_vec(chunk).map_err(|e| StoreError::Permanent(e.to_string()))?;
+        self.send(&body)
+    }
+
+    fn send(&self, body: &[u8]) -> Result<(), StoreError> {
+        let mut request = self.agent.post(&self.config.url).set("Content-Type", "application/json");
+        if let Some(secret) = &self.config.secret {
+            request = request.set(SIGNATURE_HEADER, &format!("sha256={}", sign(secret, body)));
+        }
+        for (name, value) in &self.config.headers {
+            request = request.set(name, value);
+        }
+
+        match request.send_bytes(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, _)) if code == 429 || code >= 500 => {
+                Err(StoreError::Transient(format!("webhook answered {}", code)))
+            }
+            Err(ureq::Error::Status(code, _)) => Err(StoreError::Permanent(format!("webhook answered {}", code))),
+            Err(err) => Err(StoreError::Transient(err.to_string())),
+        }
+    }
+}
+
+impl Store for WebhookStore {
+    fn save(&self, p: GeoPacket) -> Result<(), StoreError> {
+        self.save_batch(&[p])
+    }
+
+    fn save_batch(&self, batch: &[GeoPacket]) -> Result<(), StoreError> {
+        let chunks = Mutex::new(batch.chunks(self.config.batch_size.max(1)));
+        let senders = self.config.concurrency.max(1).min(batch.len());
+
+        // every sender takes the next chunk until none are left, the first error is reported
+        let results: Vec<Result<(), StoreError>> = thread::scope(|s| {
+            let handles: Vec<_> = (0..senders).map(|_| s.spawn(|| {
+                loop {
+                    let chunk = chunks.lock().unwrap().next();
+                    match chunk {
+                        Some(c) => self.post(c)?,
+                        None => return Ok(()),
+                    }
+                }
+            })).collect();
+            handles.into_iter()
+                .map(|h| h.join().unwrap_or_else(|_| Err(StoreError::Permanent(String::from("webhook sender panicked")))))
+                .collect()
+        });
+        results.into_iter().collect()
+    }
+}
+
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[test]
+fn test_webhook_store() {
+    use std::net::TcpListener;
+    use crate::store::{test_http_server, test_packet};
+
+    // the first request of the batch is refused for a while, the one after the batch is invalid
+    let (url, received) = test_http_server(vec![("503 Service Unavailable", ""), ("200 OK", ""), ("200 OK", ""),
+                                                ("200 OK", ""), ("400 Bad Request", "unknown field")]);
+
+    assert_eq!(sign("key", b"The quick brown fox jumps over the lazy dog"),
+               "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+
+    let mut config = WebhookConfig::new(&format!("{}/positions", url));
+    config.secret = Some(String::from("secret"));
+    config.batch_size = 2;
+    let store = WebhookStore::new(config);
+
+    let batch: Vec<GeoPacket> = (0..3).map(|i| test_packet(&i.to_string())).collect();
+    // the 503 is left to the pipeline, which sends the whole batch again
+    assert!(matches!(store.save_batch(&batch), Err(StoreError::Transient(_))));
+    received.try_iter().for_each(drop);
+    store.save_batch(&batch).unwrap();
+
+    let mut imeis = Vec::new();
+    for (headers, body) in received.try_iter() {
+        assert!(headers.contains(&format!("x-wialon-signature: sha256={}", sign("secret", &body))));
+        let packets: Vec<GeoPacket> = serde_json::from_slice(&body).unwrap();
+        imeis.extend(packets.into_iter().map(|p| p.imei));
+    }
+    imeis.sort();
+    assert_eq!(imeis, vec!["0", "1", "2"]);
+
+    assert!(matches!(store.save(test_packet("3")), Err(StoreError::Permanent(_))));
+
+    // nothing listening is worth another try
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let store = WebhookStore::new(WebhookConfig::new(&format!("http://{}/positions", closed)));
+    assert!(matches!(store.save(test_packet("3")), Err(StoreError::Transient(_))));
+}
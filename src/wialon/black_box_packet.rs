@@ -0,0 +1,50 @@
+use std::convert::TryFrom;
+use std::fmt;
+use serde::Serialize;
+
+use crate::wialon::{DataPacket, Packet, PacketTypes, ShortDataPacket};
+
+// Fields of a message in the short data format, longer ones are full data messages.
+const SHORT_DATA_FIELDS: usize = 10;
+
+/// Positions a device collected while it had no connection, sent in one `#B#` packet.
+#[derive(Debug, Serialize)]
+pub struct BlackBoxPacket<'a> {
+    /// SD and D packets, oldest first.
+    pub messages: Vec<Packet<'a>>,
+}
+
+impl<'a> TryFrom<&'a str> for BlackBoxPacket<'a> {
+    type Error = &'static str;
+
+    fn try_from(body: &'a str) -> Result<Self, Self::Error> {
+        let messages = body.split('|').filter(|m| !m.is_empty()).map(|m| {
+            let fields: Vec<&'a str> = m.split(';').collect();
+            Ok(if fields.len() > SHORT_DATA_FIELDS {
+                Packet { ptype: String::from("D"), body: PacketTypes::DataPacket(DataPacket::try_from(fields)?) }
+            } else {
+                Packet { ptype: String::from("SD"), body: PacketTypes::ShortDataPacket(ShortDataPacket::try_from(fields)?) }
+            })
+        }).collect::<Result<_, Self::Error>>()?;
+
+        Ok(BlackBoxPacket { messages })
+    }
+}
+
+impl fmt::Display for BlackBoxPacket<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} messages", self.messages.len())
+    }
+}
+
+#[test]
+fn test_black_box_packet_body() {
+    let msg = BlackBoxPacket::try_from("280421;055220;5355.09260;N;02732.40990;E;0;0;300;7|\
+                                    280421;055429;5355.09260;N;02732.40990;E;0;0;300;7;22;5;0;;NA;test1:1:1|").unwrap();
+
+    assert_eq!(msg.messages.len(), 2);
+    assert_eq!(msg.messages[0].ptype, "SD");
+    assert_eq!(msg.messages[1].ptype, "D");
+    assert_eq!(msg.messages[1].get_params().len(), 1);
+    assert!(BlackBoxPacket::try_from("280421;055220;5355.09260;N|").is_err());
+}
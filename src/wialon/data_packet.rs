@@ -1,12 +1,15 @@
 // use core::any::Any;
 use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt;
+use serde::Serialize;
 
 // use crate::wialon::BodyParser;
-use crate::wialon::short_data_packet::ShortDataPacket;
+use crate::wialon::short_data_packet::{field, ShortDataPacket};
 
 
-#[derive(Debug, PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone, Serialize)]
+#[serde(untagged)]
 pub enum Params<'a> {
     Int(i32),
     Float(f64),
@@ -15,11 +18,15 @@ pub enum Params<'a> {
 
 impl fmt::Display for Params<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            Params::Int(v) => write!(f, "{}", v),
+            Params::Float(v) => write!(f, "{}", v),
+            Params::String(v) => write!(f, "{}", v),
+        }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct DataPacket<'a> {
     pub spd: ShortDataPacket,
     pub hdop: f64,
@@ -30,36 +37,43 @@ pub struct DataPacket<'a> {
     pub params: HashMap<String, Params<'a>>,
 }
 
-impl<'a> From<Vec<&'a str>> for DataPacket<'a> {
-    fn from(body: Vec<&'a str>) -> Self {
-        let hdop = body[10].to_string().parse().unwrap();
-        let inputs = body[11].to_string().parse().unwrap();
-        let outputs = body[12].to_string().parse().unwrap();
+impl<'a> TryFrom<Vec<&'a str>> for DataPacket<'a> {
+    type Error = &'static str;
 
-        let params: Vec<&'a str> = body[15].split(",").collect();
-        let mut params_map = HashMap::new();
+    fn try_from(body: Vec<&'a str>) -> Result<Self, Self::Error> {
+        if body.len() < 16 {
+            return Err("Не хватает полей пакета");
+        }
+        let hdop = field(body[10])?;
+        let inputs = field(body[11])?;
+        let outputs = field(body[12])?;
 
-        for p in params {
+        let mut params_map = HashMap::new();
+        // a device without parameters sends NA or nothing
+        for p in body[15].split(",").filter(|p| !p.is_empty() && *p != "NA") {
             let param_tuple: Vec<&'a str>  = p.split(":").collect();
+            if param_tuple.len() < 3 {
+                return Err("Не корректный параметр");
+            }
             let param_type = param_tuple[1];
             let v: Params = match param_type {
-                "1" => Params::Int(param_tuple[2].to_string().parse().unwrap()),
-                "2" => Params::Float(param_tuple[2].to_string().parse().unwrap()),
+                "1" => Params::Int(param_tuple[2].parse().map_err(|_| "Не корректный параметр")?),
+                "2" => Params::Float(param_tuple[2].parse().map_err(|_| "Не корректный параметр")?),
                 "3" => Params::String(param_tuple[2]),
                 _ => Params::Int(0),
             };
             params_map.insert(param_tuple[0].to_string(), v);   
         }
 
-        DataPacket {
-            spd: ShortDataPacket::from(body[0..10].to_vec()),
+        Ok(DataPacket {
+            spd: ShortDataPacket::try_from(body[0..10].to_vec())?,
             hdop,
             inputs,
             outputs,
             adc: body[13].to_string(),
             ibutton: body[14].to_string(),
             params: params_map,
-        }
+        })
     }
 }
 
@@ -88,12 +102,12 @@ fn test_short_data_packet_body() {
 
     let test_data = vec!("280421", "055429", "5355.09260", "N", "02732.40990",
                          "E", "0", "0", "300", "7", "22", "5", "0", "", "NA", "test1:1:1,var:2:4.5,texttest:3:1");
-    let msg = DataPacket::from(test_data);
+    let msg = DataPacket::try_from(test_data).unwrap();
 
     let test_ts = NaiveDateTime::parse_from_str("280421055429", "%d%m%y%H%M%S").unwrap();
     assert_eq!(msg.spd.timestamp, test_ts);
-    assert_eq!(msg.spd.lon, 53.5509260);
-    assert_eq!(msg.spd.lat, 27.3240990);
+    assert!((msg.spd.lat - 53.918210).abs() < 1e-9);
+    assert!((msg.spd.lon - 27.540165).abs() < 1e-9);
     assert_eq!(msg.spd.speed, 0);
     assert_eq!(msg.spd.course, 0);
     assert_eq!(msg.spd.height, 300);
@@ -106,4 +120,11 @@ fn test_short_data_packet_body() {
     
     let p = msg.params.get("var").unwrap();
     assert_eq!(*p, Params::Float(4.5));
-}
\ No newline at end of file
+
+    let test_data = vec!("280421", "055429", "5355.09260", "N", "02732.40990",
+                         "E", "0", "0", "300", "7", "NA", "NA", "NA", "", "NA", "NA");
+    assert!(DataPacket::try_from(test_data).unwrap().params.is_empty());
+    let test_data = vec!("280421", "055429", "5355.09260", "N", "02732.40990",
+                         "E", "0", "0", "300", "7", "22", "5", "0", "", "NA", "test1:1");
+    assert!(DataPacket::try_from(test_data).is_err());
+}
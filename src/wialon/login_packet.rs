@@ -1,14 +1,21 @@
+use std::convert::TryFrom;
 use std::fmt;
+use serde::Serialize;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct LoginPacket {
     pub imei: String,
     pub password: String,
 }
 
-impl From<Vec<&str>> for LoginPacket {
-    fn from(body: Vec<&str>) -> Self {
-        LoginPacket{imei: body[0].to_string(), password: body[1].to_string()}
+impl TryFrom<Vec<&str>> for LoginPacket {
+    type Error = &'static str;
+
+    fn try_from(body: Vec<&str>) -> Result<Self, Self::Error> {
+        match body.as_slice() {
+            [imei, password, ..] => Ok(LoginPacket{imei: imei.to_string(), password: password.to_string()}),
+            _ => Err("Не хватает полей пакета"),
+        }
     }
 }
 
@@ -27,8 +34,9 @@ impl PartialEq for LoginPacket {
 #[test]
 fn test_login_packet_body() {
     let test_data = vec!("1", "1");
-    let msg = LoginPacket::from(test_data);
+    let msg = LoginPacket::try_from(test_data).unwrap();
 
     assert_eq!(msg.imei, "1");
     assert_eq!(msg.password, "1");
+    assert!(LoginPacket::try_from(vec!("1")).is_err());
 }
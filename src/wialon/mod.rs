@@ -1,24 +1,35 @@
+use std::convert::TryFrom;
 use std::fmt;
 use std::str;
+use serde::Serialize;
 
 mod short_data_packet;
 
 pub use short_data_packet::ShortDataPacket;
 
 mod data_packet;
-use data_packet::{DataPacket, Params};
+use data_packet::DataPacket;
+pub use data_packet::Params;
 
 mod login_packet;
 use login_packet::LoginPacket;
 
+mod black_box_packet;
+pub use black_box_packet::BlackBoxPacket;
+
 mod response_packet;
 pub use response_packet::ResponsePacket;
+pub(crate) use response_packet::PASSWORD_ERROR;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+#[allow(clippy::enum_variant_names)]
 enum PacketTypes<'a> {
     LoginPacket(LoginPacket),
     ShortDataPacket(ShortDataPacket),
     DataPacket(DataPacket<'a>),
+    BlackBoxPacket(BlackBoxPacket<'a>),
+    PingPacket,
 }
 impl fmt::Display for PacketTypes<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -26,7 +37,7 @@ impl fmt::Display for PacketTypes<'_> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Packet<'a> {
     pub ptype: String,
     body: PacketTypes<'a>,
@@ -38,36 +49,52 @@ impl<'a> Packet<'a> {
             "L" => String::from("AL"),
             "SD" => String::from("ASD"),
             "D" => String::from("AD"),
+            "B" => String::from("AB"),
+            "P" => String::from("AP"),
             _ => return Err("Неизвестный тип пакета")
         };
 
+        // a black box is answered with the number of accepted messages
+        let code = match &self.body {
+            PacketTypes::BlackBoxPacket(b) if result_code == 1 => b.messages.len().to_string(),
+            _ => result_code.to_string(),
+        };
+
         Ok(ResponsePacket{
             ptype,
-            code: result_code
+            code,
         })
     }
-    pub fn from(msg: &'a [u8]) -> Result<Packet, &str> {
-        let s = str::from_utf8(msg).unwrap();
+    /// Answer asking the device to send the packet again later, when the server can't take it now.
+    pub fn rejection(&self) -> Result<ResponsePacket, &str> {
+        self.response(1).map(|r| ResponsePacket::retry(&r.ptype))
+    }
+    pub fn from(msg: &'a [u8]) -> Result<Packet<'a>, &'a str> {
+        let s = str::from_utf8(msg).map_err(|_| "Не корректное сообщение")?;
         if !(s.starts_with("#") && s.ends_with("\r\n")) {
             return Err("Не корректное сообщение");
         }
 
         let t = s;
-        let parts: Vec<&'a str> = t.trim_start_matches('#').trim_end().split("#").collect();
-        let packet_type = parts[0];
-        let body_parts: Vec<&str> = parts[1].split(";").collect();
+        let (packet_type, body) = match t.trim_start_matches('#').trim_end().split_once("#") {
+            Some(parts) => parts,
+            None => return Err("Не корректное сообщение"),
+        };
+        let body_parts: Vec<&str> = body.split(";").collect();
 
         let b: PacketTypes = match packet_type {
-            "L" => PacketTypes::LoginPacket(LoginPacket::from(body_parts)),
-            "SD" => PacketTypes::ShortDataPacket(ShortDataPacket::from(body_parts)),
-            "D" => PacketTypes::DataPacket(DataPacket::from(body_parts)),
+            "L" => PacketTypes::LoginPacket(LoginPacket::try_from(body_parts)?),
+            "SD" => PacketTypes::ShortDataPacket(ShortDataPacket::try_from(body_parts)?),
+            "D" => PacketTypes::DataPacket(DataPacket::try_from(body_parts)?),
+            "B" => PacketTypes::BlackBoxPacket(BlackBoxPacket::try_from(body)?),
+            "P" => PacketTypes::PingPacket,
             _ => return Err("Не корректное сообщение"),
         };
 
-        return Ok(Packet {
+        Ok(Packet {
             ptype: packet_type.to_string(),
             body: b,
-        });
+        })
     }
 
     pub fn is_auth_packet(&self) -> bool {
@@ -77,27 +104,42 @@ impl<'a> Packet<'a> {
     pub fn get_auth_data(&self) -> Result<&LoginPacket, &str> {
         let p: &LoginPacket = match &self.body {
             PacketTypes::LoginPacket(b) => b,
-            PacketTypes::ShortDataPacket(_) => return Err("Не верный тип пакета"),
-            PacketTypes::DataPacket(_) => return Err("Не верный тип пакета"),
+            _ => return Err("Не верный тип пакета"),
         };
         Ok(p)
     }
 
     pub fn get_navigate_data(&self) -> Result<&ShortDataPacket, &str> {
         let p: &ShortDataPacket = match &self.body {
-            PacketTypes::LoginPacket(_) => return Err("Не верный тип пакета"),
             PacketTypes::ShortDataPacket(b) => b,
             PacketTypes::DataPacket(b) => &b.spd,
+            _ => return Err("Не верный тип пакета"),
         };
         Ok(p)
     }
 
-    #[warn(dead_code)]
-    pub fn get_extra_param(&self, param_name: &str) -> Result<&Params, &str> {
+    /// Positions of the packet: the packet itself for SD and D, the messages of a black box,
+    /// nothing for login and ping packets.
+    pub fn get_messages(&self) -> Vec<&Packet<'a>> {
+        match &self.body {
+            PacketTypes::ShortDataPacket(_) | PacketTypes::DataPacket(_) => vec![self],
+            PacketTypes::BlackBoxPacket(b) => b.messages.iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Extra parameters of a D packet, empty for other packets.
+    pub fn get_params(&self) -> Vec<(&str, &Params<'_>)> {
+        match &self.body {
+            PacketTypes::DataPacket(b) => b.params.iter().map(|(k, v)| (k.as_str(), v)).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn get_extra_param(&self, param_name: &str) -> Result<&Params<'_>, &str> {
         let p: &DataPacket<'_> = match &self.body {
-            PacketTypes::LoginPacket(_) => return Err("Пакет не содержит экстра данных"),
-            PacketTypes::ShortDataPacket(_) => return Err("Пакет не содержит экстра данных"),
             PacketTypes::DataPacket(b) => b,
+            _ => return Err("Пакет не содержит экстра данных"),
         };
 
         let r: &Params = match p.params.get(param_name) {
@@ -126,6 +168,14 @@ fn parsing_packets() {
         Err(err) => assert_eq!("Не корректное сообщение", err),
     }
 
+    // malformed fields are errors, not panics
+    for msg in ["#SD#280421;055447;5355.09260;N\r\n", "#SD#280421;0554;5355.09260;N;02732.40990;E;60;0;300;7\r\n",
+                "#D#280421;055500;5355.09260;N;02732.40990;E;60;0;300;7;x;5;5120;;eee;NA\r\n", "#L#1\r\n",
+                "#B#280421;055220;53|\r\n", "#SD\r\n"] {
+        assert!(Packet::from(msg.as_bytes()).is_err(), "{}", msg);
+    }
+    assert!(Packet::from(&[0x23, 0xff, 0x23, 0x0d, 0x0a]).is_err());
+
     match Packet::from("#L#1;1\r\n".as_bytes()) {
         Ok(p) => {
             assert_eq!(p.ptype, "L");
@@ -148,8 +198,8 @@ fn parsing_packets() {
                 msg.timestamp,
                 NaiveDateTime::parse_from_str("280421055447", "%d%m%y%H%M%S").unwrap()
             );
-            assert_eq!(msg.lon, 53.5509260);
-            assert_eq!(msg.lat, 27.3240990);
+            assert!((msg.lat - 53.918210).abs() < 1e-9);
+            assert!((msg.lon - 27.540165).abs() < 1e-9);
             assert_eq!(msg.speed, 60);
             assert_eq!(msg.course, 0);
             assert_eq!(msg.height, 300);
@@ -194,4 +244,22 @@ fn response_packets() {
         }
         Err(err) => panic!("{:?}", err)
     }
+
+    match Packet::from("#B#280421;055220;5355.09260;N;02732.40990;E;0;0;300;7|280421;055447;5355.09260;N;02732.40990;E;60;0;300;7|\r\n".as_bytes()) {
+        Ok(p) => {
+            assert_eq!(p.get_messages().len(), 2);
+            assert_eq!(p.response(1).unwrap().to_string(), "#AB#2\r\n");
+            assert_eq!(p.response(0).unwrap().to_string(), "#AB#0\r\n");
+            assert_eq!(p.rejection().unwrap().to_string(), "#AB#0\r\n");
+        }
+        Err(err) => panic!("{:?}", err)
+    }
+
+    match Packet::from("#P#\r\n".as_bytes()) {
+        Ok(p) => {
+            assert!(p.get_messages().is_empty());
+            assert_eq!(p.response(1).unwrap().to_string(), "#AP#\r\n");
+        }
+        Err(err) => panic!("{:?}", err)
+    }
 }
\ No newline at end of file
@@ -1,13 +1,81 @@
 use std::fmt;
 
-#[derive(Debug)]
+/// Code of `#AL#01`, a wrong password.
+pub(crate) const PASSWORD_ERROR: &str = "01";
+
+// The protocol has no "busy" code. Data the server can't take now is answered with the checksum
+// error of Wialon IPS 2.0 (`#ASD#13`, `#AD#16`), after which the device sends the packet again as it
+// is, and a black box with the number of messages taken (`#AB#0`). Every other code on data means the
+// packet itself is wrong: -1 its structure, 0 its time, 10-15 one of its fields.
+const RETRY_SHORT_DATA: &str = "13";
+const RETRY_DATA: &str = "16";
+const RETRY_BLACK_BOX: &str = "0";
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ResponsePacket {
-    pub ptype: String,    
-    pub code: i8,
+    pub ptype: String,
+    /// Result code as sent, the number of accepted messages for a black box. Kept as text because
+    /// `#AL#01` (wrong password) and `#AL#1` (success) only differ in the leading zero.
+    pub code: String,
+}
+
+impl ResponsePacket {
+    /// Reads an answer of the server such as `#ASD#1\r\n`, the line end is optional.
+    pub fn parse(msg: &str) -> Result<ResponsePacket, &'static str> {
+        let parts: Vec<&str> = match msg.trim_end().strip_prefix('#') {
+            Some(s) => s.split('#').collect(),
+            None => return Err("Не корректное сообщение"),
+        };
+
+        let (ptype, code) = match parts.as_slice() {
+            [ptype, code] if ptype.starts_with('A') => (*ptype, *code),
+            _ => return Err("Не корректное сообщение"),
+        };
+        if !(ptype == "AP" && code.is_empty()) && code.parse::<i32>().is_err() {
+            return Err("Не корректный код ответа");
+        }
+
+        Ok(ResponsePacket { ptype: ptype.to_string(), code: code.to_string() })
+    }
+
+    /// Answer telling the device to send the data of this answer type again later.
+    pub(crate) fn retry(ptype: &str) -> ResponsePacket {
+        let code = match ptype {
+            "ASD" => RETRY_SHORT_DATA,
+            "AD" => RETRY_DATA,
+            _ => RETRY_BLACK_BOX,
+        };
+        ResponsePacket { ptype: ptype.to_string(), code: code.to_string() }
+    }
+
+    /// Whether the server didn't take the data now but expects it again, see `retry`.
+    pub fn is_retry(&self) -> bool {
+        matches!((self.ptype.as_str(), self.code.as_str()),
+            ("ASD", RETRY_SHORT_DATA) | ("AD", RETRY_DATA) | ("AB", RETRY_BLACK_BOX))
+    }
 }
 
 impl fmt::Display for ResponsePacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "#{}#{}\r\n", self.ptype, self.code)
+        match self.ptype.as_str() {
+            // ping is answered without a code
+            "AP" => write!(f, "#AP#\r\n"),
+            _ => write!(f, "#{}#{}\r\n", self.ptype, self.code),
+        }
+    }
+}
+
+#[test]
+fn test_response_packet_parse() {
+    for answer in ["#AL#1\r\n", "#AL#0\r\n", "#AL#01\r\n", "#AL#10\r\n", "#ASD#-1\r\n", "#AB#15\r\n", "#AP#\r\n"] {
+        assert_eq!(ResponsePacket::parse(answer).unwrap().to_string(), answer);
     }
+    assert_eq!(ResponsePacket::parse("#AL#01").unwrap().code, PASSWORD_ERROR);
+    assert_eq!(ResponsePacket::parse("#AD#1").unwrap(), ResponsePacket { ptype: String::from("AD"), code: String::from("1") });
+    assert!(ResponsePacket::parse("#ASD#13").unwrap().is_retry());
+    assert!(!ResponsePacket::parse("#ASD#0").unwrap().is_retry());
+    assert_eq!(ResponsePacket::retry("AB").to_string(), "#AB#0\r\n");
+    assert!(ResponsePacket::parse("#SD#1\r\n").is_err());
+    assert!(ResponsePacket::parse("#ASD#x\r\n").is_err());
+    assert!(ResponsePacket::parse("wewe").is_err());
 }
@@ -1,7 +1,10 @@
 use chrono::NaiveDateTime;
+use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
+use serde::Serialize;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct ShortDataPacket {
     pub timestamp: NaiveDateTime,
     pub lat: f64,
@@ -12,35 +15,58 @@ pub struct ShortDataPacket {
     pub sats: i16,
 }
 
-impl From<Vec<&str>> for ShortDataPacket {
-    fn from(body: Vec<&str>) -> Self {
-        let mut ts: String = body[0].to_string();
-        ts.push_str(body[1]);
+impl TryFrom<Vec<&str>> for ShortDataPacket {
+    type Error = &'static str;
 
-        let mut lon: f64 = body[2].to_string().parse().unwrap();
-        lon = lon / 100.0;
-        if body[3] != "N" {
-            lon = lon * -1.0
+    fn try_from(body: Vec<&str>) -> Result<Self, Self::Error> {
+        if body.len() < 10 {
+            return Err("Не хватает полей пакета");
         }
+        let mut ts: String = body[0].to_string();
+        ts.push_str(body[1]);
+        let timestamp = NaiveDateTime::parse_from_str(ts.as_str(), "%d%m%y%H%M%S")
+            .map_err(|_| "Не корректное время")?;
 
-        let mut lat: f64 = body[4].to_string().parse().unwrap();
-        lat = lat / 100.0;
-        if body[5] != "E" {
-            lon = lon * -1.0
-        }
+        let lat = coordinate(body[2], body[3], "N", "S")?;
+        let lon = coordinate(body[4], body[5], "E", "W")?;
 
-        ShortDataPacket {
-            timestamp: NaiveDateTime::parse_from_str(ts.as_str(), "%d%m%y%H%M%S").unwrap(),
+        Ok(ShortDataPacket {
+            timestamp,
             lat,
             lon,
-            speed: body[6].to_string().parse().unwrap(),
-            course: body[7].to_string().parse().unwrap(),
-            height: body[8].to_string().parse().unwrap(),
-            sats: body[9].to_string().parse().unwrap(),
-        }
+            speed: field(body[6])?,
+            course: field(body[7])?,
+            height: field(body[8])?,
+            sats: field(body[9])?,
+        })
     }
 }
 
+/// Degrees of a `DDMM.MMMM` (`DDDMM.MMMM` for longitude) field, negative in the southern and
+/// western hemispheres.
+fn coordinate(value: &str, hemisphere: &str, positive: &str, negative: &str) -> Result<f64, &'static str> {
+    let value: f64 = value.parse().map_err(|_| "Не корректные координаты")?;
+    let degrees = (value / 100.0).trunc();
+    let minutes = value - degrees * 100.0;
+    if !(0.0..60.0).contains(&minutes) {
+        return Err("Не корректные координаты");
+    }
+    // rounded far below the precision of the minutes, so the decimal value prints without float noise
+    let degrees = ((degrees + minutes / 60.0) * 1e9).round() / 1e9;
+    match hemisphere {
+        h if h == positive => Ok(degrees),
+        h if h == negative => Ok(-degrees),
+        _ => Err("Не корректные координаты"),
+    }
+}
+
+/// Numeric field of a packet, `NA` (no value) reads as zero.
+pub(crate) fn field<T: FromStr + Default>(value: &str) -> Result<T, &'static str> {
+    match value {
+        "NA" => Ok(T::default()),
+        v => v.parse().map_err(|_| "Не корректное поле пакета"),
+    }
+}
 
 impl fmt::Display for ShortDataPacket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -64,21 +90,32 @@ impl PartialEq for ShortDataPacket {
 #[test]
 fn test_short_data_packet_body() {
     let test_data = vec!("280421", "055220", "5355.09260", "N", "02732.40990", "E", "0", "0", "300", "7");
-    let msg = ShortDataPacket::from(test_data);
+    let msg = ShortDataPacket::try_from(test_data).unwrap();
 
     let test_ts = NaiveDateTime::parse_from_str("280421055220", "%d%m%y%H%M%S").unwrap();
     assert_eq!(msg.timestamp, test_ts);
-    assert_eq!(msg.lon, 53.5509260);
-    assert_eq!(msg.lat, 27.3240990);
+    assert!((msg.lat - 53.918210).abs() < 1e-9);
+    assert!((msg.lon - 27.540165).abs() < 1e-9);
     assert_eq!(msg.speed, 0);
     assert_eq!(msg.course, 0);
     assert_eq!(msg.height, 300);
     assert_eq!(msg.sats, 7);
 
     let test_data = vec!("280421", "055447", "5355.09260", "N", "02732.40990", "E", "60", "0", "300", "7");
-    let msg = ShortDataPacket::from(test_data);
+    let msg = ShortDataPacket::try_from(test_data).unwrap();
 
     let test_ts = NaiveDateTime::parse_from_str("280421055447", "%d%m%y%H%M%S").unwrap();
     assert_eq!(msg.timestamp, test_ts);
     assert_eq!(msg.speed, 60);
-}
\ No newline at end of file
+
+    let msg = ShortDataPacket::try_from(vec!("280421", "055447", "3352.12000", "S", "15112.60000", "W", "60", "0", "300", "7")).unwrap();
+    assert!((msg.lat + 33.868667).abs() < 1e-6);
+    assert!((msg.lon + 151.21).abs() < 1e-9);
+    assert!(ShortDataPacket::try_from(vec!("280421", "055447", "5375.00000", "N", "02732.40990", "E", "60", "0", "300", "7")).is_err());
+    assert!(ShortDataPacket::try_from(vec!("280421", "055447", "5355.09260", "X", "02732.40990", "E", "60", "0", "300", "7")).is_err());
+
+    assert!(ShortDataPacket::try_from(vec!("280421", "055447", "5355.09260", "N")).is_err());
+    assert!(ShortDataPacket::try_from(vec!("280421", "05544x", "5355.09260", "N", "02732.40990", "E", "60", "0", "300", "7")).is_err());
+    assert!(ShortDataPacket::try_from(vec!("280421", "055447", "x", "N", "02732.40990", "E", "60", "0", "300", "7")).is_err());
+    assert!(ShortDataPacket::try_from(vec!("280421", "055447", "5355.09260", "N", "02732.40990", "E", "60", "0", "300", "NA")).is_ok());
+}
@@ -0,0 +1,263 @@
+use mio::{Events, Interest, Poll, Token, Waker};
+use std::io;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+use log::{info, error};
+use crate::ack::{AckRoute, Completion};
+use crate::connection::Connection;
+
+// Token of the waker which signals that new sockets were handed off to the worker.
+const WAKER: Token = Token(0);
+
+// The hand-off channel is also checked this often, so a missed wake-up only delays it.
+const HANDOFF_INTERVAL: Duration = Duration::from_millis(100);
+
+// How often connections are checked against their idle timeout.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Handle used by the acceptor to pass accepted connections to a worker thread.
+pub struct WorkerHandle {
+    sockets: Sender<Connection>,
+    waker: Arc<Waker>,
+}
+
+impl WorkerHandle {
+    /// Passes a connection to the worker. A worker which is gone gives the connection back.
+    pub fn assign(&self, connection: Connection) -> Result<(), Box<Connection>> {
+        if let Err(err) = self.sockets.send(connection) {
+            return Err(Box::new(err.0));
+        }
+        // the worker also polls the channel, a failed wake-up only delays it
+        if let Err(err) = self.waker.wake() {
+            error!("failed to wake worker: {:?}", err);
+        }
+        Ok(())
+    }
+
+    /// Ask the worker to close its connections and exit.
+    pub fn stop(self) -> io::Result<()> {
+        let waker = self.waker;
+        drop(self.sockets);
+        waker.wake()
+    }
+}
+
+/// Reactor thread serving its own share of device connections.
+pub struct Worker {
+    id: usize,
+    poll: Poll,
+    waker: Arc<Waker>,
+    sockets: Receiver<Connection>,
+    // responses confirmed by the store, see `AckMode::AfterStore`
+    completions: Receiver<Completion>,
+    completion_sender: Sender<Completion>,
+    current_conn_token: Token,
+    connections: HashMap<Token, Connection>,
+    // connections waiting for room in the store queue, see `OverflowPolicy::Pause`
+    paused: Vec<Token>,
+    last_idle_check: Instant,
+}
+
+impl Worker {
+    pub fn new(id: usize) -> io::Result<(Worker, WorkerHandle)> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, receiver) = channel();
+        let (completion_sender, completions) = channel();
+
+        let worker = Worker {
+            id,
+            poll,
+            waker: waker.clone(),
+            sockets: receiver,
+            completions,
+            completion_sender,
+            current_conn_token: WAKER,
+            connections: HashMap::new(),
+            paused: Vec::new(),
+            last_idle_check: Instant::now(),
+        };
+
+        Ok((worker, WorkerHandle { sockets: sender, waker }))
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(128);
+
+        info!("Start worker: {}", self.id);
+        loop {
+            // a signal (e.g. the one requesting shutdown) interrupts the wait
+            match self.poll.poll(&mut events, Some(HANDOFF_INTERVAL)) {
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    // hand-offs and completions are picked up after the events are processed
+                    WAKER => {}
+                    token => {
+                        let closed = match self.process(token, event.is_readable(), event.is_writable()) {
+                            Ok(closed) => closed,
+                            Err(err) => {
+                                error!("connection error: {:?}", err);
+                                true
+                            }
+                        };
+
+                        if closed {
+                            info!("Connection closed");
+                            self.connections.remove(&token);
+                        }
+                    }
+                }
+            }
+
+            self.complete_acks();
+            self.resume_paused();
+            self.close_idle();
+
+            if !self.accept_sockets()? {
+                self.close_connections();
+                info!("Stop worker: {}", self.id);
+                return Ok(());
+            }
+        }
+    }
+
+    // Registers connections handed off by the acceptor. Returns false once the acceptor is gone.
+    fn accept_sockets(&mut self) -> io::Result<bool> {
+        loop {
+            match self.sockets.try_recv() {
+                Ok(mut connection) => {
+                    let token = self.next_token();
+                    connection.set_ack_route(AckRoute {
+                        token,
+                        completions: self.completion_sender.clone(),
+                        waker: self.waker.clone(),
+                    });
+                    self.poll.registry().register(&mut connection, token, Interest::READABLE)?;
+
+                    self.connections.insert(token, connection);
+                }
+                Err(TryRecvError::Empty) => return Ok(true),
+                Err(TryRecvError::Disconnected) => return Ok(false),
+            }
+        }
+    }
+
+    // Sends responses for packets the store has saved.
+    fn complete_acks(&mut self) {
+        while let Ok(completion) = self.completions.try_recv() {
+            let token = completion.token;
+            let connection = match self.connections.get_mut(&token) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let poll = &self.poll;
+            let sent = connection.push(completion.response.to_string().as_bytes())
+                .and_then(|_| Worker::update_interest(poll, connection, token));
+            if let Err(err) = sent {
+                error!("connection error: {:?}", err);
+                self.connections.remove(&token);
+            }
+        }
+    }
+
+    // Retries packets the full store queue didn't take.
+    fn resume_paused(&mut self) {
+        let mut paused = std::mem::take(&mut self.paused);
+        paused.retain(|&token| {
+            let connection = match self.connections.get_mut(&token) {
+                Some(c) => c,
+                None => return false,
+            };
+
+            let poll = &self.poll;
+            let resumed = connection.resume()
+                .and_then(|closed| Worker::update_interest(poll, connection, token).map(|_| closed));
+            match resumed {
+                Ok(false) => connection.is_paused(),
+                Ok(true) => {
+                    info!("Connection closed");
+                    self.connections.remove(&token);
+                    false
+                }
+                Err(err) => {
+                    error!("connection error: {:?}", err);
+                    self.connections.remove(&token);
+                    false
+                }
+            }
+        });
+        self.paused = paused;
+    }
+
+    fn close_idle(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_idle_check) < IDLE_CHECK_INTERVAL {
+            return;
+        }
+        self.last_idle_check = now;
+
+        self.connections.retain(|_, connection| {
+            let idle = connection.is_idle(now);
+            if idle {
+                info!("Closing idle connection");
+            }
+            !idle
+        });
+    }
+
+    // Sends out whatever acks are still queued and drops all connections.
+    fn close_connections(&mut self) {
+        for (_, mut connection) in self.connections.drain() {
+            if let Err(err) = connection.flush() {
+                error!("connection error: {:?}", err);
+            }
+        }
+    }
+
+    fn process(&mut self, token: Token, readable: bool, writable: bool) -> io::Result<bool> {
+        let connection = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+
+        if writable {
+            connection.flush()?;
+        }
+
+        if readable && connection.get_message()? {
+            return Ok(true);
+        }
+
+        if connection.is_paused() && !self.paused.contains(&token) {
+            self.paused.push(token);
+        }
+
+        if connection.is_closed() {
+            return Ok(true);
+        }
+
+        Worker::update_interest(&self.poll, connection, token)?;
+        Ok(false)
+    }
+
+    fn update_interest(poll: &Poll, connection: &mut Connection, token: Token) -> io::Result<()> {
+        let interest = connection.wanted_interest();
+        if interest != connection.interest() {
+            poll.registry().reregister(connection, token, interest)?;
+        }
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Token {
+        self.current_conn_token.0 += 1;
+        self.current_conn_token
+    }
+}